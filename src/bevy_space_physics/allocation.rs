@@ -0,0 +1,344 @@
+use bevy::math::DVec3;
+
+// Force and torque one thruster gives at full throttle, in ship axes and about the centre of mass.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThrusterEffect {
+    pub force: DVec3,
+    pub torque: DVec3,
+}
+
+impl ThrusterEffect {
+    // The exhaust leaves along `direction`, so the ship is pushed the other way.
+    pub fn new(position: DVec3, direction: DVec3, force: f64, center_of_mass: DVec3) -> Self {
+        let force = -direction.normalize_or_zero() * force;
+        ThrusterEffect { force, torque: (position - center_of_mass).cross(force) }
+    }
+}
+
+// Control allocation: the throttles in [0, 1] of a set of thrusters that give a requested force
+// and torque as closely as possible. It is solved as a bounded least squares problem in which
+// `torque_weight` says how many newtons of force error one newton metre of torque error is worth.
+// Errors off the requested direction cost `direction_weight` times more than falling short along
+// it, so a request the thrusters cannot meet is scaled down rather than bent, and translating
+// does not spin the ship. The small `regularization` picks the solution burning the least.
+#[derive(Clone, Debug)]
+pub struct ThrusterAllocation {
+    pub effects: Vec<ThrusterEffect>,
+    pub torque_weight: f64,
+    pub direction_weight: f64,
+    pub regularization: f64,
+    pub iterations: usize,
+}
+
+impl ThrusterAllocation {
+    pub fn new(effects: Vec<ThrusterEffect>) -> Self {
+        ThrusterAllocation {
+            effects,
+            torque_weight: 1.0,
+            direction_weight: 30.0,
+            regularization: 1e-6,
+            iterations: 100,
+        }
+    }
+
+    pub fn with_torque_weight(mut self, torque_weight: f64) -> Self {
+        self.torque_weight = torque_weight;
+        self
+    }
+
+    pub fn with_direction_weight(mut self, direction_weight: f64) -> Self {
+        self.direction_weight = direction_weight;
+        self
+    }
+
+    pub fn with_regularization(mut self, regularization: f64) -> Self {
+        self.regularization = regularization;
+        self
+    }
+
+    pub fn with_iterations(mut self, iterations: usize) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    // Largest force along `direction` all the thrusters pushing that way could give together,
+    // torque aside. Commands are scaled by it, the solver then takes what can be balanced.
+    pub fn max_force(&self, direction: DVec3) -> f64 {
+        let direction = direction.normalize_or_zero();
+        self.effects.iter().map(|effect| effect.force.dot(direction).max(0.0)).sum()
+    }
+
+    pub fn max_torque(&self, axis: DVec3) -> f64 {
+        let axis = axis.normalize_or_zero();
+        self.effects.iter().map(|effect| effect.torque.dot(axis).max(0.0)).sum()
+    }
+
+    pub fn result(&self, throttles: &[f64]) -> (DVec3, DVec3) {
+        self.effects
+            .iter()
+            .zip(throttles)
+            .fold((DVec3::ZERO, DVec3::ZERO), |(force, torque), (effect, throttle)| {
+                (force + effect.force * *throttle, torque + effect.torque * *throttle)
+            })
+    }
+
+    // Throttle of every thruster, in the order of `effects`.
+    pub fn allocate(&self, force: DVec3, torque: DVec3) -> Vec<f64> {
+        let n = self.effects.len();
+        // force and weighted torque make up the error space, `along` is the requested direction
+        let columns: Vec<(DVec3, DVec3)> = self.effects.iter().map(|effect| (effect.force, effect.torque * self.torque_weight)).collect();
+        let target = (force, torque * self.torque_weight);
+        let dot = |a: (DVec3, DVec3), b: (DVec3, DVec3)| a.0.dot(b.0) + a.1.dot(b.1);
+        let length = dot(target, target).sqrt();
+        if length == 0.0 {
+            return vec![0.0; n];
+        }
+        let along = (target.0 / length, target.1 / length);
+        let off_direction = self.direction_weight * self.direction_weight - 1.0;
+
+        // normal equations of the weighted problem
+        let mut hessian = vec![0.0; n * n];
+        let mut scale: f64 = 0.0;
+        for (i, a) in columns.iter().enumerate() {
+            for (j, b) in columns.iter().enumerate() {
+                let product = dot(*a, *b);
+                hessian[i * n + j] = product + off_direction * (product - dot(*a, along) * dot(*b, along));
+            }
+            scale = scale.max(dot(*a, *a));
+        }
+        for i in 0..n {
+            hessian[i * n + i] += self.regularization * scale;
+        }
+        // the target has nothing off its own direction
+        let gradient: Vec<f64> = columns.iter().map(|column| dot(*column, target)).collect();
+
+        minimize_in_unit_box(&hessian, &gradient, self.iterations)
+    }
+}
+
+// Minimises `x H x / 2 - g x` over 0 <= x <= 1 for a positive definite `hessian` with the primal
+// active set method: the free throttles are solved for exactly, a step that would leave the box
+// stops at the first bound it hits, and a throttle resting on a bound is freed again when the
+// gradient pulls it inside.
+fn minimize_in_unit_box(hessian: &[f64], gradient: &[f64], iterations: usize) -> Vec<f64> {
+    let n = gradient.len();
+    let mut x = vec![0.0; n];
+    let mut free = vec![false; n];
+    for _ in 0..iterations {
+        let free_indices: Vec<usize> = (0..n).filter(|i| free[*i]).collect();
+        let m = free_indices.len();
+        // optimum over the free throttles with the others held on their bounds
+        let mut system = vec![0.0; m * (m + 1)];
+        for (row, &i) in free_indices.iter().enumerate() {
+            for (column, &j) in free_indices.iter().enumerate() {
+                system[row * (m + 1) + column] = hessian[i * n + j];
+            }
+            system[row * (m + 1) + m] = gradient[i] - (0..n).filter(|j| !free[*j]).map(|j| hessian[i * n + j] * x[j]).sum::<f64>();
+        }
+        let Some(optimum) = solve_linear(&mut system, m) else {
+            break;
+        };
+
+        // walk towards it as far as the box allows
+        let mut step: f64 = 1.0;
+        let mut blocking = None;
+        for (row, &i) in free_indices.iter().enumerate() {
+            let delta = optimum[row] - x[i];
+            let limit = if delta < 0.0 { -x[i] / delta } else if delta > 0.0 { (1.0 - x[i]) / delta } else { f64::INFINITY };
+            if limit < step {
+                step = limit;
+                blocking = Some(i);
+            }
+        }
+        for (row, &i) in free_indices.iter().enumerate() {
+            x[i] = (x[i] + step * (optimum[row] - x[i])).clamp(0.0, 1.0);
+        }
+        if let Some(i) = blocking {
+            x[i] = x[i].round();
+            free[i] = false;
+            continue;
+        }
+
+        // free the bound throttle whose gradient points furthest into the box
+        let mut most_violating = None;
+        let mut largest: f64 = 1e-12 * hessian.iter().fold(0.0, |max: f64, value| max.max(value.abs()));
+        for i in (0..n).filter(|i| !free[*i]) {
+            let descent = gradient[i] - (0..n).map(|j| hessian[i * n + j] * x[j]).sum::<f64>();
+            let violation = if x[i] == 0.0 { descent } else { -descent };
+            if violation > largest {
+                largest = violation;
+                most_violating = Some(i);
+            }
+        }
+        match most_violating {
+            Some(i) => free[i] = true,
+            None => break,
+        }
+    }
+    x
+}
+
+// Gaussian elimination with partial pivoting of an `m` x (`m` + 1) augmented matrix.
+fn solve_linear(system: &mut [f64], m: usize) -> Option<Vec<f64>> {
+    let width = m + 1;
+    for pivot in 0..m {
+        let best = (pivot..m).max_by(|a, b| system[a * width + pivot].abs().total_cmp(&system[b * width + pivot].abs()))?;
+        if system[best * width + pivot].abs() < 1e-300 {
+            return None;
+        }
+        for column in 0..width {
+            system.swap(pivot * width + column, best * width + column);
+        }
+        for row in pivot + 1..m {
+            let factor = system[row * width + pivot] / system[pivot * width + pivot];
+            for column in pivot..width {
+                system[row * width + column] -= factor * system[pivot * width + column];
+            }
+        }
+    }
+    let mut solution = vec![0.0; m];
+    for row in (0..m).rev() {
+        let sum: f64 = (row + 1..m).map(|column| system[row * width + column] * solution[column]).sum();
+        solution[row] = (system[row * width + m] - sum) / system[row * width + row];
+    }
+    Some(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Vec3;
+
+    use super::*;
+    use super::super::blueprint::ShipBlueprint;
+
+    fn ship_blueprint() -> ShipBlueprint {
+        ron::de::from_str(include_str!("../../assets/ships/default.ship.ron")).unwrap()
+    }
+
+    // (position, direction, force, mass) of the thrusters of the default ship
+    fn ship_layout() -> Vec<(DVec3, DVec3, f64, f64)> {
+        ship_blueprint()
+            .thrusters
+            .iter()
+            .map(|thruster| {
+                (Vec3::from(thruster.position).as_dvec3(), Vec3::from(thruster.direction).as_dvec3(), thruster.force as f64, thruster.mass)
+            })
+            .collect()
+    }
+
+    // the hull sits at the origin
+    fn ship_center_of_mass() -> DVec3 {
+        let blueprint = ship_blueprint();
+        let parts = blueprint.parts.iter().map(|part| (Vec3::from(part.position).as_dvec3(), part.mass));
+        let tanks = blueprint.tanks.iter().map(|tank| (Vec3::from(tank.position).as_dvec3(), tank.tank().mass()));
+        let thrusters = ship_layout().into_iter().map(|(position, _, _, mass)| (position, mass));
+        let moment = parts.chain(tanks).chain(thrusters).fold(DVec3::ZERO, |moment, (position, mass)| moment + position * mass);
+        moment / blueprint.mass()
+    }
+
+    fn ship_allocation() -> ThrusterAllocation {
+        let center_of_mass = ship_center_of_mass();
+        let effects = ship_layout()
+            .into_iter()
+            .map(|(position, direction, force, _)| ThrusterEffect::new(position, direction, force, center_of_mass))
+            .collect();
+        ThrusterAllocation::new(effects)
+    }
+
+    // the way `apply_thrusters` turns the wishes of the pilot into a request
+    fn command(allocation: &ThrusterAllocation, movement: DVec3, rotation: DVec3) -> (DVec3, DVec3) {
+        (
+            movement.normalize_or_zero() * allocation.max_force(movement),
+            rotation.normalize_or_zero() * allocation.max_torque(rotation),
+        )
+    }
+
+    #[test]
+    fn layout_has_seventeen_thrusters() {
+        assert_eq!(ship_layout().len(), 17);
+        // main engine and tank behind the hull, the side thrusters in balanced pairs, positions
+        // are read as f32
+        let center_of_mass = (1.5 * 60.0 + 0.3 * 650.0) / 1650.0;
+        assert!((ship_center_of_mass() - DVec3::new(0.0, 0.0, center_of_mass)).length() < 1e-6);
+    }
+
+    #[test]
+    fn throttles_stay_in_bounds() {
+        let allocation = ship_allocation();
+        for (movement, rotation) in [(DVec3::NEG_Z, DVec3::X), (DVec3::ONE, DVec3::NEG_ONE), (DVec3::Y, DVec3::Z)] {
+            let (force, torque) = command(&allocation, movement, rotation);
+            for throttle in allocation.allocate(force * 10.0, torque * 10.0) {
+                assert!((0.0..=1.0).contains(&throttle));
+            }
+        }
+    }
+
+    #[test]
+    fn reachable_request_is_met_exactly() {
+        let allocation = ship_allocation();
+        let force = DVec3::new(30.0, -50.0, -400.0);
+        let torque = DVec3::new(20.0, -10.0, 5.0);
+        let (achieved_force, achieved_torque) = allocation.result(&allocation.allocate(force, torque));
+        assert!((achieved_force - force).length() < 1e-3);
+        assert!((achieved_torque - torque).length() < 1e-3);
+    }
+
+    #[test]
+    fn translation_does_not_spin_the_ship() {
+        let allocation = ship_allocation();
+        for movement in [DVec3::NEG_Z, DVec3::X, DVec3::NEG_X, DVec3::Y, DVec3::NEG_Y] {
+            let (force, torque) = command(&allocation, movement, DVec3::ZERO);
+            let (achieved_force, achieved_torque) = allocation.result(&allocation.allocate(force, torque));
+            // the side thrusters sit at uneven arms around the centre of mass, balancing them
+            // costs some force but leaves next to no torque
+            assert!(achieved_force.dot(movement) > 0.85 * force.length());
+            assert!(achieved_force.normalize().dot(movement) > 0.999_999);
+            assert!(achieved_torque.length() < 1e-3 * achieved_force.length());
+        }
+    }
+
+    #[test]
+    fn unbalanced_request_keeps_its_direction() {
+        // the main engine could push forwards harder than the side thrusters sideways, the
+        // whole request is scaled down instead
+        let allocation = ship_allocation();
+        let movement = DVec3::new(1.0, 1.0, -1.0);
+        let (force, torque) = command(&allocation, movement, DVec3::ZERO);
+        let (achieved_force, achieved_torque) = allocation.result(&allocation.allocate(force, torque));
+        assert!(achieved_force.length() > 550.0);
+        assert!(achieved_force.normalize().dot(movement.normalize()) > 0.999_999);
+        assert!(achieved_torque.length() < 1e-3 * achieved_force.length());
+    }
+
+    #[test]
+    fn rotation_does_not_push_the_ship() {
+        let allocation = ship_allocation();
+        for rotation in [DVec3::X, DVec3::NEG_X, DVec3::Y, DVec3::NEG_Y, DVec3::Z, DVec3::NEG_Z] {
+            let (force, torque) = command(&allocation, DVec3::ZERO, rotation);
+            let throttles = allocation.allocate(force, torque);
+            let (achieved_force, achieved_torque) = allocation.result(&throttles);
+            assert!(achieved_torque.dot(rotation) > 100.0);
+            assert!(achieved_torque.normalize().dot(rotation) > 0.999_999);
+            assert!(achieved_force.length() < 1e-3 * achieved_torque.length());
+            // the main engine cannot be balanced, it stays off
+            assert!(throttles[0] < 1e-6);
+        }
+    }
+
+    #[test]
+    fn unreachable_request_leaves_thrusters_off() {
+        // nothing pushes the ship backwards
+        let allocation = ship_allocation();
+        let (force, torque) = command(&allocation, DVec3::Z, DVec3::ZERO);
+        assert_eq!(force, DVec3::ZERO);
+        let throttles = allocation.allocate(DVec3::new(0.0, 0.0, 500.0), torque);
+        assert!(throttles.iter().all(|throttle| *throttle < 1e-6));
+    }
+
+    #[test]
+    fn empty_layout_allocates_nothing() {
+        let allocation = ThrusterAllocation::new(Vec::new());
+        assert!(allocation.allocate(DVec3::X, DVec3::Y).is_empty());
+        assert_eq!(allocation.max_force(DVec3::X), 0.0);
+    }
+}
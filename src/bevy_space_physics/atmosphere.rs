@@ -0,0 +1,280 @@
+use bevy::{
+    math::DVec3,
+    prelude::*,
+};
+
+use big_space::{
+    precision::GridPrecision,
+    reference_frame::local_origin::ReferenceFrames,
+    world_query::GridTransformReadOnly,
+};
+
+use super::forces::ExternalForces;
+use super::mass::MassProperties;
+use super::physics::{BodyRadius, GravityPoint, SpaceObject};
+
+// Sutton-Graves constant of air in kg^0.5 / m, carbon dioxide atmospheres are close to 1.9e-4.
+pub const EARTH_HEATING_CONSTANT: f64 = 1.7415e-4;
+
+// Exponential atmosphere of a gravity point. The density falls off with the altitude above the
+// `BodyRadius` surface and is zero beyond `radius`, measured from the centre of the body.
+// The air turns with the body.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Atmosphere {
+    pub surface_density: f64,  // kg/m3
+    pub scale_height: f64,
+    pub radius: f64,
+    pub heating_constant: f64,
+}
+
+impl Atmosphere {
+    pub fn new(surface_density: f64, scale_height: f64, radius: f64) -> Self {
+        Atmosphere { surface_density, scale_height, radius, heating_constant: EARTH_HEATING_CONSTANT }
+    }
+
+    pub fn with_heating_constant(mut self, heating_constant: f64) -> Self {
+        self.heating_constant = heating_constant;
+        self
+    }
+
+    pub fn density_at(&self, distance: f64, surface_radius: f64) -> f64 {
+        if distance >= self.radius {
+            return 0.0;
+        }
+        let altitude = (distance - surface_radius).max(0.0);
+        self.surface_density * (-altitude / self.scale_height).exp()
+    }
+}
+
+// Aerodynamics of a ship. `reference_area` is seen from every direction unless `axis_areas` gives
+// the areas seen along the ship X, Y and Z axes, which are blended by the direction of the flow.
+// `center_of_pressure` is in ship axes, away from the centre of mass drag turns the ship.
+// `nose_radius` feeds the estimate of the heat flux at the stagnation point.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct DragProfile {
+    pub drag_coefficient: f64,
+    pub reference_area: f64,
+    pub axis_areas: Option<DVec3>,
+    pub center_of_pressure: DVec3,
+    pub nose_radius: f64,
+}
+
+impl Default for DragProfile {
+    fn default() -> Self {
+        DragProfile {
+            drag_coefficient: 1.0,
+            reference_area: 1.0,
+            axis_areas: None,
+            center_of_pressure: DVec3::ZERO,
+            nose_radius: 1.0,
+        }
+    }
+}
+
+impl DragProfile {
+    pub fn new(drag_coefficient: f64, reference_area: f64) -> Self {
+        DragProfile { drag_coefficient, reference_area, ..default() }
+    }
+
+    pub fn with_axis_areas(mut self, axis_areas: DVec3) -> Self {
+        self.axis_areas = Some(axis_areas);
+        self
+    }
+
+    pub fn with_center_of_pressure(mut self, center_of_pressure: DVec3) -> Self {
+        self.center_of_pressure = center_of_pressure;
+        self
+    }
+
+    pub fn with_nose_radius(mut self, nose_radius: f64) -> Self {
+        self.nose_radius = nose_radius;
+        self
+    }
+
+    // `flow_direction` is a unit vector in ship axes.
+    pub fn area(&self, flow_direction: DVec3) -> f64 {
+        match self.axis_areas {
+            Some(axis_areas) => flow_direction.abs().dot(axis_areas),
+            None => self.reference_area,
+        }
+    }
+
+    // Drag on a ship moving with `airspeed` (world axes) through air of the given density.
+    pub fn drag_force(&self, density: f64, airspeed: DVec3, rotation: Quat) -> DVec3 {
+        let speed = airspeed.length();
+        if speed == 0.0 {
+            return DVec3::ZERO;
+        }
+        let flow_direction = rotation.as_dquat().inverse() * (airspeed / speed);
+        -0.5 * density * speed * self.drag_coefficient * self.area(flow_direction) * airspeed
+    }
+}
+
+// What the air does to an object, updated for objects with a `DragProfile`. `heat_flux` is the
+// Sutton-Graves estimate at the stagnation point in W/m2.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct AerodynamicState {
+    pub body: Option<Entity>,
+    pub density: f64,
+    pub airspeed: f64,
+    pub dynamic_pressure: f64,
+    pub heat_flux: f64,
+}
+
+struct AtmosphereBody {
+    entity: Entity,
+    position: DVec3,
+    velocity: DVec3,
+    angular_velocity: DVec3,
+    surface_radius: f64,
+    atmosphere: Atmosphere,
+}
+
+impl AtmosphereBody {
+    fn new(entity: Entity, object: &SpaceObject, position: DVec3, atmosphere: &Atmosphere, radius: Option<&BodyRadius>) -> Self {
+        AtmosphereBody {
+            entity,
+            position,
+            velocity: object.velocity,
+            angular_velocity: object.angular_velocity.as_dvec3(),
+            surface_radius: radius.map_or(0.0, |radius| radius.0),
+            atmosphere: *atmosphere,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn apply_drag(
+    bodies: &[AtmosphereBody],
+    object: &SpaceObject,
+    position: DVec3,
+    rotation: Quat,
+    profile: &DragProfile,
+    forces: &mut ExternalForces,
+    mass_properties: Option<&MassProperties>,
+    state: Option<&mut AerodynamicState>,
+) {
+    let mut new_state = AerodynamicState::default();
+    let body = bodies.iter().find(|body| body.position.distance(position) < body.atmosphere.radius);
+    if let Some(body) = body {
+        let density = body.atmosphere.density_at(body.position.distance(position), body.surface_radius);
+        let air_velocity = body.velocity + body.angular_velocity.cross(position - body.position);
+        let airspeed = object.velocity - air_velocity;
+        let speed = airspeed.length();
+
+        let center_of_mass = mass_properties.map_or(DVec3::ZERO, |mass_properties| mass_properties.center_of_mass);
+        let point = rotation.as_dquat() * (profile.center_of_pressure - center_of_mass);
+        forces.apply_force_at_point(profile.drag_force(density, airspeed, rotation), point);
+
+        new_state = AerodynamicState {
+            body: Some(body.entity),
+            density,
+            airspeed: speed,
+            dynamic_pressure: 0.5 * density * speed * speed,
+            heat_flux: body.atmosphere.heating_constant * (density / profile.nose_radius).sqrt() * speed.powi(3),
+        };
+    }
+    if let Some(state) = state {
+        *state = new_state;
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(super) fn atmospheric_drag(
+    body_query: Query<(&SpaceObject, Entity, &Transform, &Atmosphere, Option<&BodyRadius>), With<GravityPoint>>,
+    mut object_query: Query<(&SpaceObject, &Transform, &DragProfile, &mut ExternalForces, Option<&MassProperties>, Option<&mut AerodynamicState>)>,
+) {
+    let bodies: Vec<AtmosphereBody> = body_query
+        .iter()
+        .map(|(object, entity, transform, atmosphere, radius)| AtmosphereBody::new(entity, object, transform.translation.as_dvec3(), atmosphere, radius))
+        .collect();
+
+    for (object, transform, profile, mut forces, mass_properties, state) in object_query.iter_mut() {
+        apply_drag(
+            &bodies,
+            object,
+            transform.translation.as_dvec3(),
+            transform.rotation,
+            profile,
+            &mut forces,
+            mass_properties,
+            state.map(|state| state.into_inner()),
+        );
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(super) fn atmospheric_drag_big_space<P: GridPrecision>(
+    frames: ReferenceFrames<P>,
+    body_query: Query<(&SpaceObject, Entity, GridTransformReadOnly<P>, &Atmosphere, Option<&BodyRadius>), With<GravityPoint>>,
+    mut object_query: Query<(&SpaceObject, Entity, GridTransformReadOnly<P>, &DragProfile, &mut ExternalForces, Option<&MassProperties>, Option<&mut AerodynamicState>)>,
+) {
+    let bodies: Vec<AtmosphereBody> = body_query
+        .iter()
+        .filter_map(|(object, entity, grid_transform, atmosphere, radius)| {
+            let reference_frame = frames.parent_frame(entity)?;
+            let position = grid_transform.position_double(reference_frame);
+            Some(AtmosphereBody::new(entity, object, position, atmosphere, radius))
+        })
+        .collect();
+
+    for (object, entity, grid_transform, profile, mut forces, mass_properties, state) in object_query.iter_mut() {
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        apply_drag(
+            &bodies,
+            object,
+            grid_transform.position_double(reference_frame),
+            grid_transform.transform.rotation,
+            profile,
+            &mut forces,
+            mass_properties,
+            state.map(|state| state.into_inner()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn density_falls_off_with_scale_height() {
+        let atmosphere = Atmosphere::new(1.225, 8500.0, 6_511_000.0);
+        let surface_radius = 6_371_000.0;
+        assert_eq!(atmosphere.density_at(surface_radius, surface_radius), 1.225);
+        let density = atmosphere.density_at(surface_radius + 8500.0, surface_radius);
+        assert!((density - 1.225 / std::f64::consts::E).abs() < 1e-12);
+        assert_eq!(atmosphere.density_at(6_600_000.0, surface_radius), 0.0);
+    }
+
+    #[test]
+    fn drag_opposes_airspeed_with_the_area_facing_the_flow() {
+        let profile = DragProfile::new(2.0, 1.0).with_axis_areas(DVec3::new(2.5, 2.5, 1.0));
+        let airspeed = DVec3::new(0.0, 0.0, 100.0);
+        let nose_first = profile.drag_force(1.0, airspeed, Quat::IDENTITY);
+        assert!((nose_first - DVec3::new(0.0, 0.0, -10_000.0)).length() < 1e-6);
+
+        let sideways = profile.drag_force(1.0, airspeed, Quat::from_rotation_y(std::f32::consts::FRAC_PI_2));
+        assert!((sideways - DVec3::new(0.0, 0.0, -25_000.0)).length() < 1e-2);
+    }
+
+    #[test]
+    fn drag_behind_the_centre_of_mass_turns_the_nose_into_the_flow() {
+        let atmosphere = Atmosphere::new(1.0, 8500.0, 7_000_000.0);
+        let body = AtmosphereBody::new(Entity::PLACEHOLDER, &SpaceObject::new(5.972e24), DVec3::ZERO, &atmosphere, Some(&BodyRadius(6_371_000.0)));
+        let position = DVec3::new(0.0, 6_371_000.0, 0.0);
+        let mut object = SpaceObject::new(1000.0);
+        object.velocity = DVec3::new(100.0, 0.0, 0.0);
+
+        // nose along +Z, flying sideways along +X
+        let profile = DragProfile::new(1.0, 1.0).with_center_of_pressure(DVec3::new(0.0, 0.0, -2.0));
+        let mut forces = ExternalForces::default();
+        apply_drag(&[body], &object, position, Quat::IDENTITY, &profile, &mut forces, None, None);
+
+        assert!((forces.force - DVec3::new(-5000.0, 0.0, 0.0)).length() < 1e-6);
+        // turning around +Y swings +Z towards +X
+        assert!((forces.torque - DVec3::new(0.0, 10_000.0, 0.0)).length() < 1e-6);
+    }
+}
@@ -0,0 +1,196 @@
+use bevy::math::DVec3;
+
+use super::physics::G;
+
+const MAX_DEPTH: u32 = 32;
+
+struct Node {
+    center: DVec3,
+    half_size: f64,
+    mass: f64,
+    center_of_mass: DVec3,
+    radius: f64,  // largest body radius in the cell
+    children: Vec<usize>,
+}
+
+// Octree of point masses for approximate gravity, a cell that looks smaller than
+// `opening_angle` from the sampled position is treated as a single mass at its centre of mass.
+// Bodies are (position, mass, radius), below the radius the pull falls off linearly like in
+// `GravitySource::acceleration_at`.
+pub struct Octree {
+    nodes: Vec<Node>,
+}
+
+impl Octree {
+    pub fn new(bodies: &[(DVec3, f64, f64)]) -> Self {
+        let mut octree = Octree { nodes: Vec::new() };
+        if bodies.is_empty() {
+            return octree;
+        }
+
+        let (min, max) = bodies.iter().fold(
+            (DVec3::splat(f64::INFINITY), DVec3::splat(f64::NEG_INFINITY)),
+            |(min, max), (position, _, _)| (min.min(*position), max.max(*position)),
+        );
+        let center = (min + max) / 2.0;
+        let half_size = ((max - min).max_element() / 2.0).max(f64::EPSILON);
+
+        let indices: Vec<usize> = (0..bodies.len()).collect();
+        octree.build(bodies, &indices, center, half_size, 0);
+        octree
+    }
+
+    fn build(&mut self, bodies: &[(DVec3, f64, f64)], indices: &[usize], center: DVec3, half_size: f64, depth: u32) -> usize {
+        let mass: f64 = indices.iter().map(|&i| bodies[i].1).sum();
+        // a single body keeps its exact position, so that it can recognise itself when sampling
+        let center_of_mass = if indices.len() == 1 {
+            bodies[indices[0]].0
+        } else if mass > 0.0 {
+            indices.iter().map(|&i| bodies[i].0 * bodies[i].1).sum::<DVec3>() / mass
+        } else {
+            center
+        };
+        let radius = indices.iter().map(|&i| bodies[i].2).fold(0.0, f64::max);
+
+        let node_index = self.nodes.len();
+        self.nodes.push(Node { center, half_size, mass, center_of_mass, radius, children: Vec::new() });
+
+        if indices.len() <= 1 || depth >= MAX_DEPTH {
+            return node_index;
+        }
+
+        let mut octants: [Vec<usize>; 8] = Default::default();
+        for &i in indices {
+            let position = bodies[i].0;
+            let octant = (position.x > center.x) as usize
+                | ((position.y > center.y) as usize) << 1
+                | ((position.z > center.z) as usize) << 2;
+            octants[octant].push(i);
+        }
+
+        let quarter_size = half_size / 2.0;
+        let mut children = Vec::new();
+        for (octant, octant_indices) in octants.iter().enumerate() {
+            if octant_indices.is_empty() {
+                continue;
+            }
+            let offset = DVec3::new(
+                if octant & 1 != 0 { quarter_size } else { -quarter_size },
+                if octant & 2 != 0 { quarter_size } else { -quarter_size },
+                if octant & 4 != 0 { quarter_size } else { -quarter_size },
+            );
+            children.push(self.build(bodies, octant_indices, center + offset, quarter_size, depth + 1));
+        }
+        self.nodes[node_index].children = children;
+        node_index
+    }
+
+    pub fn acceleration_at(&self, position: DVec3, opening_angle: f64) -> DVec3 {
+        let mut acceleration = DVec3::ZERO;
+        if self.nodes.is_empty() {
+            return acceleration;
+        }
+
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node.mass == 0.0 {
+                continue;
+            }
+            let distance_vec = node.center_of_mass - position;
+            let distance = distance_vec.length();
+            let inside = (position - node.center).abs().max_element() <= node.half_size;
+
+            if node.children.is_empty() || (!inside && 2.0 * node.half_size < opening_angle * distance) {
+                // a body does not attract itself
+                if distance > 0.0 {
+                    let distance = distance.max(node.radius);
+                    acceleration += G * node.mass / (distance * distance * distance) * distance_vec;
+                }
+            } else {
+                stack.extend(node.children.iter().copied());
+            }
+        }
+        acceleration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::physics::GravitySource;
+    use bevy::prelude::Entity;
+
+    fn brute_force_acceleration(bodies: &[(DVec3, f64, f64)], position: DVec3) -> DVec3 {
+        bodies
+            .iter()
+            .filter(|(body_position, _, _)| *body_position != position)
+            .map(|(body_position, mass, _)| {
+                let distance_vec = *body_position - position;
+                G * mass / distance_vec.length().powi(3) * distance_vec
+            })
+            .sum()
+    }
+
+    // deterministic asteroid field: 2000 bodies in a 10 000 km cube around a heavy body
+    fn asteroid_field() -> Vec<(DVec3, f64, f64)> {
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let mut bodies = vec![(DVec3::ZERO, 5.972e24, 0.0)];
+        for _ in 0..2000 {
+            let position = DVec3::new(next() - 0.5, next() - 0.5, next() - 0.5) * 1.0e7;
+            bodies.push((position, 1.0e12 + next() * 1.0e15, 0.0));
+        }
+        bodies
+    }
+
+    #[test]
+    fn zero_opening_angle_matches_brute_force() {
+        let bodies = asteroid_field();
+        let octree = Octree::new(&bodies);
+        for (position, _, _) in bodies.iter().take(100) {
+            let expected = brute_force_acceleration(&bodies, *position);
+            let actual = octree.acceleration_at(*position, 0.0);
+            assert!((actual - expected).length() <= expected.length() * 1e-9);
+        }
+    }
+
+    #[test]
+    fn approximation_error_is_bounded() {
+        let bodies = asteroid_field();
+        let octree = Octree::new(&bodies);
+        for (position, _, _) in bodies.iter().skip(1).take(200) {
+            let expected = brute_force_acceleration(&bodies, *position);
+            let actual = octree.acceleration_at(*position, 0.5);
+            assert!((actual - expected).length() <= expected.length() * 1e-2);
+        }
+    }
+
+    #[test]
+    fn far_away_point_sees_total_mass() {
+        let bodies = asteroid_field();
+        let octree = Octree::new(&bodies);
+        let position = DVec3::new(1.0e12, 0.0, 0.0);
+        let expected = brute_force_acceleration(&bodies, position);
+        let actual = octree.acceleration_at(position, 1.0);
+        assert!((actual - expected).length() <= expected.length() * 1e-6);
+    }
+
+    #[test]
+    fn pull_below_the_surface_matches_a_gravity_source() {
+        let (mass, radius) = (5.972e24, 6.371e6);
+        let octree = Octree::new(&[(DVec3::ZERO, mass, radius), (DVec3::new(3.844e8, 0.0, 0.0), 7.342e22, 1.737e6)]);
+        let earth = GravitySource::new(Entity::from_raw(0), DVec3::ZERO, DVec3::ZERO, mass).with_radius(radius);
+        for position in [DVec3::new(0.0, 1.0, 0.0), DVec3::new(0.0, 3.0e6, 0.0), DVec3::new(0.0, 0.0, -7.0e6)] {
+            let moon_pull = brute_force_acceleration(&[(DVec3::new(3.844e8, 0.0, 0.0), 7.342e22, 0.0)], position);
+            let expected = earth.acceleration_at(position) + moon_pull;
+            let actual = octree.acceleration_at(position, 0.0);
+            assert!((actual - expected).length() <= expected.length() * 1e-9, "{actual} != {expected} at {position}");
+        }
+    }
+}
@@ -0,0 +1,438 @@
+use std::fmt;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    ecs::system::EntityCommands,
+    math::DVec3,
+    prelude::*,
+    utils::HashSet,
+};
+use bevy_hanabi::prelude::*;
+use serde::Deserialize;
+
+use super::atmosphere::{AerodynamicState, DragProfile};
+use super::collision::Collider;
+use super::forces::ExternalForces;
+use super::landing::LandingGear;
+use super::mass::{MassPart, MassProperties, PartShape};
+use super::player::{create_main_thruster_effect, create_side_thruster_effect, ExhaustEffect, Gimbal, Thruster};
+use super::propellant::{PropellantState, PropellantTank};
+use super::radiation::RadiationProfile;
+use super::time_warp::Thrusting;
+
+// Everything `spawn_ship_from_blueprint` builds a ship from, loaded from `.ship.ron` files.
+// Positions and directions are in the axes of the ship, masses in kilograms, angles in degrees.
+#[derive(Asset, TypePath, Clone, Debug, Deserialize)]
+pub struct ShipBlueprint {
+    pub hull: HullBlueprint,
+    #[serde(default)]
+    pub collider: ColliderBlueprint,
+    #[serde(default)]
+    pub drag: Option<DragBlueprint>,
+    #[serde(default)]
+    pub radiation: Option<RadiationBlueprint>,
+    #[serde(default)]
+    pub parts: Vec<PartBlueprint>,
+    #[serde(default)]
+    pub tanks: Vec<TankBlueprint>,
+    #[serde(default)]
+    pub thrusters: Vec<ThrusterBlueprint>,
+}
+
+impl ShipBlueprint {
+    // Dry mass plus the propellant the tanks start with.
+    pub fn mass(&self) -> f64 {
+        self.hull.mass
+            + self.parts.iter().map(|part| part.mass).sum::<f64>()
+            + self.tanks.iter().map(|tank| tank.tank().mass()).sum::<f64>()
+            + self.thrusters.iter().map(|thruster| thruster.mass).sum::<f64>()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct HullBlueprint {
+    pub size: [f32; 3],
+    pub mass: f64,
+    pub color: [u8; 3],
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub enum ShapeBlueprint {
+    // convex hull of the hull mesh
+    #[default]
+    Hull,
+    Sphere { radius: f64 },
+    Cuboid { half_extents: [f64; 3] },
+    ConvexHull { points: Vec<[f64; 3]> },
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ColliderBlueprint {
+    pub shape: ShapeBlueprint,
+    #[serde(default)]
+    pub restitution: Option<f64>,
+    #[serde(default)]
+    pub friction: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DragBlueprint {
+    pub drag_coefficient: f64,
+    pub reference_area: f64,
+    #[serde(default)]
+    pub axis_areas: Option<[f64; 3]>,
+    #[serde(default)]
+    pub nose_radius: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RadiationBlueprint {
+    pub area: f64,
+    pub reflectivity: f64,
+}
+
+// A cuboid of extra mass, drawn when it has a colour.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PartBlueprint {
+    pub position: [f32; 3],
+    pub size: [f32; 3],
+    pub mass: f64,
+    #[serde(default)]
+    pub color: Option<[u8; 3]>,
+}
+
+// Tanks start full unless `contents` says otherwise.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TankBlueprint {
+    pub position: [f32; 3],
+    pub size: [f32; 3],
+    pub capacity: f64,
+    pub dry_mass: f64,
+    #[serde(default)]
+    pub contents: Option<f64>,
+}
+
+impl TankBlueprint {
+    pub fn tank(&self) -> PropellantTank {
+        let tank = PropellantTank::new(self.capacity, self.dry_mass);
+        match self.contents {
+            Some(contents) => tank.with_contents(contents),
+            None => tank,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum ExhaustKind {
+    Main,
+    Side,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExhaustBlueprint {
+    pub kind: ExhaustKind,
+    pub offset: [f32; 3],
+    pub rate: f32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GimbalBlueprint {
+    pub max_angle: f32,
+    pub slew_rate: f32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ThrusterBlueprint {
+    pub position: [f32; 3],
+    pub direction: [f32; 3],
+    pub force: f32,
+    #[serde(default)]
+    pub specific_impulse: f32,
+    #[serde(default)]
+    pub min_throttle: f32,
+    #[serde(default)]
+    pub spool_times: (f32, f32),
+    #[serde(default)]
+    pub pulse_period: Option<f32>,
+    #[serde(default)]
+    pub gimbal: Option<GimbalBlueprint>,
+    pub size: f32,
+    pub mass: f64,
+    #[serde(default)]
+    pub exhaust: Option<ExhaustBlueprint>,
+    #[serde(default)]
+    pub sound: Option<String>,
+}
+
+impl ThrusterBlueprint {
+    pub fn thruster(&self) -> Thruster {
+        let thruster = Thruster::new(self.force, Vec3::from(self.direction))
+            .with_specific_impulse(self.specific_impulse)
+            .with_min_throttle(self.min_throttle)
+            .with_spool_times(self.spool_times.0, self.spool_times.1);
+        match self.pulse_period {
+            Some(period) => thruster.with_pulse_width_modulation(period),
+            None => thruster,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ShipBlueprintLoaderError {
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+}
+
+impl fmt::Display for ShipBlueprintLoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShipBlueprintLoaderError::Io(error) => write!(f, "could not read ship blueprint: {error}"),
+            ShipBlueprintLoaderError::Ron(error) => write!(f, "could not parse ship blueprint: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ShipBlueprintLoaderError {}
+
+impl From<std::io::Error> for ShipBlueprintLoaderError {
+    fn from(error: std::io::Error) -> Self {
+        ShipBlueprintLoaderError::Io(error)
+    }
+}
+
+impl From<ron::error::SpannedError> for ShipBlueprintLoaderError {
+    fn from(error: ron::error::SpannedError) -> Self {
+        ShipBlueprintLoaderError::Ron(error)
+    }
+}
+
+#[derive(Default)]
+pub struct ShipBlueprintLoader;
+
+impl AssetLoader for ShipBlueprintLoader {
+    type Asset = ShipBlueprint;
+    type Settings = ();
+    type Error = ShipBlueprintLoaderError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<ShipBlueprint, ShipBlueprintLoaderError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Ok(ron::de::from_bytes(&bytes)?)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ship.ron"]
+    }
+}
+
+// The ship is built once the blueprint has loaded, and built again whenever the file changes.
+// A rebuilt ship gets fresh thrusters and full tanks.
+#[derive(Component, Clone, Debug)]
+pub struct ShipBlueprintHandle(pub Handle<ShipBlueprint>);
+
+// Children made from the blueprint, taken down on a rebuild.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ShipPart;
+
+pub fn spawn_ship_from_blueprint(
+    commands: &mut EntityCommands,
+    blueprint: &ShipBlueprint,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    effects: &mut Assets<EffectAsset>,
+    asset_server: &AssetServer,
+) {
+    let hull_size = Vec3::from(blueprint.hull.size);
+    let hull_mesh = meshes.add(Cuboid::from_size(hull_size));
+    let collider = match &blueprint.collider.shape {
+        ShapeBlueprint::Hull => meshes.get(&hull_mesh).and_then(Collider::convex_hull_from_mesh),
+        ShapeBlueprint::Sphere { radius } => Some(Collider::sphere(*radius)),
+        ShapeBlueprint::Cuboid { half_extents } => Some(Collider::cuboid(DVec3::from(*half_extents))),
+        ShapeBlueprint::ConvexHull { points } => Some(Collider::convex_hull(points.iter().copied().map(DVec3::from).collect())),
+    };
+    if let Some(mut collider) = collider {
+        if let Some(restitution) = blueprint.collider.restitution {
+            collider = collider.with_restitution(restitution);
+        }
+        if let Some(friction) = blueprint.collider.friction {
+            collider = collider.with_friction(friction);
+        }
+        commands.insert(collider);
+    }
+    commands.insert((
+        LandingGear::default(),
+        MassProperties::default(),
+        ExternalForces::default(),
+        Thrusting::default(),
+        PropellantState::default(),
+        AerodynamicState::default(),
+    ));
+    if let Some(drag) = &blueprint.drag {
+        let mut profile = DragProfile::new(drag.drag_coefficient, drag.reference_area);
+        if let Some(axis_areas) = drag.axis_areas {
+            profile = profile.with_axis_areas(DVec3::from(axis_areas));
+        }
+        if let Some(nose_radius) = drag.nose_radius {
+            profile = profile.with_nose_radius(nose_radius);
+        }
+        commands.insert(profile);
+    }
+    if let Some(radiation) = &blueprint.radiation {
+        commands.insert(RadiationProfile::new(radiation.area, radiation.reflectivity));
+    }
+
+    commands.with_children(|children| {
+        children.spawn((
+            PbrBundle {
+                mesh: hull_mesh,
+                material: materials.add(Color::srgb_u8(blueprint.hull.color[0], blueprint.hull.color[1], blueprint.hull.color[2])),
+                ..default()
+            },
+            MassPart::new(blueprint.hull.mass, PartShape::Cuboid { size: hull_size.as_dvec3() }),
+            ShipPart,
+        ));
+
+        for part in &blueprint.parts {
+            let size = Vec3::from(part.size);
+            let transform = Transform::from_translation(Vec3::from(part.position));
+            let mass_part = MassPart::new(part.mass, PartShape::Cuboid { size: size.as_dvec3() });
+            match part.color {
+                Some([r, g, b]) => children.spawn((
+                    PbrBundle {
+                        mesh: meshes.add(Cuboid::from_size(size)),
+                        material: materials.add(Color::srgb_u8(r, g, b)),
+                        transform,
+                        ..default()
+                    },
+                    mass_part,
+                    ShipPart,
+                )),
+                None => children.spawn((TransformBundle::from_transform(transform), mass_part, ShipPart)),
+            };
+        }
+
+        // the tank systems keep the part mass of a tank
+        for tank in &blueprint.tanks {
+            let propellant_tank = tank.tank();
+            children.spawn((
+                TransformBundle::from_transform(Transform::from_translation(Vec3::from(tank.position))),
+                MassPart::new(propellant_tank.mass(), PartShape::Cuboid { size: Vec3::from(tank.size).as_dvec3() }),
+                propellant_tank,
+                ShipPart,
+            ));
+        }
+
+        for thruster in &blueprint.thrusters {
+            let mut thruster_commands = children.spawn((
+                PbrBundle {
+                    mesh: meshes.add(Cuboid::from_length(thruster.size)),
+                    material: materials.add(Color::srgb_u8(50, 50, 50)),
+                    transform: Transform::from_translation(Vec3::from(thruster.position)),
+                    ..default()
+                },
+                thruster.thruster(),
+                MassPart::new(thruster.mass, PartShape::Cuboid { size: DVec3::splat(thruster.size as f64) }),
+                ShipPart,
+            ));
+            if let Some(gimbal) = &thruster.gimbal {
+                thruster_commands.insert(Gimbal::new(gimbal.max_angle.to_radians(), gimbal.slew_rate.to_radians()));
+            }
+            thruster_commands.with_children(|thruster_children| {
+                if let Some(exhaust) = &thruster.exhaust {
+                    let effect = match exhaust.kind {
+                        ExhaustKind::Main => create_main_thruster_effect(),
+                        ExhaustKind::Side => create_side_thruster_effect(),
+                    };
+                    thruster_children.spawn((
+                        ParticleEffectBundle {
+                            effect: ParticleEffect::new(effects.add(effect)),
+                            transform: Transform::from_translation(Vec3::from(exhaust.offset)),
+                            ..default()
+                        },
+                        ExhaustEffect::new(exhaust.rate),
+                    ));
+                }
+                if let Some(sound) = &thruster.sound {
+                    thruster_children.spawn(AudioBundle {
+                        source: asset_server.load(sound.clone()),
+                        settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
+                    });
+                }
+            });
+        }
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(super) fn build_ships_from_blueprints(
+    mut commands: Commands,
+    mut blueprint_events: EventReader<AssetEvent<ShipBlueprint>>,
+    blueprints: Res<Assets<ShipBlueprint>>,
+    ship_query: Query<(Entity, Ref<ShipBlueprintHandle>, Option<&Children>)>,
+    part_query: Query<(), With<ShipPart>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut effects: ResMut<Assets<EffectAsset>>,
+    asset_server: Res<AssetServer>,
+) {
+    let changed: HashSet<AssetId<ShipBlueprint>> = blueprint_events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+
+    for (ship, handle, children) in ship_query.iter() {
+        if !handle.is_added() && !changed.contains(&handle.0.id()) {
+            continue;
+        }
+        let Some(blueprint) = blueprints.get(&handle.0) else {
+            continue;
+        };
+        for &child in children.into_iter().flat_map(|children| children.iter()) {
+            if part_query.contains(child) {
+                commands.entity(child).despawn_recursive();
+            }
+        }
+        // a reloaded blueprint may have dropped the collider, drag or radiation, the rest is
+        // inserted again anyway
+        if !handle.is_added() {
+            commands.entity(ship).remove::<(Collider, DragProfile, RadiationProfile, LandingGear)>();
+        }
+        spawn_ship_from_blueprint(&mut commands.entity(ship), blueprint, &mut meshes, &mut materials, &mut effects, &asset_server);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_ship_blueprint_parses() {
+        let blueprint: ShipBlueprint = ron::de::from_str(include_str!("../../assets/ships/default.ship.ron")).unwrap();
+        assert_eq!(blueprint.thrusters.len(), 17);
+        assert_eq!(blueprint.thrusters.iter().filter(|thruster| thruster.gimbal.is_some()).count(), 1);
+        assert!(matches!(blueprint.collider.shape, ShapeBlueprint::Hull));
+        // hull, main engine, 16 side thrusters and a full tank
+        assert_eq!(blueprint.mass(), 900.0 + 60.0 + 16.0 * 2.5 + 650.0);
+    }
+
+    #[test]
+    fn optional_fields_have_defaults() {
+        let blueprint: ShipBlueprint = ron::de::from_str(
+            "(hull: (size: (1.0, 1.0, 1.0), mass: 100.0, color: (0, 0, 0)), thrusters: [(position: (0.0, 0.0, 0.5), direction: (0.0, 0.0, 1.0), force: 10.0, size: 0.1, mass: 1.0)])",
+        )
+        .unwrap();
+        assert!(blueprint.tanks.is_empty() && blueprint.drag.is_none());
+        let thruster = blueprint.thrusters[0].thruster();
+        assert!(!thruster.uses_propellant());
+        assert_eq!(thruster.min_throttle, 0.0);
+        assert_eq!(thruster.spool_up_time, 0.0);
+    }
+}
@@ -0,0 +1,234 @@
+use std::f64::consts::TAU;
+
+use bevy::{
+    math::{DQuat, DVec3},
+    prelude::*,
+};
+
+use big_space::{
+    precision::GridPrecision,
+    reference_frame::local_origin::ReferenceFrames,
+    world_query::GridTransformReadOnly,
+};
+
+use super::landing::Landed;
+use super::physics::{GravitySources, PhysicsTime, SpaceObject, G};
+
+// Spins a gravity point analytically around `axis` (world axes), once per `sidereal_period`
+// seconds. The local +Y of the body is kept on the axis, so it is the north pole of the body
+// fixed frame, and `rotation_at_epoch` is the angle the body is turned by at physics time `epoch`.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct BodyRotation {
+    pub sidereal_period: f64,
+    pub axis: DVec3,
+    pub rotation_at_epoch: f64,
+    pub epoch: f64,
+}
+
+impl BodyRotation {
+    pub fn new(sidereal_period: f64, axis: DVec3) -> Self {
+        BodyRotation {
+            sidereal_period,
+            axis: axis.normalize(),
+            rotation_at_epoch: 0.0,
+            epoch: 0.0,
+        }
+    }
+
+    pub fn angular_velocity(&self) -> DVec3 {
+        self.axis * TAU / self.sidereal_period
+    }
+
+    pub fn rotation_at(&self, time: f64) -> DQuat {
+        let angle = self.rotation_at_epoch + TAU * (time - self.epoch) / self.sidereal_period;
+        DQuat::from_axis_angle(self.axis, angle % TAU) * DQuat::from_rotation_arc(DVec3::Y, self.axis)
+    }
+
+    // Position and velocity relative to the body of an object staying above the given longitude
+    // of the equator, on the orbit whose period is the sidereal day.
+    pub fn stationary_state(&self, mass: f64, longitude: f64, time: f64) -> (DVec3, DVec3) {
+        let radius = synchronous_orbit_radius(mass, self.sidereal_period);
+        let offset = self.rotation_at(time) * surface_direction(0.0, longitude) * radius;
+        (offset, self.angular_velocity().cross(offset))
+    }
+}
+
+// Radius of the circular orbit around a body of the given mass whose period is `period`.
+pub fn synchronous_orbit_radius(mass: f64, period: f64) -> f64 {
+    (G * mass * period * period / (TAU * TAU)).cbrt()
+}
+
+// Unit vector of a point on the surface in the body fixed frame. Latitude and longitude are in
+// radians, longitude zero is the +X axis and grows eastwards, in the direction of the spin.
+pub fn surface_direction(latitude: f64, longitude: f64) -> DVec3 {
+    let (sin_latitude, cos_latitude) = latitude.sin_cos();
+    let (sin_longitude, cos_longitude) = longitude.sin_cos();
+    DVec3::new(cos_latitude * cos_longitude, sin_latitude, -cos_latitude * sin_longitude)
+}
+
+// Latitude and longitude of a direction in the body fixed frame, the inverse of `surface_direction`.
+pub fn surface_coordinates(direction: DVec3) -> (f64, f64) {
+    let direction = direction.normalize();
+    (direction.y.clamp(-1.0, 1.0).asin(), (-direction.z).atan2(direction.x))
+}
+
+pub fn to_body_fixed(body_position: DVec3, body_rotation: DQuat, position: DVec3) -> DVec3 {
+    body_rotation.inverse() * (position - body_position)
+}
+
+pub fn from_body_fixed(body_position: DVec3, body_rotation: DQuat, position: DVec3) -> DVec3 {
+    body_position + body_rotation * position
+}
+
+// A fixed place on the surface of a body. Spawn it with a `SpaceObject` and the `Landed` given
+// by `landed`, the surface contact systems then carry it around with the body.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct GroundStation {
+    pub body: Entity,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: f64,
+}
+
+impl GroundStation {
+    pub fn new(body: Entity, latitude: f64, longitude: f64) -> Self {
+        GroundStation { body, latitude, longitude, altitude: 0.0 }
+    }
+
+    pub fn with_altitude(mut self, altitude: f64) -> Self {
+        self.altitude = altitude;
+        self
+    }
+
+    // Local +Y of the station points up.
+    pub fn landed(&self, body_radius: f64) -> Landed {
+        let up = surface_direction(self.latitude, self.longitude);
+        Landed {
+            body: self.body,
+            offset: up * (body_radius + self.altitude),
+            rotation: DQuat::from_rotation_arc(DVec3::Y, up).as_quat(),
+        }
+    }
+}
+
+// Where an object is over the body whose sphere of influence it is in, and how it moves relative
+// to the turning surface. Angles are in radians, `altitude` is above the `BodyRadius` surface.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct SurfaceRelativeState {
+    pub body: Option<Entity>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: f64,
+    pub velocity: DVec3,  // world axes
+    pub vertical_speed: f64,
+    pub ground_speed: f64,
+}
+
+pub(super) fn update_body_rotations(
+    time: Res<PhysicsTime>,
+    mut body_query: Query<(&BodyRotation, &mut SpaceObject, &mut Transform)>,
+) {
+    for (rotation, mut object, mut transform) in body_query.iter_mut() {
+        object.angular_velocity = rotation.angular_velocity().as_vec3();
+        transform.rotation = rotation.rotation_at(time.elapsed_seconds_f64()).as_quat();
+    }
+}
+
+fn surface_relative_state(
+    gravity_sources: &GravitySources,
+    body_query: &Query<(&SpaceObject, &Transform)>,
+    object: &SpaceObject,
+    position: DVec3,
+) -> SurfaceRelativeState {
+    let Some(source) = gravity_sources.soi_source(position) else {
+        return SurfaceRelativeState::default();
+    };
+    let Ok((body, body_transform)) = body_query.get(source.entity) else {
+        return SurfaceRelativeState::default();
+    };
+    let offset = position - source.position;
+    let up = offset.normalize_or_zero();
+    let velocity = object.velocity - source.velocity - body.angular_velocity.as_dvec3().cross(offset);
+    let vertical_speed = velocity.dot(up);
+    let (latitude, longitude) = surface_coordinates(to_body_fixed(source.position, body_transform.rotation.as_dquat(), position));
+    SurfaceRelativeState {
+        body: Some(source.entity),
+        latitude,
+        longitude,
+        altitude: offset.length() - source.radius,
+        velocity,
+        vertical_speed,
+        ground_speed: (velocity - up * vertical_speed).length(),
+    }
+}
+
+pub(super) fn update_surface_relative_states(
+    gravity_sources: Res<GravitySources>,
+    body_query: Query<(&SpaceObject, &Transform)>,
+    mut object_query: Query<(&mut SurfaceRelativeState, Entity, &Transform)>,
+) {
+    for (mut state, entity, transform) in object_query.iter_mut() {
+        let Ok((object, _)) = body_query.get(entity) else {
+            continue;
+        };
+        *state = surface_relative_state(&gravity_sources, &body_query, object, transform.translation.as_dvec3());
+    }
+}
+
+pub(super) fn update_surface_relative_states_big_space<P: GridPrecision>(
+    gravity_sources: Res<GravitySources>,
+    frames: ReferenceFrames<P>,
+    body_query: Query<(&SpaceObject, &Transform)>,
+    mut object_query: Query<(&mut SurfaceRelativeState, Entity, GridTransformReadOnly<P>)>,
+) {
+    for (mut state, entity, grid_transform) in object_query.iter_mut() {
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        let Ok((object, _)) = body_query.get(entity) else {
+            continue;
+        };
+        *state = surface_relative_state(&gravity_sources, &body_query, object, grid_transform.position_double(reference_frame));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geostationary_orbit_of_the_earth() {
+        let radius = synchronous_orbit_radius(5.972e24, 86_164.090_5);
+        assert!((radius - 42_164_000.0).abs() < 5_000.0);
+    }
+
+    #[test]
+    fn stationary_state_keeps_above_the_same_longitude() {
+        let rotation = BodyRotation::new(86_164.090_5, DQuat::from_rotation_x(0.4) * DVec3::Y);
+        let mass = 5.972e24;
+        for time in [0.0, 1000.0, 43_000.0] {
+            let (offset, velocity) = rotation.stationary_state(mass, 1.0, time);
+            let (latitude, longitude) = surface_coordinates(to_body_fixed(DVec3::ZERO, rotation.rotation_at(time), offset));
+            assert!(latitude.abs() < 1e-9);
+            assert!((longitude - 1.0).abs() < 1e-9);
+            // on the equator and moving at circular orbit speed
+            assert!(offset.dot(rotation.axis).abs() < 1e-3);
+            assert!((velocity.length() - (G * mass / offset.length()).sqrt()).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn ground_station_stands_at_its_coordinates() {
+        let body_position = DVec3::new(1.0e9, -2.0e8, 3.0e8);
+        let body_rotation = DQuat::from_rotation_y(1.2) * DQuat::from_rotation_x(0.4);
+        let station = GroundStation::new(Entity::PLACEHOLDER, 0.5, -2.0).with_altitude(120.0);
+        let landed = station.landed(6_371_000.0);
+        assert!((landed.offset.length() - 6_371_120.0).abs() < 1e-6);
+        assert!((landed.rotation * Vec3::Y).as_dvec3().distance(landed.offset.normalize()) < 1e-6);
+
+        let position = from_body_fixed(body_position, body_rotation, landed.offset);
+        let (latitude, longitude) = surface_coordinates(to_body_fixed(body_position, body_rotation, position));
+        assert!((latitude - 0.5).abs() < 1e-9);
+        assert!((longitude + 2.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,746 @@
+use bevy::{
+    math::{DMat3, DQuat, DVec3},
+    prelude::*,
+    render::mesh::VertexAttributeValues,
+};
+
+use big_space::{
+    precision::GridPrecision,
+    reference_frame::local_origin::ReferenceFrames,
+    world_query::GridTransform,
+};
+
+use super::landing::Landed;
+use super::mass::MassProperties;
+use super::orbit::KeplerOrbit;
+use super::physics::SpaceObject;
+
+const MAX_ITERATIONS: usize = 64;
+const EPA_TOLERANCE: f64 = 1e-6;
+const PENETRATION_SLOP: f64 = 0.01;  // metres of overlap left alone to avoid jitter of resting contacts
+const PENETRATION_CORRECTION: f64 = 0.8;
+
+// Shapes are in the local space of the object, centred at its origin.
+#[derive(Clone, Debug)]
+pub enum ColliderShape {
+    Sphere { radius: f64 },
+    Cuboid { half_extents: DVec3 },
+    ConvexHull { points: Vec<DVec3> },
+}
+
+// Restitution and friction of a pair are combined like in Box2D: the larger restitution
+// and the geometric mean of frictions.
+#[derive(Component, Clone, Debug)]
+pub struct Collider {
+    pub shape: ColliderShape,
+    pub restitution: f64,
+    pub friction: f64,
+}
+
+impl Collider {
+    fn new(shape: ColliderShape) -> Self {
+        Collider { shape, restitution: 0.2, friction: 0.5 }
+    }
+
+    pub fn sphere(radius: f64) -> Self {
+        Collider::new(ColliderShape::Sphere { radius })
+    }
+
+    pub fn cuboid(half_extents: DVec3) -> Self {
+        Collider::new(ColliderShape::Cuboid { half_extents })
+    }
+
+    pub fn convex_hull(points: Vec<DVec3>) -> Self {
+        Collider::new(ColliderShape::ConvexHull { points })
+    }
+
+    // The support point of a point cloud is the one of its convex hull, so the vertices
+    // of the mesh are used as they are.
+    pub fn convex_hull_from_mesh(mesh: &Mesh) -> Option<Self> {
+        let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION)? {
+            VertexAttributeValues::Float32x3(positions) => positions,
+            _ => return None,
+        };
+        if positions.is_empty() {
+            return None;
+        }
+        Some(Collider::convex_hull(positions.iter().map(|&position| Vec3::from(position).as_dvec3()).collect()))
+    }
+
+    pub fn with_restitution(mut self, restitution: f64) -> Self {
+        self.restitution = restitution;
+        self
+    }
+
+    pub fn with_friction(mut self, friction: f64) -> Self {
+        self.friction = friction;
+        self
+    }
+
+    pub fn bounding_radius(&self) -> f64 {
+        match &self.shape {
+            ColliderShape::Sphere { radius } => *radius,
+            ColliderShape::Cuboid { half_extents } => half_extents.length(),
+            ColliderShape::ConvexHull { points } => points.iter().map(|point| point.length()).fold(0.0, f64::max),
+        }
+    }
+
+    // Farthest point of the shape in `direction`, both relative to the object and in world axes.
+    pub fn support(&self, rotation: DQuat, direction: DVec3) -> DVec3 {
+        let local_direction = rotation.inverse() * direction;
+        let local_support = match &self.shape {
+            ColliderShape::Sphere { radius } => local_direction.normalize_or_zero() * *radius,
+            ColliderShape::Cuboid { half_extents } => DVec3::select(local_direction.cmpge(DVec3::ZERO), *half_extents, -*half_extents),
+            ColliderShape::ConvexHull { points } => points
+                .iter()
+                .copied()
+                .max_by(|a, b| a.dot(local_direction).total_cmp(&b.dot(local_direction)))
+                .unwrap_or(DVec3::ZERO),
+        };
+        rotation * local_support
+    }
+
+    // Principal moments of inertia of a solid shape, hulls are approximated by their bounding box.
+    pub fn principal_inertia(&self, mass: f64) -> DVec3 {
+        let cuboid_inertia = |size: DVec3| {
+            let size = size * size;
+            DVec3::new(size.y + size.z, size.x + size.z, size.x + size.y) * mass / 12.0
+        };
+        match &self.shape {
+            ColliderShape::Sphere { radius } => DVec3::splat(0.4 * mass * radius * radius),
+            ColliderShape::Cuboid { half_extents } => cuboid_inertia(*half_extents * 2.0),
+            ColliderShape::ConvexHull { points } => {
+                let (min, max) = points.iter().fold(
+                    (DVec3::splat(f64::INFINITY), DVec3::splat(f64::NEG_INFINITY)),
+                    |(min, max), point| (min.min(*point), max.max(*point)),
+                );
+                cuboid_inertia((max - min).max(DVec3::ZERO))
+            }
+        }
+    }
+}
+
+// Closest point of a triangle to the origin together with the vertices of the feature it lies on
+// (Ericson, Real-Time Collision Detection, 5.1.5).
+fn closest_on_triangle(a: DVec3, b: DVec3, c: DVec3) -> (DVec3, Vec<usize>) {
+    let ab = b - a;
+    let ac = c - a;
+    let d1 = -ab.dot(a);
+    let d2 = -ac.dot(a);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return (a, vec![0]);
+    }
+    let d3 = -ab.dot(b);
+    let d4 = -ac.dot(b);
+    if d3 >= 0.0 && d4 <= d3 {
+        return (b, vec![1]);
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return (a + ab * (d1 / (d1 - d3)), vec![0, 1]);
+    }
+    let d5 = -ab.dot(c);
+    let d6 = -ac.dot(c);
+    if d6 >= 0.0 && d5 <= d6 {
+        return (c, vec![2]);
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return (a + ac * (d2 / (d2 - d6)), vec![0, 2]);
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        return (b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6))), vec![1, 2]);
+    }
+    let denominator = 1.0 / (va + vb + vc);
+    (a + ab * (vb * denominator) + ac * (vc * denominator), vec![0, 1, 2])
+}
+
+// Closest point of a simplex to the origin and the vertices of the smallest sub-simplex
+// containing it. No point means that the origin is inside the tetrahedron.
+fn closest_on_simplex(simplex: &[DVec3]) -> (Option<DVec3>, Vec<usize>) {
+    match *simplex {
+        [a] => (Some(a), vec![0]),
+        [a, b] => {
+            let ab = b - a;
+            let t = -a.dot(ab) / ab.length_squared();
+            if t.is_nan() || t <= 0.0 {
+                (Some(a), vec![0])
+            } else if t >= 1.0 {
+                (Some(b), vec![1])
+            } else {
+                (Some(a + ab * t), vec![0, 1])
+            }
+        }
+        [a, b, c] => {
+            let (point, indices) = closest_on_triangle(a, b, c);
+            (Some(point), indices)
+        }
+        [_, _, _, _] => {
+            let mut closest: Option<(DVec3, Vec<usize>)> = None;
+            for [i, j, k, opposite] in [[0, 1, 2, 3], [0, 2, 3, 1], [0, 3, 1, 2], [1, 3, 2, 0]] {
+                let (a, b, c) = (simplex[i], simplex[j], simplex[k]);
+                let normal = (b - a).cross(c - a);
+                // the origin is on the other side of this face than the remaining vertex
+                if normal.dot(-a) * normal.dot(simplex[opposite] - a) <= 0.0 {
+                    let (point, indices) = closest_on_triangle(a, b, c);
+                    if closest.as_ref().is_none_or(|(closest, _)| point.length_squared() < closest.length_squared()) {
+                        closest = Some((point, indices.into_iter().map(|index| [i, j, k][index]).collect()));
+                    }
+                }
+            }
+            match closest {
+                Some((point, indices)) => (Some(point), indices),
+                None => (None, vec![0, 1, 2, 3]),
+            }
+        }
+        _ => (None, (0..simplex.len()).collect()),
+    }
+}
+
+// Point of the Minkowski difference a - b together with the point of `a` it comes from.
+#[derive(Clone, Copy)]
+struct SupportPoint {
+    point: DVec3,
+    a: DVec3,
+}
+
+enum Gjk {
+    Separated(DVec3),  // closest point of the Minkowski difference to the origin
+    Overlapping([SupportPoint; 4]),
+}
+
+// When the origin lies on a lower dimensional simplex, it is extended to a tetrahedron around
+// the origin with support points in directions off its affine hull. Nothing is found for
+// shapes that only touch.
+fn complete_tetrahedron(mut simplex: Vec<SupportPoint>, support: impl Fn(DVec3) -> SupportPoint) -> Option<[SupportPoint; 4]> {
+    let scale = simplex.iter().map(|vertex| vertex.point.length()).fold(0.0, f64::max);
+    let epsilon = 1e-10 * scale.max(f64::MIN_POSITIVE);
+    let axes = [DVec3::X, DVec3::Y, DVec3::Z];
+    while simplex.len() < 4 {
+        let a = simplex[0].point;
+        let directions: Vec<DVec3> = match simplex.len() {
+            1 => axes.to_vec(),
+            2 => axes.iter().map(|axis| (simplex[1].point - a).cross(*axis)).collect(),
+            _ => vec![(simplex[1].point - a).cross(simplex[2].point - a)],
+        };
+        let off_hull = |point: DVec3| match simplex.len() {
+            1 => (point - a).length(),
+            2 => (point - a).cross((simplex[1].point - a).normalize_or_zero()).length(),
+            _ => (point - a).dot(directions[0].normalize_or_zero()).abs(),
+        };
+        let new_point = directions
+            .iter()
+            .filter(|direction| **direction != DVec3::ZERO)
+            .flat_map(|direction| [support(*direction), support(-*direction)])
+            .find(|vertex| off_hull(vertex.point) > epsilon)?;
+        simplex.push(new_point);
+    }
+    let [a, b, c, d] = simplex[..] else {
+        return None;
+    };
+    Some([a, b, c, d])
+}
+
+// Distance between convex sets given by the support function of their Minkowski difference.
+fn gjk(support: impl Fn(DVec3) -> SupportPoint) -> Gjk {
+    let mut simplex = vec![support(DVec3::X)];
+    let mut closest = simplex[0].point;
+    for _ in 0..MAX_ITERATIONS {
+        let distance_squared = closest.length_squared();
+        let scale_squared = simplex.iter().map(|vertex| vertex.point.length_squared()).fold(0.0, f64::max);
+        if distance_squared <= 1e-20 * scale_squared {
+            return match complete_tetrahedron(simplex, &support) {
+                Some(tetrahedron) => Gjk::Overlapping(tetrahedron),
+                None => Gjk::Separated(DVec3::ZERO),
+            };
+        }
+        let new_point = support(-closest);
+        // no point of the difference is closer to the origin than `closest` beyond rounding errors
+        if distance_squared - closest.dot(new_point.point) <= 1e-12 * distance_squared.sqrt() * scale_squared.sqrt()
+            || simplex.iter().any(|vertex| vertex.point == new_point.point)
+        {
+            return Gjk::Separated(closest);
+        }
+        simplex.push(new_point);
+        let points: Vec<DVec3> = simplex.iter().map(|vertex| vertex.point).collect();
+        let (point, indices) = closest_on_simplex(&points);
+        simplex = indices.into_iter().map(|index| simplex[index]).collect();
+        let Some(point) = point else {
+            let [a, b, c, d] = simplex[..] else {
+                return Gjk::Separated(DVec3::ZERO);
+            };
+            return Gjk::Overlapping([a, b, c, d]);
+        };
+        closest = point;
+    }
+    Gjk::Separated(closest)
+}
+
+// Expanding polytope: the face of the Minkowski difference closest to the origin gives the
+// penetration normal and depth. Returns the normal from `a` to `b`, the depth and the deepest
+// point of `a`.
+fn epa(simplex: [SupportPoint; 4], support: impl Fn(DVec3) -> SupportPoint) -> Option<(DVec3, f64, DVec3)> {
+    let mut vertices = simplex.to_vec();
+    let centroid = vertices.iter().map(|vertex| vertex.point).sum::<DVec3>() / 4.0;
+    let mut faces: Vec<[usize; 3]> = [[0, 1, 2], [0, 3, 1], [0, 2, 3], [1, 3, 2]]
+        .into_iter()
+        .map(|[i, j, k]| {
+            let normal = (vertices[j].point - vertices[i].point).cross(vertices[k].point - vertices[i].point);
+            if normal.dot(vertices[i].point - centroid) < 0.0 { [i, k, j] } else { [i, j, k] }
+        })
+        .collect();
+
+    let face_plane = |vertices: &[SupportPoint], [i, j, k]: [usize; 3]| {
+        let normal = (vertices[j].point - vertices[i].point)
+            .cross(vertices[k].point - vertices[i].point)
+            .normalize_or_zero();
+        (normal, normal.dot(vertices[i].point))
+    };
+
+    let mut closest = None;
+    for _ in 0..MAX_ITERATIONS {
+        let Some((face, normal, distance)) = faces
+            .iter()
+            .map(|&face| {
+                let (normal, distance) = face_plane(&vertices, face);
+                (face, normal, distance)
+            })
+            .filter(|(_, normal, _)| *normal != DVec3::ZERO)
+            .min_by(|(_, _, a), (_, _, b)| a.total_cmp(b))
+        else {
+            break;
+        };
+        closest = Some((face, normal, distance));
+
+        let new_vertex = support(normal);
+        if new_vertex.point.dot(normal) - distance <= EPA_TOLERANCE {
+            break;
+        }
+
+        // remove the faces seen from the new vertex and patch the hole from the horizon
+        let mut horizon: Vec<(usize, usize)> = Vec::new();
+        faces.retain(|&face| {
+            let (normal, distance) = face_plane(&vertices, face);
+            if normal.dot(new_vertex.point) - distance <= 0.0 {
+                return true;
+            }
+            for edge in [(face[0], face[1]), (face[1], face[2]), (face[2], face[0])] {
+                if let Some(shared) = horizon.iter().position(|&(i, j)| (j, i) == edge) {
+                    horizon.swap_remove(shared);
+                } else {
+                    horizon.push(edge);
+                }
+            }
+            false
+        });
+        let index = vertices.len();
+        vertices.push(new_vertex);
+        faces.extend(horizon.into_iter().map(|(i, j)| [i, j, index]));
+    }
+
+    let ([i, j, k], normal, distance) = closest?;
+    let (a, b, c) = (vertices[i], vertices[j], vertices[k]);
+    let barycentric = barycentric(normal * distance, a.point, b.point, c.point);
+    let deepest = a.a * barycentric.x + b.a * barycentric.y + c.a * barycentric.z;
+    Some((normal, distance.max(0.0), deepest))
+}
+
+fn barycentric(point: DVec3, a: DVec3, b: DVec3, c: DVec3) -> DVec3 {
+    let (v0, v1, v2) = (b - a, c - a, point - a);
+    let (d00, d01, d11) = (v0.dot(v0), v0.dot(v1), v1.dot(v1));
+    let (d20, d21) = (v2.dot(v0), v2.dot(v1));
+    let denominator = d00 * d11 - d01 * d01;
+    if denominator == 0.0 {
+        return DVec3::new(1.0, 0.0, 0.0);
+    }
+    let v = (d11 * d20 - d01 * d21) / denominator;
+    let w = (d00 * d21 - d01 * d20) / denominator;
+    DVec3::new(1.0 - v - w, v, w)
+}
+
+// `normal` points from the first object to the second one, `point` is halfway between the
+// surfaces and relative to the first object like `offset`.
+#[derive(Clone, Copy, Debug)]
+pub struct Contact {
+    pub normal: DVec3,
+    pub depth: f64,
+    pub point: DVec3,
+}
+
+// Sphere against any shape: the closest point of the shape to the centre of the sphere.
+fn sphere_contact(collider: &Collider, rotation: DQuat, center: DVec3, radius: f64) -> Option<Contact> {
+    let support = |direction: DVec3| {
+        let a = collider.support(rotation, direction);
+        SupportPoint { point: a - center, a }
+    };
+    match gjk(support) {
+        Gjk::Separated(closest) => {
+            let distance = closest.length();
+            if distance >= radius || distance == 0.0 {
+                return None;
+            }
+            let normal = -closest / distance;
+            let depth = radius - distance;
+            Some(Contact { normal, depth, point: closest + center - normal * depth / 2.0 })
+        }
+        Gjk::Overlapping(simplex) => {
+            // the centre is inside the shape
+            let (normal, depth, deepest) = epa(simplex, support)?;
+            let depth = depth + radius;
+            Some(Contact { normal, depth, point: deepest - normal * depth / 2.0 })
+        }
+    }
+}
+
+// Contact between two colliders, positions are relative to the first one.
+pub fn contact(a: &Collider, rotation_a: DQuat, b: &Collider, offset: DVec3, rotation_b: DQuat) -> Option<Contact> {
+    match (&a.shape, &b.shape) {
+        (ColliderShape::Sphere { radius: radius_a }, ColliderShape::Sphere { radius: radius_b }) => {
+            let distance = offset.length();
+            let depth = radius_a + radius_b - distance;
+            if depth <= 0.0 {
+                return None;
+            }
+            let normal = if distance > 0.0 { offset / distance } else { DVec3::Y };
+            Some(Contact { normal, depth, point: normal * (radius_a - depth / 2.0) })
+        }
+        (_, ColliderShape::Sphere { radius }) => sphere_contact(a, rotation_a, offset, *radius),
+        (ColliderShape::Sphere { radius }, _) => {
+            let contact = sphere_contact(b, rotation_b, -offset, *radius)?;
+            Some(Contact { normal: -contact.normal, depth: contact.depth, point: contact.point + offset })
+        }
+        _ => {
+            let support = |direction: DVec3| {
+                let support_a = a.support(rotation_a, direction);
+                SupportPoint { point: support_a - (offset + b.support(rotation_b, -direction)), a: support_a }
+            };
+            let Gjk::Overlapping(simplex) = gjk(support) else {
+                return None;
+            };
+            let (normal, depth, deepest) = epa(simplex, support)?;
+            Some(Contact { normal, depth, point: deepest - normal * depth / 2.0 })
+        }
+    }
+}
+
+// Sent every substep for every touching pair, `impulse` is zero when the objects already separate.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct CollisionEvent {
+    pub entity_a: Entity,
+    pub entity_b: Entity,
+    pub point: DVec3,  // in the same frame as the object positions
+    pub normal: DVec3,  // from `entity_a` to `entity_b`
+    pub depth: f64,
+    pub relative_speed: f64,  // closing speed along the normal before the response
+    pub impulse: f64,
+}
+
+// Objects on rails or landed have an infinite mass, collisions do not move them. The inertia
+// comes from `MassProperties` when the object has them, otherwise from the collider shape.
+// Impulses turn the object around `center_of_mass`, its offset from `position` in world axes.
+pub struct CollisionBody<'a> {
+    pub entity: Entity,
+    pub collider: &'a Collider,
+    pub position: DVec3,
+    pub rotation: DQuat,
+    pub center_of_mass: DVec3,
+    pub velocity: DVec3,
+    pub angular_velocity: DVec3,
+    pub inverse_mass: f64,
+    pub inverse_inertia: DMat3,
+    pub correction: DVec3,
+}
+
+impl<'a> CollisionBody<'a> {
+    pub fn new(
+        entity: Entity,
+        collider: &'a Collider,
+        object: &SpaceObject,
+        mass_properties: Option<&MassProperties>,
+        position: DVec3,
+        rotation: Quat,
+        kinematic: bool,
+    ) -> Self {
+        let rotation = rotation.as_dquat();
+        let mass_properties = mass_properties.filter(|mass_properties| mass_properties.mass > 0.0);
+        let (inverse_mass, inverse_inertia) = if kinematic || object.mass <= 0.0 {
+            (0.0, DMat3::ZERO)
+        } else {
+            let local_inverse_inertia = match mass_properties {
+                Some(mass_properties) => mass_properties.inverse_inertia,
+                None => {
+                    // flat hulls have no inertia around some axis, they do not spin around it
+                    let inertia = collider.principal_inertia(object.mass);
+                    DMat3::from_diagonal(DVec3::select(inertia.cmpgt(DVec3::ZERO), inertia.recip(), DVec3::ZERO))
+                }
+            };
+            let orientation = DMat3::from_quat(rotation);
+            (1.0 / object.mass, orientation * local_inverse_inertia * orientation.transpose())
+        };
+        CollisionBody {
+            entity,
+            collider,
+            position,
+            rotation,
+            center_of_mass: rotation * mass_properties.map_or(DVec3::ZERO, |mass_properties| mass_properties.center_of_mass),
+            velocity: object.velocity,
+            angular_velocity: object.angular_velocity.as_dvec3(),
+            inverse_mass,
+            inverse_inertia,
+            correction: DVec3::ZERO,
+        }
+    }
+
+    fn velocity_at(&self, offset: DVec3) -> DVec3 {
+        self.velocity + self.angular_velocity.cross(offset)
+    }
+
+    fn apply_impulse(&mut self, impulse: DVec3, offset: DVec3) {
+        self.velocity += impulse * self.inverse_mass;
+        self.angular_velocity += self.inverse_inertia * offset.cross(impulse);
+    }
+
+    fn effective_inverse_mass(&self, offset: DVec3, direction: DVec3) -> f64 {
+        self.inverse_mass + direction.dot((self.inverse_inertia * offset.cross(direction)).cross(offset))
+    }
+}
+
+// Sweep and prune of bounding spheres along X. Positions are absolute, so objects in
+// different big_space cells are compared directly.
+pub fn broad_phase(bodies: &[CollisionBody]) -> Vec<(usize, usize)> {
+    let mut order: Vec<(usize, f64)> = bodies
+        .iter()
+        .enumerate()
+        .map(|(i, body)| (i, body.collider.bounding_radius()))
+        .collect();
+    order.sort_by(|(a, radius_a), (b, radius_b)| {
+        (bodies[*a].position.x - radius_a).total_cmp(&(bodies[*b].position.x - radius_b))
+    });
+
+    let mut pairs = Vec::new();
+    for (k, &(i, radius_i)) in order.iter().enumerate() {
+        let max_x = bodies[i].position.x + radius_i;
+        for &(j, radius_j) in order[k + 1..].iter() {
+            if bodies[j].position.x - radius_j > max_x {
+                break;
+            }
+            if bodies[i].inverse_mass == 0.0 && bodies[j].inverse_mass == 0.0 {
+                continue;
+            }
+            if bodies[i].position.distance(bodies[j].position) <= radius_i + radius_j {
+                pairs.push((i.min(j), i.max(j)));
+            }
+        }
+    }
+    pairs
+}
+
+// Impulse along the normal with restitution, Coulomb friction along the sliding direction and
+// a positional correction of the overlap split by inverse masses.
+fn resolve_contact(bodies: &mut [CollisionBody], i: usize, j: usize, contact: &Contact) -> CollisionEvent {
+    let (left, right) = bodies.split_at_mut(j);
+    let (a, b) = (&mut left[i], &mut right[0]);
+    // lever arms from the centres of mass, which the inertia is about
+    let offset_a = contact.point - a.center_of_mass;
+    let offset_b = contact.point - (b.position - a.position) - b.center_of_mass;
+    let normal = contact.normal;
+
+    let relative_velocity = b.velocity_at(offset_b) - a.velocity_at(offset_a);
+    let normal_speed = relative_velocity.dot(normal);
+    let mut impulse = 0.0;
+    if normal_speed < 0.0 {
+        let restitution = a.collider.restitution.max(b.collider.restitution);
+        let normal_mass = a.effective_inverse_mass(offset_a, normal) + b.effective_inverse_mass(offset_b, normal);
+        impulse = -(1.0 + restitution) * normal_speed / normal_mass;
+        a.apply_impulse(-normal * impulse, offset_a);
+        b.apply_impulse(normal * impulse, offset_b);
+
+        let relative_velocity = b.velocity_at(offset_b) - a.velocity_at(offset_a);
+        let tangent = (relative_velocity - normal * relative_velocity.dot(normal)).normalize_or_zero();
+        if tangent != DVec3::ZERO {
+            let tangent_mass = a.effective_inverse_mass(offset_a, tangent) + b.effective_inverse_mass(offset_b, tangent);
+            let max_friction = (a.collider.friction * b.collider.friction).sqrt() * impulse;
+            let friction = (-relative_velocity.dot(tangent) / tangent_mass).clamp(-max_friction, max_friction);
+            a.apply_impulse(-tangent * friction, offset_a);
+            b.apply_impulse(tangent * friction, offset_b);
+        }
+    }
+
+    let inverse_mass = a.inverse_mass + b.inverse_mass;
+    let correction = (contact.depth - PENETRATION_SLOP).max(0.0) * PENETRATION_CORRECTION / inverse_mass * normal;
+    a.correction -= correction * a.inverse_mass;
+    b.correction += correction * b.inverse_mass;
+
+    CollisionEvent {
+        entity_a: a.entity,
+        entity_b: b.entity,
+        point: a.position + contact.point,
+        normal,
+        depth: contact.depth,
+        relative_speed: -normal_speed,
+        impulse,
+    }
+}
+
+pub fn resolve_collisions(bodies: &mut [CollisionBody]) -> Vec<CollisionEvent> {
+    let mut events = Vec::new();
+    for (i, j) in broad_phase(bodies) {
+        let (a, b) = (&bodies[i], &bodies[j]);
+        let Some(contact) = contact(a.collider, a.rotation, b.collider, b.position - a.position, b.rotation) else {
+            continue;
+        };
+        events.push(resolve_contact(bodies, i, j, &contact));
+    }
+    events
+}
+
+#[allow(clippy::type_complexity)]
+pub(super) fn collisions(
+    mut collision_events: EventWriter<CollisionEvent>,
+    collider_query: Query<&Collider>,
+    mut object_query: Query<(&mut SpaceObject, Entity, &mut Transform, Option<&MassProperties>, Has<KeplerOrbit>, Has<Landed>), With<Collider>>,
+) {
+    let mut bodies: Vec<CollisionBody> = object_query
+        .iter()
+        .filter_map(|(object, entity, transform, mass_properties, on_rails, landed)| {
+            let collider = collider_query.get(entity).ok()?;
+            let position = transform.translation.as_dvec3();
+            Some(CollisionBody::new(entity, collider, object, mass_properties, position, transform.rotation, on_rails || landed))
+        })
+        .collect();
+    let events = resolve_collisions(&mut bodies);
+    if events.is_empty() {
+        return;
+    }
+
+    for body in bodies.iter().filter(|body| body.inverse_mass > 0.0) {
+        let Ok((mut object, _, mut transform, ..)) = object_query.get_mut(body.entity) else {
+            continue;
+        };
+        object.velocity = body.velocity;
+        object.angular_velocity = body.angular_velocity.as_vec3();
+        transform.translation += body.correction.as_vec3();
+    }
+    collision_events.send_batch(events);
+}
+
+#[allow(clippy::type_complexity)]
+pub(super) fn collisions_big_space<P: GridPrecision>(
+    frames: ReferenceFrames<P>,
+    mut collision_events: EventWriter<CollisionEvent>,
+    collider_query: Query<&Collider>,
+    mut object_query: Query<(&mut SpaceObject, Entity, GridTransform<P>, Option<&MassProperties>, Has<KeplerOrbit>, Has<Landed>), With<Collider>>,
+) {
+    let mut bodies: Vec<CollisionBody> = object_query
+        .iter()
+        .filter_map(|(object, entity, grid_transform, mass_properties, on_rails, landed)| {
+            let collider = collider_query.get(entity).ok()?;
+            let reference_frame = frames.parent_frame(entity)?;
+            let position = grid_transform.position_double(reference_frame);
+            Some(CollisionBody::new(entity, collider, object, mass_properties, position, grid_transform.transform.rotation, on_rails || landed))
+        })
+        .collect();
+    let events = resolve_collisions(&mut bodies);
+    if events.is_empty() {
+        return;
+    }
+
+    for body in bodies.iter().filter(|body| body.inverse_mass > 0.0) {
+        let Some(reference_frame) = frames.parent_frame(body.entity) else {
+            continue;
+        };
+        let Ok((mut object, _, mut grid_transform, ..)) = object_query.get_mut(body.entity) else {
+            continue;
+        };
+        object.velocity = body.velocity;
+        object.angular_velocity = body.angular_velocity.as_vec3();
+        let (delta_cell, delta_translation) = reference_frame.translation_to_grid(body.correction);
+        *grid_transform.cell += delta_cell;
+        grid_transform.transform.translation += delta_translation;
+    }
+    collision_events.send_batch(events);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separated_cuboids_do_not_collide() {
+        let cuboid = Collider::cuboid(DVec3::ONE);
+        assert!(contact(&cuboid, DQuat::IDENTITY, &cuboid, DVec3::new(2.5, 0.0, 0.0), DQuat::IDENTITY).is_none());
+    }
+
+    #[test]
+    fn overlapping_cuboids_are_pushed_apart_along_the_shallow_axis() {
+        let cuboid = Collider::cuboid(DVec3::ONE);
+        let contact = contact(&cuboid, DQuat::IDENTITY, &cuboid, DVec3::new(1.9, 0.5, 0.0), DQuat::IDENTITY).unwrap();
+        assert!((contact.normal - DVec3::X).length() < 1e-6);
+        assert!((contact.depth - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn ship_touching_the_sun() {
+        let sun = Collider::sphere(696_340_000.0);
+        let ship = Collider::cuboid(DVec3::new(0.5, 0.5, 1.25));
+        let rotation = DQuat::from_rotation_y(0.3);
+        let offset = DVec3::new(0.0, 696_340_000.0 + 0.4, 0.0);
+        let contact = contact(&sun, DQuat::IDENTITY, &ship, offset, rotation).unwrap();
+        assert!((contact.normal - DVec3::Y).length() < 1e-6);
+        assert!((contact.depth - 0.1).abs() < 1e-3);
+
+        let offset = DVec3::new(0.0, 696_340_000.0 + 0.6, 0.0);
+        assert!(super::contact(&sun, DQuat::IDENTITY, &ship, offset, rotation).is_none());
+    }
+
+    #[test]
+    fn head_on_impulse_keeps_momentum() {
+        let sphere = Collider::sphere(1.0).with_restitution(1.0);
+        let mut a = SpaceObject::new(2.0);
+        a.velocity = DVec3::X;
+        let mut b = SpaceObject::new(1.0);
+        b.velocity = -DVec3::X;
+        let entity = Entity::from_raw(0);
+        let mut bodies = [
+            CollisionBody::new(entity, &sphere, &a, None, DVec3::ZERO, Quat::IDENTITY, false),
+            CollisionBody::new(entity, &sphere, &b, None, DVec3::new(1.9, 0.0, 0.0), Quat::IDENTITY, false),
+        ];
+        let events = resolve_collisions(&mut bodies);
+        assert_eq!(events.len(), 1);
+        let momentum = bodies[0].velocity * 2.0 + bodies[1].velocity;
+        assert!((momentum - DVec3::X).length() < 1e-9);
+        assert!(bodies[1].velocity.x > bodies[0].velocity.x);
+    }
+
+    #[test]
+    fn off_centre_hit_spins_around_the_center_of_mass() {
+        // centre of mass half a metre off the local X axis, which the rotation turns to world +Y
+        let sphere = Collider::sphere(1.0).with_restitution(1.0).with_friction(0.0);
+        let mass_properties = MassProperties {
+            mass: 2.0,
+            center_of_mass: DVec3::new(0.5, 0.0, 0.0),
+            inertia: DMat3::IDENTITY,
+            inverse_inertia: DMat3::IDENTITY,
+        };
+        let a = SpaceObject::new(2.0);
+        let mut b = SpaceObject::new(1.0);
+        b.velocity = -DVec3::X;
+        let entity = Entity::from_raw(0);
+        let rotation = Quat::from_rotation_z(std::f32::consts::FRAC_PI_2);
+        let mut bodies = [
+            CollisionBody::new(entity, &sphere, &a, Some(&mass_properties), DVec3::ZERO, rotation, false),
+            CollisionBody::new(entity, &sphere, &b, None, DVec3::new(1.9, 0.0, 0.0), Quat::IDENTITY, false),
+        ];
+        assert!((bodies[0].center_of_mass - DVec3::new(0.0, 0.5, 0.0)).length() < 1e-6);
+        resolve_collisions(&mut bodies);
+
+        // pushed towards -X below its centre of mass, the object turns clockwise around Z
+        let spin = bodies[0].angular_velocity;
+        assert!(spin.z < 0.0 && spin.x.abs() < 1e-9 && spin.y.abs() < 1e-9, "{spin}");
+        let momentum = bodies[0].velocity * 2.0 + bodies[1].velocity;
+        assert!((momentum + DVec3::X).length() < 1e-9);
+        let energy = bodies[0].velocity.length_squared() + 0.5 * spin.length_squared() + 0.5 * bodies[1].velocity.length_squared();
+        assert!((energy - 0.5).abs() < 1e-9, "elastic hit changed the energy to {energy}");
+    }
+}
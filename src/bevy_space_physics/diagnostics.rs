@@ -0,0 +1,379 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, DiagnosticsStore, RegisterDiagnostic},
+    math::{DMat3, DVec3},
+    prelude::*,
+};
+
+use big_space::{
+    precision::GridPrecision,
+    reference_frame::local_origin::ReferenceFrames,
+    world_query::GridTransformReadOnly,
+};
+
+use super::body_frame::BodyRotation;
+use super::mass::MassProperties;
+use super::orbit::KeplerOrbit;
+use super::physics::{GravityPoint, PhysicsSet, PhysicsSettings, SpaceObject, G};
+use super::time_warp::Thrusting;
+
+pub const KINETIC_ENERGY: DiagnosticPath = DiagnosticPath::const_new("space_physics/kinetic_energy");
+pub const POTENTIAL_ENERGY: DiagnosticPath = DiagnosticPath::const_new("space_physics/potential_energy");
+pub const TOTAL_ENERGY: DiagnosticPath = DiagnosticPath::const_new("space_physics/total_energy");
+pub const ENERGY_DRIFT: DiagnosticPath = DiagnosticPath::const_new("space_physics/energy_drift");
+pub const LINEAR_MOMENTUM: DiagnosticPath = DiagnosticPath::const_new("space_physics/linear_momentum");
+pub const LINEAR_MOMENTUM_DRIFT: DiagnosticPath = DiagnosticPath::const_new("space_physics/linear_momentum_drift");
+pub const ANGULAR_MOMENTUM: DiagnosticPath = DiagnosticPath::const_new("space_physics/angular_momentum");
+pub const ANGULAR_MOMENTUM_DRIFT: DiagnosticPath = DiagnosticPath::const_new("space_physics/angular_momentum_drift");
+
+// Measures the energy and momenta of every `SpaceObject` after each fixed step and how far they
+// moved from the first measurement, in Bevy's `DiagnosticsStore` and optionally on screen.
+// Energy drift is relative to the first total energy, momentum drifts are absolute. Objects under
+// `Thrusting` are left out, their engines change the totals on purpose, and the drift is measured
+// again from scratch whenever an object starts or stops thrusting. Bodies driven by a `KeplerOrbit`
+// or a `BodyRotation` are left out as well, they are moved along their rails and not by the forces
+// measured here, so a system with them could never show a flat drift.
+#[derive(Default)]
+pub struct ConservationDiagnosticsPlugin {
+    pub hud: bool,
+}
+
+pub struct ConservationDiagnosticsPluginBigSpace<P: GridPrecision> {
+    pub hud: bool,
+    _precision: PhantomData<P>,
+}
+
+impl<P: GridPrecision> ConservationDiagnosticsPluginBigSpace<P> {
+    pub fn new(hud: bool) -> Self {
+        ConservationDiagnosticsPluginBigSpace { hud, _precision: PhantomData }
+    }
+}
+
+impl<P: GridPrecision> Default for ConservationDiagnosticsPluginBigSpace<P> {
+    fn default() -> Self {
+        ConservationDiagnosticsPluginBigSpace::new(false)
+    }
+}
+
+fn build_conservation_diagnostics(app: &mut App, hud: bool) {
+    app
+        .init_resource::<ConservationDiagnostics>()
+        .register_diagnostic(Diagnostic::new(KINETIC_ENERGY).with_suffix(" J"))
+        .register_diagnostic(Diagnostic::new(POTENTIAL_ENERGY).with_suffix(" J"))
+        .register_diagnostic(Diagnostic::new(TOTAL_ENERGY).with_suffix(" J"))
+        .register_diagnostic(Diagnostic::new(ENERGY_DRIFT))
+        .register_diagnostic(Diagnostic::new(LINEAR_MOMENTUM).with_suffix(" kg m/s"))
+        .register_diagnostic(Diagnostic::new(LINEAR_MOMENTUM_DRIFT).with_suffix(" kg m/s"))
+        .register_diagnostic(Diagnostic::new(ANGULAR_MOMENTUM).with_suffix(" kg m2/s"))
+        .register_diagnostic(Diagnostic::new(ANGULAR_MOMENTUM_DRIFT).with_suffix(" kg m2/s"));
+    if hud {
+        app
+            .add_systems(Startup, setup_conservation_text)
+            .add_systems(Update, update_conservation_text);
+    }
+}
+
+impl Plugin for ConservationDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        build_conservation_diagnostics(app, self.hud);
+        app.add_systems(FixedUpdate, measure_conservation.after(PhysicsSet));
+    }
+}
+
+impl<P: GridPrecision> Plugin for ConservationDiagnosticsPluginBigSpace<P> {
+    fn build(&self, app: &mut App) {
+        build_conservation_diagnostics(app, self.hud);
+        app.add_systems(FixedUpdate, measure_conservation_big_space::<P>.after(PhysicsSet));
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MeasuredBody {
+    pub mass: f64,
+    pub position: DVec3,
+    pub velocity: DVec3,
+    pub angular_velocity: DVec3,
+    pub inertia: DMat3,  // world axes, about the centre of mass
+    pub is_gravity_point: bool,
+}
+
+// Totals of a system of objects. Only gravity points attract, so the potential energy has the
+// pairs with at least one of them, and the pairs of two only when they attract each other. Pairs
+// with a body that was left out of `bodies` are not counted, so the potential of a thrusting ship
+// or of a planet on rails drops out of the total together with its kinetic energy.
+// Angular momentum is taken about the origin and includes the spin of objects with an inertia.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConservedQuantities {
+    pub kinetic_energy: f64,
+    pub potential_energy: f64,
+    pub linear_momentum: DVec3,
+    pub angular_momentum: DVec3,
+}
+
+impl ConservedQuantities {
+    pub fn measure(bodies: &[MeasuredBody], n_body: bool) -> Self {
+        let mut quantities = ConservedQuantities::default();
+        for (i, body) in bodies.iter().enumerate() {
+            let spin = body.inertia * body.angular_velocity;
+            quantities.kinetic_energy += 0.5 * body.mass * body.velocity.length_squared() + 0.5 * body.angular_velocity.dot(spin);
+            quantities.linear_momentum += body.mass * body.velocity;
+            quantities.angular_momentum += body.mass * body.position.cross(body.velocity) + spin;
+
+            for other in &bodies[i + 1..] {
+                let attracting = match (body.is_gravity_point, other.is_gravity_point) {
+                    (true, true) => n_body,
+                    (false, false) => false,
+                    _ => true,
+                };
+                let distance = body.position.distance(other.position);
+                if attracting && distance > 0.0 {
+                    quantities.potential_energy -= G * body.mass * other.mass / distance;
+                }
+            }
+        }
+        quantities
+    }
+
+    pub fn total_energy(&self) -> f64 {
+        self.kinetic_energy + self.potential_energy
+    }
+}
+
+#[derive(Resource, Default, Debug)]
+pub struct ConservationDiagnostics {
+    pub initial: Option<ConservedQuantities>,
+    pub current: ConservedQuantities,
+    thrusting: Vec<Entity>,  // left out of the last measurement
+    on_rails: usize,
+}
+
+impl ConservationDiagnostics {
+    // Drift is measured again from the next step, after the system was changed on purpose.
+    pub fn reset(&mut self) {
+        self.initial = None;
+    }
+
+    // Objects left out of the last measurement, because they were thrusting or on rails.
+    pub fn left_out(&self) -> (usize, usize) {
+        (self.thrusting.len(), self.on_rails)
+    }
+
+    pub fn energy_drift(&self) -> f64 {
+        let Some(initial) = self.initial else { return 0.0 };
+        let initial_energy = initial.total_energy();
+        if initial_energy == 0.0 {
+            return 0.0;
+        }
+        (self.current.total_energy() - initial_energy) / initial_energy.abs()
+    }
+
+    pub fn linear_momentum_drift(&self) -> f64 {
+        self.initial.map_or(0.0, |initial| (self.current.linear_momentum - initial.linear_momentum).length())
+    }
+
+    pub fn angular_momentum_drift(&self) -> f64 {
+        self.initial.map_or(0.0, |initial| (self.current.angular_momentum - initial.angular_momentum).length())
+    }
+}
+
+fn measured_body(object: &SpaceObject, mass_properties: Option<&MassProperties>, position: DVec3, rotation: Quat, is_gravity_point: bool) -> MeasuredBody {
+    let inertia = mass_properties.map_or(DMat3::ZERO, |mass_properties| {
+        let rotation = DMat3::from_quat(rotation.as_dquat());
+        rotation * mass_properties.inertia * rotation.transpose()
+    });
+    MeasuredBody {
+        mass: object.mass,
+        position,
+        velocity: object.velocity,
+        angular_velocity: object.angular_velocity.as_dvec3(),
+        inertia,
+        is_gravity_point,
+    }
+}
+
+fn record_conservation(
+    diagnostics: &mut Diagnostics,
+    conservation: &mut ConservationDiagnostics,
+    quantities: ConservedQuantities,
+    mut thrusting: Vec<Entity>,
+    on_rails: usize,
+) {
+    thrusting.sort();
+    if thrusting != conservation.thrusting {
+        conservation.thrusting = thrusting;
+        conservation.reset();
+    }
+    conservation.on_rails = on_rails;
+    conservation.current = quantities;
+    if conservation.initial.is_none() {
+        conservation.initial = Some(quantities);
+    }
+    diagnostics.add_measurement(&KINETIC_ENERGY, || quantities.kinetic_energy);
+    diagnostics.add_measurement(&POTENTIAL_ENERGY, || quantities.potential_energy);
+    diagnostics.add_measurement(&TOTAL_ENERGY, || quantities.total_energy());
+    diagnostics.add_measurement(&ENERGY_DRIFT, || conservation.energy_drift());
+    diagnostics.add_measurement(&LINEAR_MOMENTUM, || quantities.linear_momentum.length());
+    diagnostics.add_measurement(&LINEAR_MOMENTUM_DRIFT, || conservation.linear_momentum_drift());
+    diagnostics.add_measurement(&ANGULAR_MOMENTUM, || quantities.angular_momentum.length());
+    diagnostics.add_measurement(&ANGULAR_MOMENTUM_DRIFT, || conservation.angular_momentum_drift());
+}
+
+#[allow(clippy::type_complexity)]
+fn measure_conservation(
+    mut diagnostics: Diagnostics,
+    mut conservation: ResMut<ConservationDiagnostics>,
+    settings: Res<PhysicsSettings>,
+    object_query: Query<(&SpaceObject, Entity, &Transform, Option<&MassProperties>, Has<GravityPoint>, Option<&Thrusting>), (Without<KeplerOrbit>, Without<BodyRotation>)>,
+    rails_query: Query<(), (With<SpaceObject>, Or<(With<KeplerOrbit>, With<BodyRotation>)>)>,
+) {
+    let mut thrusting = Vec::new();
+    let bodies: Vec<MeasuredBody> = object_query
+        .iter()
+        .filter_map(|(object, entity, transform, mass_properties, is_gravity_point, thrust)| {
+            if thrust.is_some_and(|thrust| thrust.0) {
+                thrusting.push(entity);
+                return None;
+            }
+            Some(measured_body(object, mass_properties, transform.translation.as_dvec3(), transform.rotation, is_gravity_point))
+        })
+        .collect();
+    record_conservation(&mut diagnostics, &mut conservation, ConservedQuantities::measure(&bodies, settings.n_body), thrusting, rails_query.iter().count());
+}
+
+#[allow(clippy::type_complexity)]
+fn measure_conservation_big_space<P: GridPrecision>(
+    mut diagnostics: Diagnostics,
+    mut conservation: ResMut<ConservationDiagnostics>,
+    settings: Res<PhysicsSettings>,
+    frames: ReferenceFrames<P>,
+    object_query: Query<(&SpaceObject, Entity, GridTransformReadOnly<P>, Option<&MassProperties>, Has<GravityPoint>, Option<&Thrusting>), (Without<KeplerOrbit>, Without<BodyRotation>)>,
+    rails_query: Query<(), (With<SpaceObject>, Or<(With<KeplerOrbit>, With<BodyRotation>)>)>,
+) {
+    let mut thrusting = Vec::new();
+    let bodies: Vec<MeasuredBody> = object_query
+        .iter()
+        .filter_map(|(object, entity, grid_transform, mass_properties, is_gravity_point, thrust)| {
+            if thrust.is_some_and(|thrust| thrust.0) {
+                thrusting.push(entity);
+                return None;
+            }
+            let reference_frame = frames.parent_frame(entity)?;
+            let position = grid_transform.position_double(reference_frame);
+            Some(measured_body(object, mass_properties, position, grid_transform.transform.rotation, is_gravity_point))
+        })
+        .collect();
+    record_conservation(&mut diagnostics, &mut conservation, ConservedQuantities::measure(&bodies, settings.n_body), thrusting, rails_query.iter().count());
+}
+
+#[derive(Component)]
+pub struct ConservationText;
+
+fn setup_conservation_text(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            right: Val::Px(15.0),
+            ..default()
+        }),
+        ConservationText,
+    ));
+}
+
+fn update_conservation_text(
+    store: Res<DiagnosticsStore>,
+    conservation: Res<ConservationDiagnostics>,
+    mut text_query: Query<&mut Text, With<ConservationText>>,
+) {
+    let Ok(mut text) = text_query.get_single_mut() else { return };
+    let value = |path: &DiagnosticPath| store.get(path).and_then(|diagnostic| diagnostic.value()).unwrap_or(0.0);
+
+    let total_energy = value(&TOTAL_ENERGY);
+    let energy_drift = value(&ENERGY_DRIFT);
+    let linear_momentum = value(&LINEAR_MOMENTUM);
+    let linear_momentum_drift = value(&LINEAR_MOMENTUM_DRIFT);
+    let angular_momentum = value(&ANGULAR_MOMENTUM);
+    let angular_momentum_drift = value(&ANGULAR_MOMENTUM_DRIFT);
+    let (thrusting, on_rails) = conservation.left_out();
+    text.sections[0].value = format!(
+        "Energy: {total_energy:.6e} J (drift {energy_drift:.3e})\nMomentum: {linear_momentum:.6e} kg m/s (drift {linear_momentum_drift:.3e})\nAngular momentum: {angular_momentum:.6e} kg m2/s (drift {angular_momentum_drift:.3e})\nLeft out: {on_rails} on rails, {thrusting} thrusting"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(mass: f64, position: DVec3, velocity: DVec3, is_gravity_point: bool) -> MeasuredBody {
+        MeasuredBody { mass, position, velocity, angular_velocity: DVec3::ZERO, inertia: DMat3::ZERO, is_gravity_point }
+    }
+
+    #[test]
+    fn circular_orbit_has_half_the_potential_energy_as_kinetic() {
+        let (mass, radius) = (5.972e24, 7_000_000.0);
+        let speed = (G * mass / radius).sqrt();
+        let bodies = [
+            body(mass, DVec3::ZERO, DVec3::ZERO, true),
+            body(1000.0, DVec3::new(radius, 0.0, 0.0), DVec3::new(0.0, 0.0, -speed), false),
+        ];
+        let quantities = ConservedQuantities::measure(&bodies, false);
+        assert!((quantities.total_energy() + quantities.kinetic_energy).abs() < 1e-6 * quantities.kinetic_energy);
+        assert_eq!(quantities.linear_momentum, DVec3::new(0.0, 0.0, -1000.0 * speed));
+        let angular_momentum = 1000.0 * radius * speed;
+        assert!((quantities.angular_momentum - DVec3::new(0.0, angular_momentum, 0.0)).length() < 1e-12 * angular_momentum);
+    }
+
+    #[test]
+    fn objects_do_not_attract_each_other() {
+        let bodies = [
+            body(1000.0, DVec3::ZERO, DVec3::ZERO, false),
+            body(1000.0, DVec3::X, DVec3::ZERO, false),
+            body(1.0e20, DVec3::Y, DVec3::ZERO, true),
+            body(1.0e20, DVec3::NEG_Y, DVec3::ZERO, true),
+        ];
+        let isolated = ConservedQuantities::measure(&bodies, false).potential_energy;
+        let mutual = ConservedQuantities::measure(&bodies, true).potential_energy;
+        // only the pair of gravity points two metres apart is added
+        assert!((isolated - mutual - G * 1.0e40 / 2.0).abs() < 1e-9 * G * 1.0e40);
+    }
+
+    #[test]
+    fn bodies_on_rails_are_left_out() {
+        let mut app = App::new();
+        app
+            .init_resource::<PhysicsSettings>()
+            .add_plugins(ConservationDiagnosticsPlugin::default());
+        let sun = app.world_mut().spawn((
+            SpaceObject::new(1.989e30),
+            GravityPoint,
+            Transform::default(),
+        )).id();
+        app.world_mut().spawn((
+            SpaceObject { velocity: DVec3::new(0.0, 0.0, -29_780.0), ..SpaceObject::new(5.972e24) },
+            GravityPoint,
+            KeplerOrbit::circular(sun, 1.496e11),
+            BodyRotation::new(86_164.090_5, DVec3::Y),
+            Transform::from_xyz(1.496e11, 0.0, 0.0),
+        ));
+        app.world_mut().spawn((
+            SpaceObject { velocity: DVec3::new(0.0, 0.0, -10.0), ..SpaceObject::new(1000.0) },
+            Transform::from_xyz(0.0, 1.0e9, 0.0),
+        ));
+        app.world_mut().run_schedule(FixedUpdate);
+
+        let conservation = app.world().resource::<ConservationDiagnostics>();
+        assert_eq!(conservation.left_out(), (0, 1));
+        // the planet on rails takes its kinetic energy and its pairs with it
+        assert_eq!(conservation.current.kinetic_energy, 0.5 * 1000.0 * 100.0);
+        assert_eq!(conservation.current.potential_energy, -G * 1.989e30 * 1000.0 / 1.0e9);
+    }
+}
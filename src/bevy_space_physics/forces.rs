@@ -0,0 +1,139 @@
+use bevy::{
+    math::DVec3,
+    prelude::*,
+};
+
+use super::mass::MassProperties;
+use super::physics::SpaceObject;
+
+// Forces and torques pushing an object during the current fixed step, in world axes and about
+// its centre of mass. Systems add to it in `FixedUpdate` before `PhysicsSet`, every substep turns
+// it into the `acceleration` and `angular_acceleration` of the `SpaceObject`, and it is cleared
+// once the step is done. Impulses change the velocities once, at the start of the step.
+// Torques and angular impulses need `MassProperties` on the object, otherwise they are ignored.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct ExternalForces {
+    pub force: DVec3,
+    pub torque: DVec3,
+    pub impulse: DVec3,
+    pub angular_impulse: DVec3,
+}
+
+impl ExternalForces {
+    pub fn apply_force(&mut self, force: DVec3) {
+        self.force += force;
+    }
+
+    // `point` is relative to the centre of mass, in world axes.
+    pub fn apply_force_at_point(&mut self, force: DVec3, point: DVec3) {
+        self.force += force;
+        self.torque += point.cross(force);
+    }
+
+    pub fn apply_torque(&mut self, torque: DVec3) {
+        self.torque += torque;
+    }
+
+    pub fn apply_impulse(&mut self, impulse: DVec3) {
+        self.impulse += impulse;
+    }
+
+    pub fn apply_impulse_at_point(&mut self, impulse: DVec3, point: DVec3) {
+        self.impulse += impulse;
+        self.angular_impulse += point.cross(impulse);
+    }
+
+    pub fn clear(&mut self) {
+        *self = ExternalForces::default();
+    }
+
+    // Linear and angular acceleration in world axes, the angular one includes the gyroscopic
+    // coupling of a spinning object and is `None` without mass properties.
+    pub fn accelerations(
+        &self,
+        mass: f64,
+        mass_properties: Option<&MassProperties>,
+        rotation: Quat,
+        angular_velocity: Vec3,
+    ) -> (DVec3, Option<DVec3>) {
+        let acceleration = if mass > 0.0 { self.force / mass } else { DVec3::ZERO };
+        let angular_acceleration = mass_properties.map(|mass_properties| {
+            let rotation = rotation.as_dquat();
+            let torque = rotation.inverse() * self.torque;
+            let angular_velocity = rotation.inverse() * angular_velocity.as_dvec3();
+            rotation * mass_properties.angular_acceleration(torque, angular_velocity)
+        });
+        (acceleration, angular_acceleration)
+    }
+
+    // Velocity changes of the pending impulses, in world axes.
+    pub fn velocity_changes(&self, mass: f64, mass_properties: Option<&MassProperties>, rotation: Quat) -> (DVec3, Option<DVec3>) {
+        let velocity_change = if mass > 0.0 { self.impulse / mass } else { DVec3::ZERO };
+        let angular_velocity_change = mass_properties.map(|mass_properties| {
+            let rotation = rotation.as_dquat();
+            rotation * (mass_properties.inverse_inertia * (rotation.inverse() * self.angular_impulse))
+        });
+        (velocity_change, angular_velocity_change)
+    }
+}
+
+pub(super) fn apply_external_forces(
+    mut object_query: Query<(&mut SpaceObject, &mut ExternalForces, &Transform, Option<&MassProperties>)>,
+) {
+    for (mut object, mut forces, transform, mass_properties) in object_query.iter_mut() {
+        if forces.impulse != DVec3::ZERO || forces.angular_impulse != DVec3::ZERO {
+            let (velocity_change, angular_velocity_change) = forces.velocity_changes(object.mass, mass_properties, transform.rotation);
+            object.velocity += velocity_change;
+            if let Some(angular_velocity_change) = angular_velocity_change {
+                object.angular_velocity += angular_velocity_change.as_vec3();
+            }
+            forces.impulse = DVec3::ZERO;
+            forces.angular_impulse = DVec3::ZERO;
+        }
+
+        let (acceleration, angular_acceleration) = forces.accelerations(object.mass, mass_properties, transform.rotation, object.angular_velocity);
+        object.acceleration = acceleration;
+        if let Some(angular_acceleration) = angular_acceleration {
+            object.angular_acceleration = angular_acceleration.as_vec3();
+        }
+    }
+}
+
+pub(super) fn clear_external_forces(mut forces_query: Query<&mut ExternalForces>) {
+    for mut forces in forces_query.iter_mut() {
+        forces.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mass::{MassPart, PartShape};
+
+    #[test]
+    fn force_at_point_adds_torque_about_centre_of_mass() {
+        let mut forces = ExternalForces::default();
+        forces.apply_force_at_point(DVec3::new(0.0, 10.0, 0.0), DVec3::new(2.0, 0.0, 0.0));
+        forces.apply_force(DVec3::new(0.0, 0.0, 5.0));
+        assert_eq!(forces.force, DVec3::new(0.0, 10.0, 5.0));
+        assert_eq!(forces.torque, DVec3::new(0.0, 0.0, 20.0));
+        forces.clear();
+        assert_eq!(forces.force, DVec3::ZERO);
+        assert_eq!(forces.torque, DVec3::ZERO);
+    }
+
+    #[test]
+    fn torque_turns_a_rotated_object_around_the_world_axis() {
+        let part = MassPart::new(12.0, PartShape::Cuboid { size: DVec3::new(1.0, 1.0, 1.0) });
+        let mass_properties = MassProperties::from_parts([(part, Transform::IDENTITY)]);
+        let mut forces = ExternalForces::default();
+        forces.apply_torque(DVec3::new(0.0, 0.0, 2.0));
+
+        let rotation = Quat::from_rotation_x(std::f32::consts::FRAC_PI_2);
+        let (acceleration, angular_acceleration) = forces.accelerations(12.0, Some(&mass_properties), rotation, Vec3::ZERO);
+        assert_eq!(acceleration, DVec3::ZERO);
+        // the cube has an inertia of 2 around every axis
+        assert!((angular_acceleration.unwrap() - DVec3::new(0.0, 0.0, 1.0)).length() < 1e-6);
+        assert_eq!(forces.accelerations(12.0, None, rotation, Vec3::ZERO).1, None);
+    }
+}
@@ -0,0 +1,210 @@
+use bevy::{math::DVec3, prelude::*};
+
+// Numerical scheme used to advance translational motion. Set globally through
+// `PhysicsSettings::integrator`, or insert it on an entity to override it for that object.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Integrator {
+    ExplicitEuler,
+    SemiImplicitEuler,
+    #[default]
+    VelocityVerlet,
+    Rk4,
+    Yoshida4,
+}
+
+impl Integrator {
+    // Advances a body starting at `position` with `velocity` by `dt`. `acceleration` is
+    // evaluated at intermediate positions, so it has to be a function of position only.
+    pub fn step(
+        &self,
+        position: DVec3,
+        velocity: DVec3,
+        dt: f64,
+        acceleration: impl Fn(DVec3) -> DVec3,
+    ) -> (DVec3, DVec3) {
+        match self {
+            Integrator::ExplicitEuler => {
+                let a = acceleration(position);
+                (position + velocity * dt, velocity + a * dt)
+            }
+            Integrator::SemiImplicitEuler => {
+                let velocity = velocity + acceleration(position) * dt;
+                (position + velocity * dt, velocity)
+            }
+            Integrator::VelocityVerlet => {
+                let a0 = acceleration(position);
+                let position = position + velocity * dt + 0.5 * a0 * dt * dt;
+                let a1 = acceleration(position);
+                (position, velocity + 0.5 * (a0 + a1) * dt)
+            }
+            Integrator::Rk4 => {
+                let k1_x = velocity;
+                let k1_v = acceleration(position);
+                let k2_x = velocity + k1_v * dt / 2.0;
+                let k2_v = acceleration(position + k1_x * dt / 2.0);
+                let k3_x = velocity + k2_v * dt / 2.0;
+                let k3_v = acceleration(position + k2_x * dt / 2.0);
+                let k4_x = velocity + k3_v * dt;
+                let k4_v = acceleration(position + k3_x * dt);
+                (
+                    position + (k1_x + 2.0 * k2_x + 2.0 * k3_x + k4_x) * dt / 6.0,
+                    velocity + (k1_v + 2.0 * k2_v + 2.0 * k3_v + k4_v) * dt / 6.0,
+                )
+            }
+            Integrator::Yoshida4 => {
+                // 4th order symplectic composition of three leapfrog steps
+                let cbrt_2 = 2f64.cbrt();
+                let w1 = 1.0 / (2.0 - cbrt_2);
+                let w0 = -cbrt_2 / (2.0 - cbrt_2);
+                let c = [w1 / 2.0, (w0 + w1) / 2.0, (w0 + w1) / 2.0, w1 / 2.0];
+                let d = [w1, w0, w1];
+
+                let (mut position, mut velocity) = (position, velocity);
+                for (&c, d) in c.iter().zip(d) {
+                    position += c * velocity * dt;
+                    velocity += d * acceleration(position) * dt;
+                }
+                (position + c[3] * velocity * dt, velocity)
+            }
+        }
+    }
+
+    // Same schemes for a coupled system, `acceleration` fills accelerations of all bodies
+    // for the given positions at once. Used for mutual gravity of `GravityPoint`s.
+    pub fn step_system(
+        &self,
+        positions: &mut [DVec3],
+        velocities: &mut [DVec3],
+        dt: f64,
+        acceleration: impl Fn(&[DVec3], &mut [DVec3]),
+    ) {
+        let n = positions.len();
+        let mut a0 = vec![DVec3::ZERO; n];
+        match self {
+            Integrator::ExplicitEuler => {
+                acceleration(positions, &mut a0);
+                for i in 0..n {
+                    positions[i] += velocities[i] * dt;
+                    velocities[i] += a0[i] * dt;
+                }
+            }
+            Integrator::SemiImplicitEuler => {
+                acceleration(positions, &mut a0);
+                for i in 0..n {
+                    velocities[i] += a0[i] * dt;
+                    positions[i] += velocities[i] * dt;
+                }
+            }
+            Integrator::VelocityVerlet => {
+                acceleration(positions, &mut a0);
+                for i in 0..n {
+                    positions[i] += velocities[i] * dt + 0.5 * a0[i] * dt * dt;
+                }
+                let mut a1 = vec![DVec3::ZERO; n];
+                acceleration(positions, &mut a1);
+                for i in 0..n {
+                    velocities[i] += 0.5 * (a0[i] + a1[i]) * dt;
+                }
+            }
+            Integrator::Rk4 => {
+                let x0 = positions.to_vec();
+                let v0 = velocities.to_vec();
+                let mut sum_x = vec![DVec3::ZERO; n];
+                let mut sum_v = vec![DVec3::ZERO; n];
+                let mut stage_x = x0.clone();
+                let mut stage_v = v0.clone();
+                let mut stage_a = vec![DVec3::ZERO; n];
+                for (weight, next_step) in [(1.0, 0.5), (2.0, 0.5), (2.0, 1.0), (1.0, 0.0)] {
+                    acceleration(&stage_x, &mut stage_a);
+                    for i in 0..n {
+                        sum_x[i] += weight * stage_v[i];
+                        sum_v[i] += weight * stage_a[i];
+                    }
+                    let (k_x, k_a) = (stage_v.clone(), stage_a.clone());
+                    for i in 0..n {
+                        stage_x[i] = x0[i] + k_x[i] * dt * next_step;
+                        stage_v[i] = v0[i] + k_a[i] * dt * next_step;
+                    }
+                }
+                for i in 0..n {
+                    positions[i] = x0[i] + sum_x[i] * dt / 6.0;
+                    velocities[i] = v0[i] + sum_v[i] * dt / 6.0;
+                }
+            }
+            Integrator::Yoshida4 => {
+                let cbrt_2 = 2f64.cbrt();
+                let w1 = 1.0 / (2.0 - cbrt_2);
+                let w0 = -cbrt_2 / (2.0 - cbrt_2);
+                let c = [w1 / 2.0, (w0 + w1) / 2.0, (w0 + w1) / 2.0, w1 / 2.0];
+                let d = [w1, w0, w1];
+
+                for (&c, d) in c.iter().zip(d) {
+                    for i in 0..n {
+                        positions[i] += c * velocities[i] * dt;
+                    }
+                    acceleration(positions, &mut a0);
+                    for i in 0..n {
+                        velocities[i] += d * a0[i] * dt;
+                    }
+                }
+                for i in 0..n {
+                    positions[i] += c[3] * velocities[i] * dt;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MU: f64 = 3.986e14;
+    const RADIUS: f64 = 7_000_000.0;
+
+    fn gravity(position: DVec3) -> DVec3 {
+        -MU / position.length().powi(3) * position
+    }
+
+    fn energy(position: DVec3, velocity: DVec3) -> f64 {
+        velocity.length_squared() / 2.0 - MU / position.length()
+    }
+
+    // low orbit of the Earth in the XZ plane, `time` after passing +X
+    fn circular_orbit_at(time: f64) -> (DVec3, DVec3) {
+        let speed = (MU / RADIUS).sqrt();
+        let (sin, cos) = (speed / RADIUS * time).sin_cos();
+        (DVec3::new(cos, 0.0, sin) * RADIUS, DVec3::new(-sin, 0.0, cos) * speed)
+    }
+
+    #[test]
+    fn symplectic_integrators_keep_the_energy_of_an_orbit_bounded() {
+        let period = std::f64::consts::TAU * (RADIUS.powi(3) / MU).sqrt();
+        let dt = 10.0;
+        let steps = (100.0 * period / dt) as usize;
+        for (integrator, tolerance) in [(Integrator::VelocityVerlet, 1e-4), (Integrator::Yoshida4, 1e-8)] {
+            let (mut position, mut velocity) = circular_orbit_at(0.0);
+            let initial_energy = energy(position, velocity);
+            let mut max_error: f64 = 0.0;
+            for _ in 0..steps {
+                (position, velocity) = integrator.step(position, velocity, dt, gravity);
+                max_error = max_error.max(((energy(position, velocity) - initial_energy) / initial_energy).abs());
+            }
+            assert!(max_error < tolerance, "{integrator:?} drifted by {max_error:e}");
+        }
+    }
+
+    #[test]
+    fn rk4_converges_at_fourth_order() {
+        let duration = 5_760.0;
+        let error = |dt: f64| {
+            let (mut position, mut velocity) = circular_orbit_at(0.0);
+            for _ in 0..(duration / dt).round() as usize {
+                (position, velocity) = Integrator::Rk4.step(position, velocity, dt, gravity);
+            }
+            (position - circular_orbit_at(duration).0).length()
+        };
+        let ratio = error(30.0) / error(15.0);
+        assert!((14.0..18.0).contains(&ratio), "halving the step divided the error by {ratio}");
+    }
+}
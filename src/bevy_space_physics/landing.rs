@@ -0,0 +1,344 @@
+use bevy::{
+    math::{DQuat, DVec3},
+    prelude::*,
+};
+
+use big_space::{
+    precision::GridPrecision,
+    reference_frame::local_origin::ReferenceFrames,
+    world_query::{GridTransform, GridTransformReadOnly},
+};
+
+use super::physics::{BodyRadius, GravityPoint, PhysicsTime, SpaceObject, G};
+
+// Touchdown limits of a ship, speeds are relative to the ground and `max_tilt` is the angle in
+// radians between the local up of the ship and the vertical. `clearance` is the height of the
+// ship origin above the ground when resting on its gear. Objects without gear always crash.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct LandingGear {
+    pub max_vertical_speed: f64,
+    pub max_horizontal_speed: f64,
+    pub max_tilt: f64,
+    pub clearance: f64,
+}
+
+impl Default for LandingGear {
+    fn default() -> Self {
+        LandingGear {
+            max_vertical_speed: 3.0,
+            max_horizontal_speed: 1.0,
+            max_tilt: 10f64.to_radians(),
+            clearance: 0.5,
+        }
+    }
+}
+
+// Pins an object to the surface of `body`. `offset` and `rotation` are in the axes of the body,
+// so the object turns with it. Thrust pushing up harder than the surface gravity lifts it off.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Landed {
+    pub body: Entity,
+    pub offset: DVec3,
+    pub rotation: Quat,
+}
+
+#[derive(Event, Clone, Copy, Debug)]
+pub struct LandingEvent {
+    pub entity: Entity,
+    pub body: Entity,
+    pub vertical_speed: f64,  // descent speed at touchdown
+    pub horizontal_speed: f64,
+    pub tilt: f64,
+}
+
+// Touchdown outside of the landing gear limits. The object is stopped on the surface but not
+// `Landed`, what the crash destroys is up to the game.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ImpactEvent {
+    pub entity: Entity,
+    pub body: Entity,
+    pub vertical_speed: f64,
+    pub horizontal_speed: f64,
+    pub tilt: f64,
+}
+
+struct SurfaceBody {
+    entity: Entity,
+    position: DVec3,
+    rotation: DQuat,
+    velocity: DVec3,
+    angular_velocity: DVec3,
+    mass: f64,
+    radius: f64,
+}
+
+impl SurfaceBody {
+    fn new(entity: Entity, object: &SpaceObject, position: DVec3, rotation: Quat, radius: f64) -> Self {
+        SurfaceBody {
+            entity,
+            position,
+            rotation: rotation.as_dquat(),
+            velocity: object.velocity,
+            angular_velocity: object.angular_velocity.as_dvec3(),
+            mass: object.mass,
+            radius,
+        }
+    }
+
+    fn surface_velocity(&self, position: DVec3) -> DVec3 {
+        self.velocity + self.angular_velocity.cross(position - self.position)
+    }
+}
+
+// Moves a landed object along with its body, or checks a free one coming down for touchdown.
+// Outside of the gear limits the object only loses its downward speed and stays free, coming
+// down slower than the surface gravity adds in one substep is resting there and raises no new
+// impact. Returns the new position and rotation of the object when it is on the ground.
+#[allow(clippy::too_many_arguments)]
+fn update_surface_contact(
+    commands: &mut Commands,
+    landing_events: &mut EventWriter<LandingEvent>,
+    impact_events: &mut EventWriter<ImpactEvent>,
+    bodies: &[SurfaceBody],
+    delta_seconds: f64,
+    entity: Entity,
+    object: &mut SpaceObject,
+    position: DVec3,
+    rotation: Quat,
+    gear: Option<&LandingGear>,
+    landed: Option<&Landed>,
+) -> Option<(DVec3, Quat)> {
+    let landed = match landed {
+        Some(landed) => *landed,
+        None => {
+            let clearance = gear.map_or(0.0, |gear| gear.clearance);
+            let body = bodies.iter().find(|body| {
+                let distance = body.position.distance(position);
+                distance > 0.0 && distance < body.radius + clearance
+            })?;
+            let up = (position - body.position).normalize();
+            let relative_velocity = object.velocity - body.surface_velocity(position);
+            let vertical_speed = -relative_velocity.dot(up);
+            if vertical_speed <= 0.0 {
+                return None;
+            }
+            let horizontal_speed = (relative_velocity + up * vertical_speed).length();
+            let tilt = (rotation * Vec3::Y).as_dvec3().angle_between(up);
+
+            let within_limits = gear.is_some_and(|gear| {
+                vertical_speed <= gear.max_vertical_speed && horizontal_speed <= gear.max_horizontal_speed && tilt <= gear.max_tilt
+            });
+            if !within_limits {
+                let contact_radius = body.radius + clearance;
+                let resting = vertical_speed <= G * body.mass / (contact_radius * contact_radius) * delta_seconds;
+                if !resting {
+                    impact_events.send(ImpactEvent { entity, body: body.entity, vertical_speed, horizontal_speed, tilt });
+                }
+                object.velocity += up * vertical_speed;
+                return Some((body.position + up * contact_radius, rotation));
+            }
+            landing_events.send(LandingEvent { entity, body: body.entity, vertical_speed, horizontal_speed, tilt });
+
+            let landed = Landed {
+                body: body.entity,
+                offset: body.rotation.inverse() * up * (body.radius + clearance),
+                rotation: body.rotation.inverse().as_quat() * rotation,
+            };
+            commands.entity(entity).insert(landed);
+            landed
+        }
+    };
+
+    let Some(body) = bodies.iter().find(|body| body.entity == landed.body) else {
+        commands.entity(entity).remove::<Landed>();
+        return None;
+    };
+    let offset = body.rotation * landed.offset;
+    let position = body.position + offset;
+    object.velocity = body.surface_velocity(position);
+    object.angular_velocity = body.angular_velocity.as_vec3();
+
+    let surface_gravity = G * body.mass / offset.length_squared();
+    if object.acceleration.dot(offset.normalize()) > surface_gravity {
+        commands.entity(entity).remove::<Landed>();
+    }
+    Some((position, body.rotation.as_quat() * landed.rotation))
+}
+
+#[allow(clippy::type_complexity)]
+pub(super) fn surface_contacts(
+    mut commands: Commands,
+    mut landing_events: EventWriter<LandingEvent>,
+    mut impact_events: EventWriter<ImpactEvent>,
+    time: Res<PhysicsTime>,
+    body_query: Query<(&SpaceObject, Entity, &Transform, &BodyRadius), With<GravityPoint>>,
+    mut object_query: Query<(&mut SpaceObject, Entity, &mut Transform, Option<&LandingGear>, Option<&Landed>), Without<GravityPoint>>,
+) {
+    let bodies: Vec<SurfaceBody> = body_query
+        .iter()
+        .map(|(object, entity, transform, radius)| SurfaceBody::new(entity, object, transform.translation.as_dvec3(), transform.rotation, radius.0))
+        .collect();
+    if bodies.is_empty() {
+        return;
+    }
+
+    for (mut object, entity, mut transform, gear, landed) in object_query.iter_mut() {
+        let Some((position, rotation)) = update_surface_contact(
+            &mut commands,
+            &mut landing_events,
+            &mut impact_events,
+            &bodies,
+            time.delta_seconds_f64(),
+            entity,
+            &mut object,
+            transform.translation.as_dvec3(),
+            transform.rotation,
+            gear,
+            landed,
+        ) else {
+            continue;
+        };
+        transform.translation = position.as_vec3();
+        transform.rotation = rotation;
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(super) fn surface_contacts_big_space<P: GridPrecision>(
+    mut commands: Commands,
+    mut landing_events: EventWriter<LandingEvent>,
+    mut impact_events: EventWriter<ImpactEvent>,
+    time: Res<PhysicsTime>,
+    frames: ReferenceFrames<P>,
+    body_query: Query<(&SpaceObject, Entity, GridTransformReadOnly<P>, &BodyRadius), With<GravityPoint>>,
+    mut object_query: Query<(&mut SpaceObject, Entity, GridTransform<P>, Option<&LandingGear>, Option<&Landed>), Without<GravityPoint>>,
+) {
+    let bodies: Vec<SurfaceBody> = body_query
+        .iter()
+        .filter_map(|(object, entity, grid_transform, radius)| {
+            let reference_frame = frames.parent_frame(entity)?;
+            let position = grid_transform.position_double(reference_frame);
+            Some(SurfaceBody::new(entity, object, position, grid_transform.transform.rotation, radius.0))
+        })
+        .collect();
+    if bodies.is_empty() {
+        return;
+    }
+
+    for (mut object, entity, mut grid_transform, gear, landed) in object_query.iter_mut() {
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        let Some((position, rotation)) = update_surface_contact(
+            &mut commands,
+            &mut landing_events,
+            &mut impact_events,
+            &bodies,
+            time.delta_seconds_f64(),
+            entity,
+            &mut object,
+            grid_transform.position_double(reference_frame),
+            grid_transform.transform.rotation,
+            gear,
+            landed,
+        ) else {
+            continue;
+        };
+        let (cell, translation) = reference_frame.translation_to_grid(position);
+        *grid_transform.cell = cell;
+        grid_transform.transform.translation = translation;
+        grid_transform.transform.rotation = rotation;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Touchdown {
+        landings: Vec<LandingEvent>,
+        impacts: Vec<ImpactEvent>,
+        landed: bool,
+        velocity: DVec3,
+        translation: Vec3,
+    }
+
+    // Brings a ship with default gear down on a small body at `velocity` and `rotation`.
+    fn touch_down(velocity: DVec3, rotation: Quat) -> Touchdown {
+        let mut app = App::new();
+        app
+            .init_resource::<PhysicsTime>()
+            .add_event::<LandingEvent>()
+            .add_event::<ImpactEvent>()
+            .add_systems(Update, surface_contacts);
+        app.world_mut().spawn((SpaceObject::new(1.0e9), Transform::default(), BodyRadius(1_000.0), GravityPoint));
+        let ship = app
+            .world_mut()
+            .spawn((
+                SpaceObject { velocity, ..SpaceObject::new(1_000.0) },
+                Transform::from_xyz(0.0, 1_000.2, 0.0).with_rotation(rotation),
+                LandingGear::default(),
+            ))
+            .id();
+        app.update();
+
+        Touchdown {
+            landings: app.world_mut().resource_mut::<Events<LandingEvent>>().drain().collect(),
+            impacts: app.world_mut().resource_mut::<Events<ImpactEvent>>().drain().collect(),
+            landed: app.world().get::<Landed>(ship).is_some(),
+            velocity: app.world().get::<SpaceObject>(ship).unwrap().velocity,
+            translation: app.world().get::<Transform>(ship).unwrap().translation,
+        }
+    }
+
+    #[test]
+    fn gentle_touchdown_lands() {
+        let touchdown = touch_down(DVec3::new(0.5, -2.0, 0.0), Quat::from_rotation_z(5f32.to_radians()));
+        assert_eq!(touchdown.landings.len(), 1);
+        assert!((touchdown.landings[0].vertical_speed - 2.0).abs() < 1e-9);
+        assert!((touchdown.landings[0].horizontal_speed - 0.5).abs() < 1e-9);
+        assert!(touchdown.impacts.is_empty());
+        assert!(touchdown.landed);
+    }
+
+    #[test]
+    fn fast_descent_is_an_impact() {
+        let touchdown = touch_down(DVec3::new(0.0, -5.0, 0.0), Quat::IDENTITY);
+        assert!(touchdown.landings.is_empty());
+        assert_eq!(touchdown.impacts.len(), 1);
+        assert!((touchdown.impacts[0].vertical_speed - 5.0).abs() < 1e-9);
+        // stopped on the gear, but not landed
+        assert!(!touchdown.landed);
+        assert_eq!(touchdown.velocity, DVec3::ZERO);
+        assert!((touchdown.translation.y - 1_000.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn sliding_touchdown_is_an_impact() {
+        let touchdown = touch_down(DVec3::new(2.0, -1.0, 0.0), Quat::IDENTITY);
+        assert!(touchdown.landings.is_empty());
+        assert_eq!(touchdown.impacts.len(), 1);
+        assert!((touchdown.impacts[0].horizontal_speed - 2.0).abs() < 1e-9);
+        // keeps sliding along the ground
+        assert!(!touchdown.landed);
+        assert_eq!(touchdown.velocity, DVec3::new(2.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn tilted_touchdown_is_an_impact() {
+        let touchdown = touch_down(DVec3::new(0.0, -1.0, 0.0), Quat::from_rotation_z(20f32.to_radians()));
+        assert!(touchdown.landings.is_empty());
+        assert_eq!(touchdown.impacts.len(), 1);
+        assert!((touchdown.impacts[0].tilt - 20f64.to_radians()).abs() < 1e-6);
+        assert!(!touchdown.landed);
+    }
+
+    #[test]
+    fn climbing_away_from_the_ground_is_no_touchdown() {
+        let touchdown = touch_down(DVec3::new(0.0, 1.0, 0.0), Quat::IDENTITY);
+        assert!(touchdown.landings.is_empty());
+        assert!(touchdown.impacts.is_empty());
+        assert!(!touchdown.landed);
+        assert_eq!(touchdown.velocity, DVec3::new(0.0, 1.0, 0.0));
+    }
+}
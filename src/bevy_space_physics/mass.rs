@@ -0,0 +1,154 @@
+use bevy::{
+    math::{DMat3, DVec3},
+    prelude::*,
+};
+
+use super::physics::SpaceObject;
+
+// Solid shapes of parts, centred at the part origin and in its axes.
+#[derive(Clone, Copy, Debug)]
+pub enum PartShape {
+    Point,
+    Sphere { radius: f64 },
+    Cuboid { size: DVec3 },
+}
+
+impl PartShape {
+    pub fn inertia(&self, mass: f64) -> DMat3 {
+        match *self {
+            PartShape::Point => DMat3::ZERO,
+            PartShape::Sphere { radius } => DMat3::from_diagonal(DVec3::splat(0.4 * mass * radius * radius)),
+            PartShape::Cuboid { size } => {
+                let size = size * size;
+                DMat3::from_diagonal(DVec3::new(size.y + size.z, size.x + size.z, size.x + size.y) * mass / 12.0)
+            }
+        }
+    }
+}
+
+// Insert on an object with `MassProperties` or on its direct children, placed by their `Transform`.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct MassPart {
+    pub mass: f64,
+    pub shape: PartShape,
+}
+
+impl MassPart {
+    pub fn new(mass: f64, shape: PartShape) -> Self {
+        MassPart { mass, shape }
+    }
+}
+
+// Mass, centre of mass and inertia tensor about it, aggregated from the `MassPart`s of an object
+// every fixed step. Everything is in the axes of the object, the total mass is written to its
+// `SpaceObject`.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct MassProperties {
+    pub mass: f64,
+    pub center_of_mass: DVec3,
+    pub inertia: DMat3,
+    pub inverse_inertia: DMat3,
+}
+
+impl Default for MassProperties {
+    fn default() -> Self {
+        MassProperties {
+            mass: 0.0,
+            center_of_mass: DVec3::ZERO,
+            inertia: DMat3::ZERO,
+            inverse_inertia: DMat3::ZERO,
+        }
+    }
+}
+
+impl MassProperties {
+    // Parts are moved to the common centre of mass with the parallel axis theorem.
+    pub fn from_parts(parts: impl IntoIterator<Item = (MassPart, Transform)>) -> Self {
+        let parts: Vec<(MassPart, Transform)> = parts.into_iter().collect();
+        let mass: f64 = parts.iter().map(|(part, _)| part.mass).sum();
+        if mass <= 0.0 {
+            return MassProperties::default();
+        }
+        let center_of_mass = parts
+            .iter()
+            .map(|(part, transform)| transform.translation.as_dvec3() * part.mass)
+            .sum::<DVec3>()
+            / mass;
+
+        let mut inertia = DMat3::ZERO;
+        for (part, transform) in parts.iter() {
+            let rotation = DMat3::from_quat(transform.rotation.as_dquat());
+            let offset = transform.translation.as_dvec3() - center_of_mass;
+            let outer_product = DMat3::from_cols(offset * offset.x, offset * offset.y, offset * offset.z);
+            inertia += rotation * part.shape.inertia(part.mass) * rotation.transpose()
+                + (DMat3::IDENTITY * offset.length_squared() - outer_product) * part.mass;
+        }
+
+        // point parts on a line have no inertia around it, torques can not turn that axis
+        let inverse_inertia = Some(inertia.inverse()).filter(DMat3::is_finite).unwrap_or(DMat3::ZERO);
+        MassProperties { mass, center_of_mass, inertia, inverse_inertia }
+    }
+
+    // Euler's rotation equation in the axes of the object: I dω/dt = τ - ω × (I ω).
+    // The second term couples the axes of a spinning object even without any torque.
+    pub fn angular_acceleration(&self, torque: DVec3, angular_velocity: DVec3) -> DVec3 {
+        self.inverse_inertia * (torque - angular_velocity.cross(self.inertia * angular_velocity))
+    }
+}
+
+pub(super) fn update_mass_properties(
+    mut object_query: Query<(&mut MassProperties, &mut SpaceObject, Option<&MassPart>, Option<&Children>)>,
+    part_query: Query<(&MassPart, &Transform)>,
+) {
+    for (mut mass_properties, mut object, own_part, children) in object_query.iter_mut() {
+        let child_parts = children
+            .into_iter()
+            .flat_map(|children| part_query.iter_many(children))
+            .map(|(part, transform)| (*part, *transform));
+        let parts = own_part.map(|part| (*part, Transform::IDENTITY)).into_iter().chain(child_parts);
+        *mass_properties = MassProperties::from_parts(parts);
+        if mass_properties.mass > 0.0 {
+            object.mass = mass_properties.mass;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagonal(matrix: DMat3) -> DVec3 {
+        DVec3::new(matrix.x_axis.x, matrix.y_axis.y, matrix.z_axis.z)
+    }
+
+    #[test]
+    fn single_cuboid_matches_its_formula() {
+        let part = MassPart::new(1000.0, PartShape::Cuboid { size: DVec3::new(1.0, 1.0, 2.5) });
+        let mass_properties = MassProperties::from_parts([(part, Transform::from_xyz(0.0, 0.0, 1.0))]);
+        assert_eq!(mass_properties.center_of_mass, DVec3::new(0.0, 0.0, 1.0));
+        let expected = DVec3::new(1.0 + 6.25, 1.0 + 6.25, 2.0) * 1000.0 / 12.0;
+        assert!((diagonal(mass_properties.inertia) - expected).length() < 1e-9);
+    }
+
+    #[test]
+    fn offset_parts_use_parallel_axis_theorem() {
+        let part = MassPart::new(1.0, PartShape::Point);
+        let mass_properties = MassProperties::from_parts([
+            (part, Transform::from_xyz(1.0, 0.0, 0.0)),
+            (part, Transform::from_xyz(-1.0, 0.0, 0.0)),
+            (part, Transform::from_xyz(0.0, 0.0, 3.0)),
+            (part, Transform::from_xyz(0.0, 0.0, -1.0)),
+        ]);
+        assert_eq!(mass_properties.center_of_mass, DVec3::new(0.0, 0.0, 0.5));
+        assert!((diagonal(mass_properties.inertia) - DVec3::new(9.0, 11.0, 2.0)).length() < 1e-12);
+    }
+
+    #[test]
+    fn torque_free_spin_around_principal_axis_is_steady() {
+        let part = MassPart::new(10.0, PartShape::Cuboid { size: DVec3::new(1.0, 2.0, 3.0) });
+        let mass_properties = MassProperties::from_parts([(part, Transform::IDENTITY)]);
+        assert_eq!(mass_properties.angular_acceleration(DVec3::ZERO, DVec3::new(0.0, 0.0, 5.0)), DVec3::ZERO);
+        let coupled = mass_properties.angular_acceleration(DVec3::ZERO, DVec3::new(1.0, 0.0, 5.0));
+        assert!(coupled.y.abs() > 0.0);
+    }
+}
@@ -1,3 +1,19 @@
+pub mod allocation;
+pub mod atmosphere;
+pub mod barnes_hut;
+pub mod blueprint;
+pub mod body_frame;
+pub mod collision;
+pub mod diagnostics;
+pub mod forces;
+pub mod integrator;
+pub mod landing;
+pub mod mass;
+pub mod orbit;
 pub mod player;
 pub mod physics;
+pub mod prediction;
+pub mod propellant;
+pub mod radiation;
 pub mod text;
+pub mod time_warp;
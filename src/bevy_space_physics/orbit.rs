@@ -0,0 +1,373 @@
+use bevy::{
+    math::{DQuat, DVec3},
+    prelude::*,
+};
+
+// Orbital elements are given in the usual astronomical frame where the reference plane is XY
+// and Z points to the north. In the world the reference plane is XZ and +Y is the north.
+fn to_world(v: DVec3) -> DVec3 {
+    DVec3::new(v.x, v.z, -v.y)
+}
+
+fn from_world(v: DVec3) -> DVec3 {
+    DVec3::new(v.x, -v.z, v.y)
+}
+
+// Moves a body analytically along a Keplerian ellipse around `parent` instead of integrating it.
+// Angles are in radians, `epoch` is the physics time at which the body is at `mean_anomaly_at_epoch`.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct KeplerOrbit {
+    pub parent: Entity,
+    pub semi_major_axis: f64,
+    pub eccentricity: f64,
+    pub inclination: f64,
+    pub longitude_of_ascending_node: f64,
+    pub argument_of_periapsis: f64,
+    pub mean_anomaly_at_epoch: f64,
+    pub epoch: f64,
+}
+
+impl KeplerOrbit {
+    pub fn circular(parent: Entity, radius: f64) -> Self {
+        KeplerOrbit {
+            parent,
+            semi_major_axis: radius,
+            eccentricity: 0.0,
+            inclination: 0.0,
+            longitude_of_ascending_node: 0.0,
+            argument_of_periapsis: 0.0,
+            mean_anomaly_at_epoch: 0.0,
+            epoch: 0.0,
+        }
+    }
+
+    pub fn with_mean_anomaly_at_epoch(mut self, mean_anomaly: f64) -> Self {
+        self.mean_anomaly_at_epoch = mean_anomaly;
+        self
+    }
+
+    // `mu` is the standard gravitational parameter G * (M + m)
+    pub fn mean_motion(&self, mu: f64) -> f64 {
+        (mu / self.semi_major_axis.powi(3)).sqrt()
+    }
+
+    pub fn period(&self, mu: f64) -> f64 {
+        std::f64::consts::TAU / self.mean_motion(mu)
+    }
+
+    // Elliptic orbit through the given state relative to `parent` at physics time `time`,
+    // `None` on escape trajectories.
+    pub fn from_state(parent: Entity, relative_position: DVec3, relative_velocity: DVec3, mu: f64, time: f64) -> Option<Self> {
+        let elements = OrbitalElements::from_state(relative_position, relative_velocity, mu);
+        let e = elements.eccentricity;
+        if e >= 1.0 || !elements.semi_major_axis.is_finite() {
+            return None;
+        }
+        let eccentric_anomaly = 2.0 * (((1.0 - e) / (1.0 + e)).sqrt() * (elements.true_anomaly / 2.0).tan()).atan();
+        Some(KeplerOrbit {
+            parent,
+            semi_major_axis: elements.semi_major_axis,
+            eccentricity: e,
+            inclination: elements.inclination,
+            longitude_of_ascending_node: elements.longitude_of_ascending_node,
+            argument_of_periapsis: elements.argument_of_periapsis,
+            mean_anomaly_at_epoch: eccentric_anomaly - e * eccentric_anomaly.sin(),
+            epoch: time,
+        })
+    }
+
+    fn orientation(&self) -> DQuat {
+        DQuat::from_rotation_z(self.longitude_of_ascending_node)
+            * DQuat::from_rotation_x(self.inclination)
+            * DQuat::from_rotation_z(self.argument_of_periapsis)
+    }
+
+    // Position and velocity relative to the parent at the given physics time.
+    pub fn state_at(&self, time: f64, mu: f64) -> (DVec3, DVec3) {
+        let a = self.semi_major_axis;
+        let e = self.eccentricity;
+        let mean_anomaly = self.mean_anomaly_at_epoch + self.mean_motion(mu) * (time - self.epoch);
+        let eccentric_anomaly = solve_kepler_equation(mean_anomaly, e);
+        let (sin_e, cos_e) = eccentric_anomaly.sin_cos();
+        let semi_minor_ratio = (1.0 - e * e).sqrt();
+
+        let radius = a * (1.0 - e * cos_e);
+        let position = DVec3::new(a * (cos_e - e), a * semi_minor_ratio * sin_e, 0.0);
+        let velocity = (mu * a).sqrt() / radius * DVec3::new(-sin_e, semi_minor_ratio * cos_e, 0.0);
+
+        let orientation = self.orientation();
+        (to_world(orientation * position), to_world(orientation * velocity))
+    }
+}
+
+// Eccentric anomaly E from mean anomaly M for an ellipse, M = E - e * sin(E)
+pub fn solve_kepler_equation(mean_anomaly: f64, eccentricity: f64) -> f64 {
+    let mean_anomaly = mean_anomaly.rem_euclid(std::f64::consts::TAU);
+    let mut eccentric_anomaly = if eccentricity < 0.8 { mean_anomaly } else { std::f64::consts::PI };
+    for _ in 0..50 {
+        let delta = (eccentric_anomaly - eccentricity * eccentric_anomaly.sin() - mean_anomaly)
+            / (1.0 - eccentricity * eccentric_anomaly.cos());
+        eccentric_anomaly -= delta;
+        if delta.abs() < 1e-14 {
+            break;
+        }
+    }
+    eccentric_anomaly
+}
+
+// Osculating elements of the two-body orbit matching the current state. Distances are measured
+// from the centre of the body, apoapsis and period are infinite on escape trajectories.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OrbitalElements {
+    pub semi_major_axis: f64,
+    pub eccentricity: f64,
+    pub inclination: f64,
+    pub longitude_of_ascending_node: f64,
+    pub argument_of_periapsis: f64,
+    pub true_anomaly: f64,
+    pub periapsis: f64,
+    pub apoapsis: f64,
+    pub period: f64,
+    pub time_to_periapsis: Option<f64>,
+    pub time_to_apoapsis: Option<f64>,
+}
+
+impl OrbitalElements {
+    pub fn from_state(relative_position: DVec3, relative_velocity: DVec3, mu: f64) -> Self {
+        use std::f64::consts::{PI, TAU};
+        const EPSILON: f64 = 1e-9;
+
+        let r_vec = from_world(relative_position);
+        let v_vec = from_world(relative_velocity);
+        let r = r_vec.length();
+        let v = v_vec.length();
+
+        let h_vec = r_vec.cross(v_vec);
+        let h = h_vec.length();
+        let n_vec = DVec3::Z.cross(h_vec);
+        let n = n_vec.length();
+        let e_vec = ((v * v - mu / r) * r_vec - r_vec.dot(v_vec) * v_vec) / mu;
+        let e = e_vec.length();
+        let energy = v * v / 2.0 - mu / r;
+
+        let angle = |a: DVec3, b: DVec3| (a.dot(b) / (a.length() * b.length())).clamp(-1.0, 1.0).acos();
+
+        let inclination = if h > 0.0 { (h_vec.z / h).clamp(-1.0, 1.0).acos() } else { 0.0 };
+        let longitude_of_ascending_node = if n > EPSILON * h {
+            n_vec.y.atan2(n_vec.x).rem_euclid(TAU)
+        } else {
+            0.0
+        };
+        let argument_of_periapsis = if e < EPSILON {
+            0.0
+        } else if n > EPSILON * h {
+            let omega = angle(n_vec, e_vec);
+            if e_vec.z < 0.0 { TAU - omega } else { omega }
+        } else {
+            // equatorial orbit, measured from the reference direction
+            let omega = e_vec.y.atan2(e_vec.x).rem_euclid(TAU);
+            if h_vec.z < 0.0 { TAU - omega } else { omega }
+        };
+        let true_anomaly = if e >= EPSILON {
+            let nu = angle(e_vec, r_vec);
+            if r_vec.dot(v_vec) < 0.0 { TAU - nu } else { nu }
+        } else if n > EPSILON * h {
+            let u = angle(n_vec, r_vec);
+            if r_vec.z < 0.0 { TAU - u } else { u }
+        } else {
+            r_vec.y.atan2(r_vec.x).rem_euclid(TAU)
+        };
+
+        let semi_major_axis = if energy.abs() > 0.0 { -mu / (2.0 * energy) } else { f64::INFINITY };
+        let periapsis = h * h / (mu * (1.0 + e));
+
+        let (apoapsis, period, time_to_periapsis, time_to_apoapsis) = if e < 1.0 {
+            let mean_motion = (mu / semi_major_axis.powi(3)).sqrt();
+            let eccentric_anomaly = 2.0 * (((1.0 - e) / (1.0 + e)).sqrt() * (true_anomaly / 2.0).tan()).atan();
+            let mean_anomaly = (eccentric_anomaly - e * eccentric_anomaly.sin()).rem_euclid(TAU);
+            (
+                semi_major_axis * (1.0 + e),
+                TAU / mean_motion,
+                Some((TAU - mean_anomaly) / mean_motion),
+                Some((PI - mean_anomaly).rem_euclid(TAU) / mean_motion),
+            )
+        } else if e > 1.0 {
+            let mean_motion = (mu / (-semi_major_axis).powi(3)).sqrt();
+            let nu = if true_anomaly > PI { true_anomaly - TAU } else { true_anomaly };
+            let hyperbolic_anomaly = 2.0 * (((e - 1.0) / (e + 1.0)).sqrt() * (nu / 2.0).tan()).atanh();
+            let mean_anomaly = e * hyperbolic_anomaly.sinh() - hyperbolic_anomaly;
+            let time_to_periapsis = (mean_anomaly < 0.0).then(|| -mean_anomaly / mean_motion);
+            (f64::INFINITY, f64::INFINITY, time_to_periapsis, None)
+        } else {
+            (f64::INFINITY, f64::INFINITY, None, None)
+        };
+
+        OrbitalElements {
+            semi_major_axis,
+            eccentricity: e,
+            inclination,
+            longitude_of_ascending_node,
+            argument_of_periapsis,
+            true_anomaly,
+            periapsis,
+            apoapsis,
+            period,
+            time_to_periapsis,
+            time_to_apoapsis,
+        }
+    }
+}
+
+// Insert on an object to have its orbit around the body of its sphere of influence computed every step.
+#[derive(Component, Default, Debug)]
+pub struct OsculatingOrbit {
+    pub body: Option<Entity>,
+    pub elements: OrbitalElements,
+}
+
+// Radius of the Laplace sphere of influence of a body of `mass` orbiting `parent_mass` at `distance`.
+pub fn sphere_of_influence_radius(mass: f64, parent_mass: f64, distance: f64) -> f64 {
+    distance * (mass / parent_mass).powf(0.4)
+}
+
+// Insert on an object to track the gravity point whose sphere of influence it is in.
+#[derive(Component, Default, Debug)]
+pub struct CurrentSoi {
+    pub body: Option<Entity>,
+}
+
+#[derive(Event, Clone, Copy, Debug)]
+pub struct SoiChanged {
+    pub entity: Entity,
+    pub previous: Option<Entity>,
+    pub current: Option<Entity>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kepler_orbit_from_state_passes_through_it() {
+        let mu = 3.986e14;
+        let position = DVec3::new(7_000_000.0, 1_000_000.0, -2_000_000.0);
+        let velocity = DVec3::new(1_000.0, 3_000.0, 6_500.0);
+        let orbit = KeplerOrbit::from_state(Entity::PLACEHOLDER, position, velocity, mu, 100.0).unwrap();
+        let (orbit_position, orbit_velocity) = orbit.state_at(100.0, mu);
+        assert!((orbit_position - position).length() < 1e-3);
+        assert!((orbit_velocity - velocity).length() < 1e-6);
+
+        let escape = KeplerOrbit::from_state(Entity::PLACEHOLDER, position, velocity * 2.0, mu, 100.0);
+        assert!(escape.is_none());
+    }
+
+    #[test]
+    fn kepler_equation_is_solved_up_to_nearly_parabolic_orbits() {
+        for eccentricity in [0.0, 0.1, 0.5, 0.9, 0.99, 0.999] {
+            for mean_anomaly in [0.0, 1e-3, 0.5, 2.0, std::f64::consts::PI, 4.0, 6.2, 20.0] {
+                let eccentric_anomaly = solve_kepler_equation(mean_anomaly, eccentricity);
+                let residual = eccentric_anomaly - eccentricity * eccentric_anomaly.sin() - mean_anomaly.rem_euclid(std::f64::consts::TAU);
+                assert!(residual.abs() < 1e-12, "e = {eccentricity}, M = {mean_anomaly}: off by {residual:e}");
+            }
+        }
+    }
+
+    fn orbit(eccentricity: f64) -> KeplerOrbit {
+        KeplerOrbit {
+            parent: Entity::PLACEHOLDER,
+            semi_major_axis: 20_000_000.0,
+            eccentricity,
+            inclination: 0.4,
+            longitude_of_ascending_node: 1.0,
+            argument_of_periapsis: 2.0,
+            mean_anomaly_at_epoch: 1.3,
+            epoch: 0.0,
+        }
+    }
+
+    #[test]
+    fn state_goes_through_the_orbit_and_back() {
+        let mu = 3.986e14;
+        for eccentricity in [0.0, 0.3, 0.7, 0.95, 0.999] {
+            let orbit = orbit(eccentricity);
+            let (position, velocity) = orbit.state_at(500.0, mu);
+            let round_trip = KeplerOrbit::from_state(Entity::PLACEHOLDER, position, velocity, mu, 500.0).unwrap();
+            assert!((round_trip.eccentricity - eccentricity).abs() < 1e-9);
+            assert!((round_trip.semi_major_axis / orbit.semi_major_axis - 1.0).abs() < 1e-9);
+            // the same body later on
+            for time in [500.0, 3_000.0, 20_000.0] {
+                let (expected_position, expected_velocity) = orbit.state_at(time, mu);
+                let (actual_position, actual_velocity) = round_trip.state_at(time, mu);
+                assert!((actual_position - expected_position).length() < 1e-6 * orbit.semi_major_axis, "e = {eccentricity}, t = {time}");
+                assert!((actual_velocity - expected_velocity).length() < 1e-6 * expected_velocity.length(), "e = {eccentricity}, t = {time}");
+            }
+        }
+    }
+
+    #[test]
+    fn orbit_closes_after_one_period() {
+        let mu = 3.986e14;
+        for eccentricity in [0.0, 0.5, 0.99] {
+            let orbit = orbit(eccentricity);
+            let period = orbit.period(mu);
+            let (start_position, start_velocity) = orbit.state_at(100.0, mu);
+            let (end_position, end_velocity) = orbit.state_at(100.0 + period, mu);
+            assert!((end_position - start_position).length() < 1e-6 * orbit.semi_major_axis);
+            assert!((end_velocity - start_velocity).length() < 1e-6 * start_velocity.length());
+            // and not before
+            let (half_way, _) = orbit.state_at(100.0 + period / 2.0, mu);
+            assert!((half_way - start_position).length() > 1e-3 * orbit.semi_major_axis);
+        }
+    }
+
+    fn assert_angle(actual: f64, expected_degrees: f64) {
+        let difference = (actual - expected_degrees.to_radians() + std::f64::consts::PI).rem_euclid(std::f64::consts::TAU) - std::f64::consts::PI;
+        assert!(difference.abs() < 1e-9, "{} degrees instead of {expected_degrees}", actual.to_degrees());
+    }
+
+    #[test]
+    fn elements_of_a_circular_equatorial_orbit() {
+        let mu: f64 = 3.986e14;
+        let radius = 7_000_000.0;
+        let speed = (mu / radius).sqrt();
+        // 30 degrees past +X, turning counterclockwise seen from +Y like the planets
+        let (sin, cos) = 30f64.to_radians().sin_cos();
+        let position = DVec3::new(cos, 0.0, -sin) * radius;
+        let velocity = DVec3::new(-sin, 0.0, -cos) * speed;
+        let elements = OrbitalElements::from_state(position, velocity, mu);
+        assert!((elements.semi_major_axis / radius - 1.0).abs() < 1e-12);
+        assert!(elements.eccentricity < 1e-12);
+        assert_angle(elements.inclination, 0.0);
+        assert_angle(elements.longitude_of_ascending_node, 0.0);
+        assert_angle(elements.argument_of_periapsis, 0.0);
+        // measured from the reference direction, as there is neither node nor periapsis
+        assert_angle(elements.true_anomaly, 30.0);
+        assert!((elements.periapsis / radius - 1.0).abs() < 1e-12);
+        assert!((elements.apoapsis / radius - 1.0).abs() < 1e-12);
+        assert!((elements.period / (std::f64::consts::TAU * (radius.powi(3) / mu).sqrt()) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn elements_of_an_inclined_eccentric_orbit() {
+        // a = 8750 km, e = 0.2, i = 45°, Ω = 90°, ω = 90°, ν = 90°. The node line is astronomical
+        // +Y, the periapsis is towards P = (-1, 0, 1) / √2 and the body is at Q = (0, -1, 0),
+        // 90° further along, where r = p = a (1 - e²) and v = √(μ / p) (e Q - P).
+        let mu: f64 = 3.986e14;
+        let (a, e): (f64, f64) = (8_750_000.0, 0.2);
+        let p = a * (1.0 - e * e);
+        let k = (mu / p).sqrt();
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        // astronomical (x, y, z) is world (x, z, -y)
+        let position = DVec3::new(0.0, 0.0, p);
+        let velocity = DVec3::new(s, -s, e) * k;
+        let elements = OrbitalElements::from_state(position, velocity, mu);
+        assert!((elements.semi_major_axis / a - 1.0).abs() < 1e-12);
+        assert!((elements.eccentricity - e).abs() < 1e-12);
+        assert_angle(elements.inclination, 45.0);
+        assert_angle(elements.longitude_of_ascending_node, 90.0);
+        assert_angle(elements.argument_of_periapsis, 90.0);
+        assert_angle(elements.true_anomaly, 90.0);
+        assert!((elements.periapsis / (a * (1.0 - e)) - 1.0).abs() < 1e-12);
+        assert!((elements.apoapsis / (a * (1.0 + e)) - 1.0).abs() < 1e-12);
+    }
+}
@@ -1,158 +1,1323 @@
 use std::marker::PhantomData;
 
-use bevy::prelude::*;
+use bevy::{
+    ecs::schedule::ScheduleLabel,
+    math::DVec3,
+    prelude::*,
+    utils::HashMap,
+};
 
 use big_space::{
     precision::GridPrecision,
     reference_frame::local_origin::ReferenceFrames,
-    world_query::GridTransform,
+    world_query::{GridTransform, GridTransformOwned, GridTransformReadOnly},
 };
 
+use super::atmosphere::{atmospheric_drag, atmospheric_drag_big_space};
+use super::barnes_hut::Octree;
+use super::body_frame::{update_body_rotations, update_surface_relative_states, update_surface_relative_states_big_space, BodyRotation, SurfaceRelativeState};
+use super::collision::{collisions, collisions_big_space, CollisionEvent};
+use super::forces::{apply_external_forces, clear_external_forces};
+use super::integrator::Integrator;
+use super::landing::{surface_contacts, surface_contacts_big_space, ImpactEvent, Landed, LandingEvent};
+use super::mass::{update_mass_properties, MassProperties};
+use super::orbit::{sphere_of_influence_radius, CurrentSoi, KeplerOrbit, OrbitalElements, OsculatingOrbit, SoiChanged};
+use super::propellant::update_propellant_masses;
+use super::radiation::{radiation_pressure, radiation_pressure_big_space};
+use super::time_warp::{update_time_warp, update_warp_rails, update_warp_rails_big_space, TimeWarp};
+
 #[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PhysicsSet;
 
-pub struct SpacePhysicsPlugin;
+// Runs `substeps` times per fixed step, gravity and integration live here.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PhysicsSubstep;
+
+#[derive(Resource, Clone, Debug)]
+pub struct PhysicsSettings {
+    pub timestep: f64,  // seconds of simulated time per FixedUpdate
+    pub substeps: u32,
+    pub interpolation: bool,
+    pub integrator: Integrator,
+    pub n_body: bool,  // gravity points attract each other as well
+    pub gravity_solver: GravitySolver,
+    pub patched_conics: bool,  // objects are pulled only by the body whose sphere of influence they are in
+}
+
+impl Default for PhysicsSettings {
+    fn default() -> Self {
+        PhysicsSettings {
+            timestep: 1.0 / 64.0,
+            substeps: 4,
+            interpolation: true,
+            integrator: Integrator::default(),
+            n_body: false,
+            gravity_solver: GravitySolver::default(),
+            patched_conics: false,
+        }
+    }
+}
+
+// Clock of the physics simulation, `delta` is the length of the current substep.
+#[derive(Resource, Default, Debug)]
+pub struct PhysicsTime {
+    delta: f64,
+    elapsed: f64,
+}
+
+impl PhysicsTime {
+    pub fn delta_seconds(&self) -> f32 {
+        self.delta as f32
+    }
+
+    pub fn delta_seconds_f64(&self) -> f64 {
+        self.delta
+    }
+
+    pub fn elapsed_seconds_f64(&self) -> f64 {
+        self.elapsed
+    }
+
+    fn advance(&mut self, delta: f64) {
+        self.delta = delta;
+        self.elapsed += delta;
+    }
+}
 
 #[derive(Default)]
-pub struct SpacePhysicsPluginBigSpace<P: GridPrecision>(PhantomData<P>);
+pub struct SpacePhysicsPlugin {
+    pub settings: PhysicsSettings,
+}
+
+impl SpacePhysicsPlugin {
+    pub fn new(settings: PhysicsSettings) -> Self {
+        SpacePhysicsPlugin { settings }
+    }
+}
+
+pub struct SpacePhysicsPluginBigSpace<P: GridPrecision> {
+    pub settings: PhysicsSettings,
+    _precision: PhantomData<P>,
+}
+
+impl<P: GridPrecision> SpacePhysicsPluginBigSpace<P> {
+    pub fn new(settings: PhysicsSettings) -> Self {
+        SpacePhysicsPluginBigSpace { settings, _precision: PhantomData }
+    }
+}
+
+impl<P: GridPrecision> Default for SpacePhysicsPluginBigSpace<P> {
+    fn default() -> Self {
+        SpacePhysicsPluginBigSpace::new(PhysicsSettings::default())
+    }
+}
+
+fn build_physics_clock(app: &mut App, settings: &PhysicsSettings) {
+    app
+        .insert_resource(settings.clone())
+        .insert_resource(Time::<Fixed>::from_seconds(settings.timestep))
+        .init_resource::<PhysicsTime>()
+        .init_resource::<TimeWarp>()
+        .init_resource::<GravitySources>()
+        .add_event::<SoiChanged>()
+        .add_event::<CollisionEvent>()
+        .add_event::<LandingEvent>()
+        .add_event::<ImpactEvent>()
+        .init_schedule(PhysicsSubstep);
+}
 
 impl Plugin for SpacePhysicsPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(
-            Update,
-            (
-                law_of_conservation_of_self_momentum,
-                gravitational_force,
-            ).in_set(PhysicsSet),
-        );
+        build_physics_clock(app, &self.settings);
+        app
+            .add_systems(
+                FixedUpdate,
+                (
+                    update_time_warp,
+                    update_warp_rails,
+                    insert_physics_interpolation.run_if(interpolation_enabled),
+                    update_propellant_masses,
+                    update_mass_properties,
+                    atmospheric_drag,
+                    radiation_pressure,
+                    prepare_physics_interpolation,
+                    run_physics_substeps,
+                    clear_external_forces,
+                    update_osculating_orbits,
+                    update_tidal_accelerations,
+                    update_current_soi,
+                    update_surface_relative_states,
+                    finish_physics_interpolation,
+                ).chain().in_set(PhysicsSet),
+            )
+            .add_systems(
+                PhysicsSubstep,
+                (
+                    apply_external_forces,
+                    update_kepler_orbits,
+                    update_body_rotations,
+                    collect_gravity_sources,
+                    update_spheres_of_influence.run_if(spheres_of_influence_needed),
+                    mutual_gravity.run_if(n_body_enabled),
+                    gravitational_force,
+                    law_of_conservation_of_self_momentum,
+                    surface_contacts,
+                    collisions,
+                ).chain(),
+            )
+            .add_systems(Update, interpolate_transforms.in_set(PhysicsSet));
     }
 }
 
 impl<P: GridPrecision> Plugin for SpacePhysicsPluginBigSpace<P> {
     fn build(&self, app: &mut App) {
-        app.add_systems(
-            PostUpdate,
-            (
-                law_of_conservation_of_self_momentum_big_space::<P>,
-                gravitational_force_big_space::<P>,
-            ).in_set(PhysicsSet),
-        );
+        build_physics_clock(app, &self.settings);
+        app
+            .add_systems(
+                FixedUpdate,
+                (
+                    update_time_warp,
+                    update_warp_rails_big_space::<P>,
+                    insert_physics_interpolation_big_space::<P>.run_if(interpolation_enabled),
+                    update_propellant_masses,
+                    update_mass_properties,
+                    atmospheric_drag_big_space::<P>,
+                    radiation_pressure_big_space::<P>,
+                    prepare_physics_interpolation_big_space::<P>,
+                    run_physics_substeps,
+                    clear_external_forces,
+                    update_osculating_orbits_big_space::<P>,
+                    update_tidal_accelerations_big_space::<P>,
+                    update_current_soi_big_space::<P>,
+                    update_surface_relative_states_big_space::<P>,
+                    finish_physics_interpolation_big_space::<P>,
+                ).chain().in_set(PhysicsSet),
+            )
+            .add_systems(
+                PhysicsSubstep,
+                (
+                    apply_external_forces,
+                    update_kepler_orbits_big_space::<P>,
+                    update_body_rotations,
+                    collect_gravity_sources_big_space::<P>,
+                    update_spheres_of_influence.run_if(spheres_of_influence_needed),
+                    mutual_gravity_big_space::<P>.run_if(n_body_enabled),
+                    gravitational_force_big_space::<P>,
+                    law_of_conservation_of_self_momentum_big_space::<P>,
+                    surface_contacts_big_space::<P>,
+                    collisions_big_space::<P>,
+                ).chain(),
+            )
+            .add_systems(Update, interpolate_transforms_big_space::<P>.in_set(PhysicsSet));
     }
 }
 
 #[derive(Component)]
+// Translational state is kept in f64: planetary masses overflow f32 products and orbital
+// speeds leave f32 velocities with millimetre per second resolution.
 pub struct SpaceObject {
-    pub mass: f32,
-    pub velocity: Vec3,
-    pub acceleration: Vec3,
+    pub mass: f64,
+    pub velocity: DVec3,
+    pub acceleration: DVec3,
+    // In world axes, radians per second along the spin axis. `local_angular_velocity` gives it
+    // in the axes of the object, which `MassProperties` are in.
     pub angular_velocity: Vec3,
-    pub angular_acceleration: Vec3,
-    pub gravitational_force: Vec3,
+    pub angular_acceleration: Vec3,  // world axes as well
+    pub gravitational_force: DVec3,
 }
 
 impl SpaceObject {
-    pub fn new(mass: f32) -> Self {
+    pub fn new(mass: f64) -> Self {
         SpaceObject {
             mass,
-            velocity: Vec3::ZERO,
-            acceleration: Vec3::ZERO,
+            velocity: DVec3::ZERO,
+            acceleration: DVec3::ZERO,
             angular_velocity: Vec3::ZERO,
             angular_acceleration: Vec3::ZERO,
-            gravitational_force: Vec3::ZERO,
+            gravitational_force: DVec3::ZERO,
         }
     }
+
+    pub fn local_angular_velocity(&self, rotation: Quat) -> Vec3 {
+        rotation.inverse() * self.angular_velocity
+    }
+
+    // Spin angular momentum about the centre of mass in world axes. Without torque it stays
+    // constant while the angular velocity of an unevenly shaped object wobbles around it.
+    pub fn angular_momentum(&self, mass_properties: &MassProperties, rotation: Quat) -> DVec3 {
+        let local_angular_velocity = self.local_angular_velocity(rotation).as_dvec3();
+        rotation.as_dquat() * (mass_properties.inertia * local_angular_velocity)
+    }
+}
+
+// Turns `rotation` by the world angular velocity over `delta_seconds` through the exponential
+// map, which is exact for a steady spin around any axis.
+pub fn integrate_rotation(rotation: Quat, angular_velocity: Vec3, delta_seconds: f32) -> Quat {
+    (Quat::from_scaled_axis(angular_velocity * delta_seconds) * rotation).normalize()
 }
 
 #[derive(Component)]
 pub struct GravityPoint;
 
+// Difference of gravity between the ends of an object. `span` goes from one end to the other in
+// the axes of the object, `acceleration` is how much harder the far end is pulled than the near
+// one and `stretch` is its part along the span, positive when the object is pulled apart.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct TidalAcceleration {
+    pub span: DVec3,
+    pub acceleration: DVec3,
+    pub stretch: f64,
+}
+
+impl TidalAcceleration {
+    pub fn new(span: DVec3) -> Self {
+        TidalAcceleration { span, acceleration: DVec3::ZERO, stretch: 0.0 }
+    }
+}
+
+// Surface radius of a gravity point. Outside of it gravity is computed as for a point mass,
+// ships touching it land or crash.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct BodyRadius(pub f64);
+
+// Second zonal harmonic of a gravity point flattened at the poles, the axis of the body is its
+// local +Y. Earth has a `j2` of 1.08263e-3 for a `reference_radius` of 6378137 m.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Oblateness {
+    pub j2: f64,
+    pub reference_radius: f64,
+}
+
+pub const G: f64 = 6.67430e-11;  // 6.67430×10^−11 N⋅m2⋅kg−2
+
+pub fn circular_orbit_speed(central_mass: f64, radius: f64) -> f64 {
+    (G * central_mass / radius).sqrt()
+}
+
+// `parent` and `soi_radius` are only known when spheres of influence are tracked, otherwise every
+// source is a root with an infinite sphere of influence. `frame_acceleration` is the acceleration
+// of the source itself under patched conics, objects inside its sphere of influence share it.
+// `radius` is zero for sources without a `BodyRadius`, `axis` is the unit spin axis of the body.
+#[derive(Clone, Copy, Debug)]
+pub struct GravitySource {
+    pub entity: Entity,
+    pub position: DVec3,
+    pub velocity: DVec3,
+    pub mass: f64,
+    pub radius: f64,
+    pub axis: DVec3,
+    pub oblateness: Option<Oblateness>,
+    pub parent: Option<Entity>,
+    pub soi_radius: f64,
+    pub frame_acceleration: DVec3,
+}
+
+impl GravitySource {
+    pub fn new(entity: Entity, position: DVec3, velocity: DVec3, mass: f64) -> Self {
+        GravitySource {
+            entity,
+            position,
+            velocity,
+            mass,
+            radius: 0.0,
+            axis: DVec3::Y,
+            oblateness: None,
+            parent: None,
+            soi_radius: f64::INFINITY,
+            frame_acceleration: DVec3::ZERO,
+        }
+    }
+
+    pub fn with_radius(mut self, radius: f64) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    pub fn with_oblateness(mut self, axis: DVec3, oblateness: Option<Oblateness>) -> Self {
+        self.axis = axis.try_normalize().unwrap_or(DVec3::Y);
+        self.oblateness = oblateness;
+        self
+    }
+
+    // Below the surface the pull falls off linearly like inside a uniform sphere, instead of
+    // growing without bound towards the centre.
+    pub fn acceleration_at(&self, position: DVec3) -> DVec3 {
+        let distance_vec = self.position - position;
+        let distance = distance_vec.length().max(self.radius);
+        if distance == 0.0 {
+            return DVec3::ZERO;
+        }
+        G * self.mass / (distance * distance * distance) * distance_vec + self.oblateness_acceleration_at(position)
+    }
+
+    // J2 perturbation on top of the point mass pull, left out below the surface.
+    pub fn oblateness_acceleration_at(&self, position: DVec3) -> DVec3 {
+        let Some(oblateness) = self.oblateness else {
+            return DVec3::ZERO;
+        };
+        let offset = position - self.position;
+        let distance = offset.length();
+        if distance <= self.radius || distance == 0.0 {
+            return DVec3::ZERO;
+        }
+        let z = offset.dot(self.axis);
+        let factor = -1.5 * oblateness.j2 * G * self.mass * oblateness.reference_radius.powi(2) / distance.powi(5);
+        factor * ((1.0 - 5.0 * z * z / (distance * distance)) * offset + 2.0 * z * self.axis)
+    }
+
+    // Difference between the point mass pull at `position + separation` and at `position`,
+    // to first order in `separation`.
+    pub fn tidal_acceleration_at(&self, position: DVec3, separation: DVec3) -> DVec3 {
+        let offset = position - self.position;
+        let distance = offset.length().max(self.radius);
+        if distance == 0.0 {
+            return DVec3::ZERO;
+        }
+        let direction = offset.normalize();
+        G * self.mass / distance.powi(3) * (3.0 * direction * direction.dot(separation) - separation)
+    }
+}
+
+// Brute force sums every gravity point, Barnes-Hut approximates far away groups of them,
+// which pays off with thousands of gravity points. Opening angle around 0.5 is a good start.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum GravitySolver {
+    #[default]
+    BruteForce,
+    BarnesHut { opening_angle: f64 },
+}
+
+// Snapshot of every `GravityPoint` taken at the beginning of a substep, so that integrators
+// can sample the gravity field at intermediate positions.
+#[derive(Resource, Default)]
+pub struct GravitySources {
+    pub sources: Vec<GravitySource>,
+    solver: GravitySolver,
+    patched_conics: bool,
+    octree: Option<Octree>,
+}
+
+impl GravitySources {
+    pub fn rebuild(&mut self, settings: &PhysicsSettings) {
+        self.solver = settings.gravity_solver;
+        self.patched_conics = settings.patched_conics;
+        self.octree = match self.solver {
+            GravitySolver::BruteForce => None,
+            GravitySolver::BarnesHut { .. } => {
+                let bodies: Vec<(DVec3, f64, f64)> = self.sources.iter().map(|source| (source.position, source.mass, source.radius)).collect();
+                Some(Octree::new(&bodies))
+            }
+        };
+    }
+
+    pub fn acceleration_at(&self, position: DVec3) -> DVec3 {
+        if self.patched_conics {
+            let Some(source) = self.soi_source(position) else {
+                return DVec3::ZERO;
+            };
+            return source.frame_acceleration + source.acceleration_at(position);
+        }
+
+        if let (GravitySolver::BarnesHut { opening_angle }, Some(octree)) = (self.solver, &self.octree) {
+            let oblateness: DVec3 = self.sources.iter().map(|source| source.oblateness_acceleration_at(position)).sum();
+            return octree.acceleration_at(position, opening_angle) + oblateness;
+        }
+
+        self.sources.iter().map(|source| source.acceleration_at(position)).sum()
+    }
+
+    // Tidal acceleration between `position` and `position + separation` from every source.
+    pub fn tidal_acceleration_at(&self, position: DVec3, separation: DVec3) -> DVec3 {
+        self.sources.iter().map(|source| source.tidal_acceleration_at(position, separation)).sum()
+    }
+
+    // The innermost sphere of influence containing the given position.
+    pub fn soi_source(&self, position: DVec3) -> Option<&GravitySource> {
+        self.sources
+            .iter()
+            .filter(|source| source.position != position && source.position.distance(position) < source.soi_radius)
+            .min_by(|a, b| {
+                let pull_a = a.mass / a.position.distance_squared(position);
+                let pull_b = b.mass / b.position.distance_squared(position);
+                a.soi_radius.total_cmp(&b.soi_radius).then(pull_b.total_cmp(&pull_a))
+            })
+    }
+
+    // The parent of a source is the heavier source with the innermost sphere of influence around
+    // it, the Moon orbits the Earth although the Sun pulls it harder. Sources are visited from
+    // the heaviest one, so that parents are resolved before their children.
+    pub fn update_spheres_of_influence(&mut self) {
+        let mut order: Vec<usize> = (0..self.sources.len()).collect();
+        order.sort_by(|&a, &b| self.sources[b].mass.total_cmp(&self.sources[a].mass));
+
+        for (k, &i) in order.iter().enumerate() {
+            let source = self.sources[i];
+            let parent = order[..k]
+                .iter()
+                .map(|&j| self.sources[j])
+                .filter(|parent| {
+                    parent.mass > source.mass
+                        && parent.position != source.position
+                        && parent.position.distance(source.position) < parent.soi_radius
+                })
+                .min_by(|a, b| {
+                    let pull_a = a.mass / a.position.distance_squared(source.position);
+                    let pull_b = b.mass / b.position.distance_squared(source.position);
+                    a.soi_radius.total_cmp(&b.soi_radius).then(pull_b.total_cmp(&pull_a))
+                });
+
+            let source = &mut self.sources[i];
+            let Some(parent) = parent else {
+                source.parent = None;
+                source.soi_radius = f64::INFINITY;
+                source.frame_acceleration = DVec3::ZERO;
+                continue;
+            };
+            let distance_vec = parent.position - source.position;
+            let distance = distance_vec.length();
+            source.parent = Some(parent.entity);
+            source.soi_radius = sphere_of_influence_radius(source.mass, parent.mass, distance);
+            // two-body motion around the parent, the same one `KeplerOrbit` follows
+            source.frame_acceleration = parent.frame_acceleration
+                + G * (parent.mass + source.mass) / (distance * distance * distance) * distance_vec;
+        }
+    }
+}
+
+fn update_spheres_of_influence(mut gravity_sources: ResMut<GravitySources>) {
+    gravity_sources.update_spheres_of_influence();
+}
+
+#[allow(clippy::type_complexity)]
+fn spheres_of_influence_needed(
+    settings: Res<PhysicsSettings>,
+    time_warp: Res<TimeWarp>,
+    tracking_query: Query<(), Or<(With<CurrentSoi>, With<OsculatingOrbit>, With<SurfaceRelativeState>)>>,
+) -> bool {
+    settings.patched_conics || time_warp.on_rails() || !tracking_query.is_empty()
+}
+
+fn update_current_soi(
+    gravity_sources: Res<GravitySources>,
+    mut soi_changed: EventWriter<SoiChanged>,
+    mut object_query: Query<(&mut CurrentSoi, Entity, &Transform)>,
+) {
+    for (mut current_soi, entity, transform) in object_query.iter_mut() {
+        let body = gravity_sources.soi_source(transform.translation.as_dvec3()).map(|source| source.entity);
+        if current_soi.body != body {
+            soi_changed.send(SoiChanged { entity, previous: current_soi.body, current: body });
+            current_soi.body = body;
+        }
+    }
+}
+
+fn update_current_soi_big_space<P: GridPrecision>(
+    gravity_sources: Res<GravitySources>,
+    frames: ReferenceFrames<P>,
+    mut soi_changed: EventWriter<SoiChanged>,
+    mut object_query: Query<(&mut CurrentSoi, Entity, GridTransformReadOnly<P>)>,
+) {
+    for (mut current_soi, entity, grid_transform) in object_query.iter_mut() {
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        let position = grid_transform.position_double(reference_frame);
+        let body = gravity_sources.soi_source(position).map(|source| source.entity);
+        if current_soi.body != body {
+            soi_changed.send(SoiChanged { entity, previous: current_soi.body, current: body });
+            current_soi.body = body;
+        }
+    }
+}
+
+fn update_osculating_orbits(
+    gravity_sources: Res<GravitySources>,
+    mut object_query: Query<(&mut OsculatingOrbit, &SpaceObject, &Transform)>,
+) {
+    for (mut orbit, object, transform) in object_query.iter_mut() {
+        let position = transform.translation.as_dvec3();
+        let Some(body) = gravity_sources.soi_source(position) else {
+            continue;
+        };
+        orbit.body = Some(body.entity);
+        orbit.elements = OrbitalElements::from_state(position - body.position, object.velocity - body.velocity, G * (body.mass + object.mass));
+    }
+}
+
+fn update_osculating_orbits_big_space<P: GridPrecision>(
+    gravity_sources: Res<GravitySources>,
+    frames: ReferenceFrames<P>,
+    mut object_query: Query<(&mut OsculatingOrbit, &SpaceObject, Entity, GridTransformReadOnly<P>)>,
+) {
+    for (mut orbit, object, entity, grid_transform) in object_query.iter_mut() {
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        let position = grid_transform.position_double(reference_frame);
+        let Some(body) = gravity_sources.soi_source(position) else {
+            continue;
+        };
+        orbit.body = Some(body.entity);
+        orbit.elements = OrbitalElements::from_state(position - body.position, object.velocity - body.velocity, G * (body.mass + object.mass));
+    }
+}
+
+fn update_tidal_accelerations(
+    gravity_sources: Res<GravitySources>,
+    mut object_query: Query<(&mut TidalAcceleration, &Transform)>,
+) {
+    for (mut tidal, transform) in object_query.iter_mut() {
+        let span = transform.rotation.as_dquat() * tidal.span;
+        tidal.acceleration = gravity_sources.tidal_acceleration_at(transform.translation.as_dvec3(), span);
+        tidal.stretch = tidal.acceleration.dot(span.normalize_or_zero());
+    }
+}
+
+fn update_tidal_accelerations_big_space<P: GridPrecision>(
+    gravity_sources: Res<GravitySources>,
+    frames: ReferenceFrames<P>,
+    mut object_query: Query<(&mut TidalAcceleration, Entity, GridTransformReadOnly<P>)>,
+) {
+    for (mut tidal, entity, grid_transform) in object_query.iter_mut() {
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        let span = grid_transform.transform.rotation.as_dquat() * tidal.span;
+        tidal.acceleration = gravity_sources.tidal_acceleration_at(grid_transform.position_double(reference_frame), span);
+        tidal.stretch = tidal.acceleration.dot(span.normalize_or_zero());
+    }
+}
+
+// Absolute state of an object, on-rails parents are resolved first. `states` holds the
+// position, velocity and mass of every object before the update.
+pub fn kepler_state(
+    entity: Entity,
+    time: f64,
+    orbits: &HashMap<Entity, KeplerOrbit>,
+    states: &HashMap<Entity, (DVec3, DVec3, f64)>,
+    depth: u32,
+) -> Option<(DVec3, DVec3)> {
+    const MAX_DEPTH: u32 = 16;
+    let &(position, velocity, mass) = states.get(&entity)?;
+    let Some(orbit) = orbits.get(&entity) else {
+        return Some((position, velocity));
+    };
+    if depth >= MAX_DEPTH {
+        return None;
+    }
+    let (parent_position, parent_velocity) = kepler_state(orbit.parent, time, orbits, states, depth + 1)?;
+    let parent_mass = states.get(&orbit.parent)?.2;
+    let (relative_position, relative_velocity) = orbit.state_at(time, G * (parent_mass + mass));
+    Some((parent_position + relative_position, parent_velocity + relative_velocity))
+}
+
+fn update_kepler_orbits(
+    time: Res<PhysicsTime>,
+    mut object_query: Query<(&mut SpaceObject, Entity, &mut Transform, Option<&KeplerOrbit>)>,
+) {
+    let mut orbits = HashMap::new();
+    let mut states = HashMap::new();
+    for (object, entity, transform, orbit) in object_query.iter() {
+        states.insert(entity, (transform.translation.as_dvec3(), object.velocity, object.mass));
+        if let Some(orbit) = orbit {
+            orbits.insert(entity, *orbit);
+        }
+    }
+
+    for (&entity, _) in orbits.iter() {
+        let Some((position, velocity)) = kepler_state(entity, time.elapsed_seconds_f64(), &orbits, &states, 0) else {
+            continue;
+        };
+        let Ok((mut object, _, mut transform, _)) = object_query.get_mut(entity) else {
+            continue;
+        };
+        object.velocity = velocity;
+        transform.translation = position.as_vec3();
+    }
+}
+
+fn update_kepler_orbits_big_space<P: GridPrecision>(
+    time: Res<PhysicsTime>,
+    frames: ReferenceFrames<P>,
+    mut object_query: Query<(&mut SpaceObject, Entity, GridTransform<P>, Option<&KeplerOrbit>)>,
+) {
+    let mut orbits = HashMap::new();
+    let mut states = HashMap::new();
+    for (object, entity, grid_transform, orbit) in object_query.iter() {
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        states.insert(entity, (grid_transform.position_double(reference_frame), object.velocity, object.mass));
+        if let Some(orbit) = orbit {
+            orbits.insert(entity, *orbit);
+        }
+    }
+
+    for (&entity, _) in orbits.iter() {
+        let Some((position, velocity)) = kepler_state(entity, time.elapsed_seconds_f64(), &orbits, &states, 0) else {
+            continue;
+        };
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        let Ok((mut object, _, mut grid_transform, _)) = object_query.get_mut(entity) else {
+            continue;
+        };
+        object.velocity = velocity;
+        let (cell, translation) = reference_frame.translation_to_grid(position);
+        *grid_transform.cell = cell;
+        grid_transform.transform.translation = translation;
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn collect_gravity_sources(
+    settings: Res<PhysicsSettings>,
+    mut gravity_sources: ResMut<GravitySources>,
+    gravity_points_query: Query<(&SpaceObject, Entity, &Transform, Option<&BodyRadius>, Option<&Oblateness>), With<GravityPoint>>,
+) {
+    gravity_sources.sources.clear();
+    for (object, entity, transform, radius, oblateness) in gravity_points_query.iter() {
+        let source = GravitySource::new(entity, transform.translation.as_dvec3(), object.velocity, object.mass)
+            .with_radius(radius.map_or(0.0, |radius| radius.0))
+            .with_oblateness((transform.rotation * Vec3::Y).as_dvec3(), oblateness.copied());
+        gravity_sources.sources.push(source);
+    }
+    gravity_sources.rebuild(&settings);
+}
+
+#[allow(clippy::type_complexity)]
+fn collect_gravity_sources_big_space<P: GridPrecision>(
+    settings: Res<PhysicsSettings>,
+    mut gravity_sources: ResMut<GravitySources>,
+    frames: ReferenceFrames<P>,
+    gravity_points_query: Query<(&SpaceObject, Entity, GridTransformReadOnly<P>, Option<&BodyRadius>, Option<&Oblateness>), With<GravityPoint>>,
+) {
+    gravity_sources.sources.clear();
+    for (object, entity, grid_transform, radius, oblateness) in gravity_points_query.iter() {
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        let source = GravitySource::new(entity, grid_transform.position_double(reference_frame), object.velocity, object.mass)
+            .with_radius(radius.map_or(0.0, |radius| radius.0))
+            .with_oblateness((grid_transform.transform.rotation * Vec3::Y).as_dvec3(), oblateness.copied());
+        gravity_sources.sources.push(source);
+    }
+    gravity_sources.rebuild(&settings);
+}
+
 fn gravitational_force(
-    gravity_points_query: Query<(&SpaceObject, &GlobalTransform), With<GravityPoint>>,
-    mut no_gravity_objects_query: Query<(&mut SpaceObject, &GlobalTransform), Without<GravityPoint>>,
+    gravity_sources: Res<GravitySources>,
+    mut no_gravity_objects_query: Query<(&mut SpaceObject, &Transform), Without<GravityPoint>>,
 ) {
-    const G: f32 = 6.67430e-11;  // 6.67430×10^−11 N⋅m2⋅kg−2
     for (mut object, transform) in no_gravity_objects_query.iter_mut() {
-        object.gravitational_force = Vec3::ZERO;
-        for (gravity_point_object, gravity_point_transform) in gravity_points_query.iter() {
-            let distance_vec = gravity_point_transform.translation() - transform.translation();
-            let distance = distance_vec.length();
-            let force_magnitude = G * gravity_point_object.mass * object.mass / (distance * distance);
-            let force_direction = distance_vec.normalize_or_zero();
-            let force = force_magnitude * force_direction;
-            object.gravitational_force += force;
-        }
+        let acceleration = gravity_sources.acceleration_at(transform.translation.as_dvec3());
+        object.gravitational_force = acceleration * object.mass;
     }
 }
 
 fn gravitational_force_big_space<P: GridPrecision>(
+    gravity_sources: Res<GravitySources>,
     frames: ReferenceFrames<P>,
-    gravity_points_query: Query<(&SpaceObject, Entity, GridTransform<P>), With<GravityPoint>>,
-    mut no_gravity_objects_query: Query<(&mut SpaceObject, Entity, GridTransform<P>), Without<GravityPoint>>,
+    mut no_gravity_objects_query: Query<(&mut SpaceObject, Entity, GridTransformReadOnly<P>), Without<GravityPoint>>,
 ) {
-    const G: f64 = 6.67430e-11;  // 6.67430×10^−11 N⋅m2⋅kg−2
     for (mut object, entity, grid_transform) in no_gravity_objects_query.iter_mut() {
-        object.gravitational_force = Vec3::ZERO;
-        for (gravity_point_object, gravity_point_entity, gravity_point_grid_transform) in gravity_points_query.iter() {
-            let Some(gravity_point_reference_frame) = frames.parent_frame(gravity_point_entity) else {
-                continue;
-            };
-            let Some(reference_frame) = frames.parent_frame(entity) else {
-                continue;
-            };
-            let distance_vec = gravity_point_grid_transform.position_double(gravity_point_reference_frame) - grid_transform.position_double(reference_frame);
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        let acceleration = gravity_sources.acceleration_at(grid_transform.position_double(reference_frame));
+        object.gravitational_force = acceleration * object.mass;
+    }
+}
+
+// Without n-body mode gravity points are not pulled by anything, only their own acceleration moves them.
+fn integrate_translation(
+    integrator: Integrator,
+    object: &SpaceObject,
+    position: DVec3,
+    is_gravity_point: bool,
+    gravity_sources: &GravitySources,
+    delta_seconds: f64,
+) -> (DVec3, DVec3) {
+    let acceleration = object.acceleration;
+    integrator.step(DVec3::ZERO, object.velocity, delta_seconds, |offset| {
+        if is_gravity_point {
+            acceleration
+        } else {
+            acceleration + gravity_sources.acceleration_at(position + offset)
+        }
+    })
+}
+
+fn mutual_gravitational_accelerations(solver: GravitySolver, masses: &[f64], positions: &[DVec3], accelerations: &mut [DVec3]) {
+    if let GravitySolver::BarnesHut { opening_angle } = solver {
+        let bodies: Vec<(DVec3, f64, f64)> = positions.iter().zip(masses).map(|(position, mass)| (*position, *mass, 0.0)).collect();
+        let octree = Octree::new(&bodies);
+        for (acceleration, position) in accelerations.iter_mut().zip(positions) {
+            *acceleration = octree.acceleration_at(*position, opening_angle);
+        }
+        return;
+    }
+
+    accelerations.fill(DVec3::ZERO);
+    for i in 0..positions.len() {
+        for j in (i + 1)..positions.len() {
+            let distance_vec = positions[j] - positions[i];
             let distance = distance_vec.length();
-            let force_magnitude = G * (gravity_point_object.mass * object.mass) as f64 / (distance * distance);
-            let force_direction = distance_vec.normalize_or_zero();
-            let force = force_magnitude * force_direction;
-            object.gravitational_force += force.as_vec3();
+            if distance == 0.0 {
+                continue;
+            }
+            // the pull of a kilogram, once per pair: each body feels it from the mass of the other
+            let pull = G / (distance * distance * distance) * distance_vec;
+            accelerations[i] += masses[j] * pull;
+            accelerations[j] -= masses[i] * pull;
+        }
+    }
+}
+
+// Gravity points are integrated together, pairwise forces cancel and total momentum is kept
+// (up to the approximation error with Barnes-Hut). Kinematic bodies (on rails) attract the
+// others but stay where they are. Returns position offsets from `positions` and new velocities.
+#[allow(clippy::too_many_arguments)]
+pub fn step_gravity_points(
+    integrator: Integrator,
+    solver: GravitySolver,
+    masses: &[f64],
+    positions: &[DVec3],
+    velocities: &[DVec3],
+    accelerations: &[DVec3],
+    kinematic: &[bool],
+    delta_seconds: f64,
+) -> (Vec<DVec3>, Vec<DVec3>) {
+    let mut offsets = vec![DVec3::ZERO; positions.len()];
+    let mut velocities: Vec<DVec3> = velocities
+        .iter()
+        .zip(kinematic)
+        .map(|(velocity, &kinematic)| if kinematic { DVec3::ZERO } else { *velocity })
+        .collect();
+    integrator.step_system(&mut offsets, &mut velocities, delta_seconds, |offsets, result| {
+        let positions: Vec<DVec3> = positions.iter().zip(offsets).map(|(position, offset)| *position + *offset).collect();
+        mutual_gravitational_accelerations(solver, masses, &positions, result);
+        for ((result, acceleration), &kinematic) in result.iter_mut().zip(accelerations).zip(kinematic) {
+            *result = if kinematic { DVec3::ZERO } else { *result + *acceleration };
+        }
+    });
+    (offsets, velocities)
+}
+
+fn mutual_gravity(
+    time: Res<PhysicsTime>,
+    settings: Res<PhysicsSettings>,
+    mut gravity_points_query: Query<(&mut SpaceObject, &mut Transform, Has<KeplerOrbit>), With<GravityPoint>>,
+) {
+    let mut bodies: Vec<_> = gravity_points_query.iter_mut().collect();
+    let masses: Vec<f64> = bodies.iter().map(|(object, ..)| object.mass).collect();
+    let positions: Vec<DVec3> = bodies.iter().map(|(_, transform, _)| transform.translation.as_dvec3()).collect();
+    let velocities: Vec<DVec3> = bodies.iter().map(|(object, ..)| object.velocity).collect();
+    let accelerations: Vec<DVec3> = bodies.iter().map(|(object, ..)| object.acceleration).collect();
+    let kinematic: Vec<bool> = bodies.iter().map(|(_, _, on_rails)| *on_rails).collect();
+
+    let mut gravitational_accelerations = vec![DVec3::ZERO; bodies.len()];
+    mutual_gravitational_accelerations(settings.gravity_solver, &masses, &positions, &mut gravitational_accelerations);
+    let (offsets, velocities) = step_gravity_points(settings.integrator, settings.gravity_solver, &masses, &positions, &velocities, &accelerations, &kinematic, time.delta_seconds_f64());
+
+    for (i, (object, transform, on_rails)) in bodies.iter_mut().enumerate() {
+        object.gravitational_force = gravitational_accelerations[i] * masses[i];
+        if *on_rails {
+            continue;
+        }
+        object.velocity = velocities[i];
+        transform.translation += offsets[i].as_vec3();
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn mutual_gravity_big_space<P: GridPrecision>(
+    time: Res<PhysicsTime>,
+    settings: Res<PhysicsSettings>,
+    frames: ReferenceFrames<P>,
+    mut gravity_points_query: Query<(&mut SpaceObject, Entity, GridTransform<P>, Has<KeplerOrbit>), With<GravityPoint>>,
+) {
+    let mut bodies: Vec<_> = gravity_points_query
+        .iter_mut()
+        .filter_map(|(object, entity, grid_transform, on_rails)| {
+            let reference_frame = frames.parent_frame(entity)?;
+            let position = grid_transform.position_double(reference_frame);
+            Some((object, reference_frame, position, grid_transform, on_rails))
+        })
+        .collect();
+    let masses: Vec<f64> = bodies.iter().map(|(object, ..)| object.mass).collect();
+    let positions: Vec<DVec3> = bodies.iter().map(|(_, _, position, ..)| *position).collect();
+    let velocities: Vec<DVec3> = bodies.iter().map(|(object, ..)| object.velocity).collect();
+    let accelerations: Vec<DVec3> = bodies.iter().map(|(object, ..)| object.acceleration).collect();
+    let kinematic: Vec<bool> = bodies.iter().map(|(.., on_rails)| *on_rails).collect();
+
+    let mut gravitational_accelerations = vec![DVec3::ZERO; bodies.len()];
+    mutual_gravitational_accelerations(settings.gravity_solver, &masses, &positions, &mut gravitational_accelerations);
+    let (offsets, velocities) = step_gravity_points(settings.integrator, settings.gravity_solver, &masses, &positions, &velocities, &accelerations, &kinematic, time.delta_seconds_f64());
+
+    for (i, (object, reference_frame, _, grid_transform, on_rails)) in bodies.iter_mut().enumerate() {
+        object.gravitational_force = gravitational_accelerations[i] * masses[i];
+        if *on_rails {
+            continue;
         }
+        object.velocity = velocities[i];
+        let (delta_cell, delta_translation) = reference_frame.translation_to_grid(offsets[i]);
+        *grid_transform.cell += delta_cell;
+        grid_transform.transform.translation += delta_translation;
     }
 }
 
+fn n_body_enabled(settings: Res<PhysicsSettings>) -> bool {
+    settings.n_body
+}
+
+#[allow(clippy::type_complexity)]
 fn law_of_conservation_of_self_momentum(
-    time: Res<Time>,
-    mut object_query: Query<(&mut SpaceObject, &mut Transform)>,
+    time: Res<PhysicsTime>,
+    settings: Res<PhysicsSettings>,
+    gravity_sources: Res<GravitySources>,
+    mut object_query: Query<(&mut SpaceObject, &mut Transform, Option<&Integrator>, Has<GravityPoint>, Has<KeplerOrbit>, Has<Landed>, Has<BodyRotation>)>,
 ) {
-    for (mut object, mut transform) in  &mut object_query {
+    for (mut object, mut transform, integrator, is_gravity_point, on_rails, landed, spinning_on_rails) in  &mut object_query {
 
-        let acceleration = object.acceleration + object.gravitational_force / object.mass;
-        object.velocity += acceleration * time.delta_seconds();
-        transform.translation += object.velocity * time.delta_seconds();
+        // on-rails bodies, n-body gravity points and landed objects are moved by their own systems
+        let moved_elsewhere = on_rails || landed || (is_gravity_point && settings.n_body);
+        if !moved_elsewhere {
+            let integrator = integrator.copied().unwrap_or(settings.integrator);
+            let (delta_translation, velocity) = integrate_translation(
+                integrator,
+                &object,
+                transform.translation.as_dvec3(),
+                is_gravity_point,
+                &gravity_sources,
+                time.delta_seconds_f64(),
+            );
+            object.velocity = velocity;
+            transform.translation += delta_translation.as_vec3();
+        }
 
+        if spinning_on_rails {
+            continue;
+        }
         let angular_acceleration = object.angular_acceleration;
         object.angular_velocity += angular_acceleration * time.delta_seconds();
-
-        let delta_rotation = Quat::from_rotation_x(object.angular_velocity.x * time.delta_seconds())
-            * Quat::from_rotation_y(object.angular_velocity.y * time.delta_seconds())
-            * Quat::from_rotation_z(object.angular_velocity.z * time.delta_seconds());
-
-        transform.rotation = delta_rotation * transform.rotation;
+        transform.rotation = integrate_rotation(transform.rotation, object.angular_velocity, time.delta_seconds());
     }
 }
 
+#[allow(clippy::type_complexity)]
 fn law_of_conservation_of_self_momentum_big_space<P: GridPrecision>(
-    time: Res<Time>,
+    time: Res<PhysicsTime>,
+    settings: Res<PhysicsSettings>,
+    gravity_sources: Res<GravitySources>,
     frames: ReferenceFrames<P>,
-    mut object_query: Query<(&mut SpaceObject, Entity, GridTransform<P>)>,
+    mut object_query: Query<(&mut SpaceObject, Entity, GridTransform<P>, Option<&Integrator>, Has<GravityPoint>, Has<KeplerOrbit>, Has<Landed>, Has<BodyRotation>)>,
 ) {
-    for (mut object, entity, mut grid_transform) in  &mut object_query {
+    for (mut object, entity, mut grid_transform, integrator, is_gravity_point, on_rails, landed, spinning_on_rails) in  &mut object_query {
         let Some(reference_frame) = frames.parent_frame(entity) else {
             continue;
         };
 
-        let acceleration = object.acceleration + object.gravitational_force / object.mass;
-        object.velocity += acceleration * time.delta_seconds();
-        let (delta_cell, delta_translation) = reference_frame.translation_to_grid(object.velocity * time.delta_seconds());
-        *grid_transform.cell += delta_cell;
-        grid_transform.transform.translation += delta_translation;
+        // on-rails bodies, n-body gravity points and landed objects are moved by their own systems
+        let moved_elsewhere = on_rails || landed || (is_gravity_point && settings.n_body);
+        if !moved_elsewhere {
+            let integrator = integrator.copied().unwrap_or(settings.integrator);
+            let (delta_translation, velocity) = integrate_translation(
+                integrator,
+                &object,
+                grid_transform.position_double(reference_frame),
+                is_gravity_point,
+                &gravity_sources,
+                time.delta_seconds_f64(),
+            );
+            object.velocity = velocity;
+            let (delta_cell, delta_translation) = reference_frame.translation_to_grid(delta_translation);
+            *grid_transform.cell += delta_cell;
+            grid_transform.transform.translation += delta_translation;
+        }
 
+        if spinning_on_rails {
+            continue;
+        }
         let angular_acceleration = object.angular_acceleration;
         object.angular_velocity += angular_acceleration * time.delta_seconds();
+        grid_transform.transform.rotation = integrate_rotation(grid_transform.transform.rotation, object.angular_velocity, time.delta_seconds());
+    }
+}
+
+// Time warp multiplies the simulated time of a fixed step. Physics warp adds substeps to keep
+// their length, beyond it the substeps get longer.
+fn run_physics_substeps(world: &mut World) {
+    let time_warp = world.resource::<TimeWarp>();
+    let (rate, physics_rate) = (time_warp.rate(), time_warp.physics_rate());
+    let substeps = world.resource::<PhysicsSettings>().substeps.max(1) * physics_rate.ceil() as u32;
+    let substep_delta = world.resource::<Time<Fixed>>().delta_seconds_f64() * rate / substeps as f64;
+    for _ in 0..substeps {
+        world.resource_mut::<PhysicsTime>().advance(substep_delta);
+        world.run_schedule(PhysicsSubstep);
+    }
+}
+
+fn interpolation_enabled(settings: Res<PhysicsSettings>) -> bool {
+    settings.interpolation
+}
+
+// Physics runs in FixedUpdate, so between two steps the rendered transform is blended from
+// the previous and the current physics states. `rendered` is what was written for rendering,
+// if the transform differs from it by the next step then somebody else moved the object.
+#[derive(Component, Clone, Copy)]
+pub struct PhysicsInterpolation {
+    previous: Transform,
+    current: Transform,
+    rendered: Transform,
+}
+
+impl PhysicsInterpolation {
+    fn new(transform: Transform) -> Self {
+        PhysicsInterpolation {
+            previous: transform,
+            current: transform,
+            rendered: transform,
+        }
+    }
+}
+
+#[derive(Component, Clone, Copy)]
+pub struct PhysicsInterpolationBigSpace<P: GridPrecision> {
+    previous: GridTransformOwned<P>,
+    current: GridTransformOwned<P>,
+    rendered: GridTransformOwned<P>,
+}
+
+impl<P: GridPrecision> PhysicsInterpolationBigSpace<P> {
+    fn new(grid_transform: GridTransformOwned<P>) -> Self {
+        PhysicsInterpolationBigSpace {
+            previous: grid_transform,
+            current: grid_transform,
+            rendered: grid_transform,
+        }
+    }
+}
+
+fn same_grid_transform<P: GridPrecision>(a: &GridTransformOwned<P>, b: &GridTransformOwned<P>) -> bool {
+    a.cell == b.cell && a.transform == b.transform
+}
+
+#[allow(clippy::type_complexity)]
+fn insert_physics_interpolation(
+    mut commands: Commands,
+    object_query: Query<(Entity, &Transform), (With<SpaceObject>, Without<PhysicsInterpolation>)>,
+) {
+    for (entity, transform) in object_query.iter() {
+        commands.entity(entity).insert(PhysicsInterpolation::new(*transform));
+    }
+}
+
+fn prepare_physics_interpolation(
+    mut object_query: Query<(&mut PhysicsInterpolation, &mut Transform)>,
+) {
+    for (mut interpolation, mut transform) in object_query.iter_mut() {
+        if *transform == interpolation.rendered {
+            *transform = interpolation.current;
+        }
+        interpolation.previous = *transform;
+    }
+}
+
+fn finish_physics_interpolation(
+    mut object_query: Query<(&mut PhysicsInterpolation, &Transform)>,
+) {
+    for (mut interpolation, transform) in object_query.iter_mut() {
+        interpolation.current = *transform;
+        interpolation.rendered = *transform;
+    }
+}
+
+fn interpolate_transforms(
+    fixed_time: Res<Time<Fixed>>,
+    mut object_query: Query<(&mut PhysicsInterpolation, &mut Transform)>,
+) {
+    let alpha = fixed_time.overstep_fraction();
+    for (mut interpolation, mut transform) in object_query.iter_mut() {
+        if *transform != interpolation.rendered {
+            *interpolation = PhysicsInterpolation::new(*transform);
+            continue;
+        }
+        let (previous, current) = (interpolation.previous, interpolation.current);
+        transform.translation = previous.translation.lerp(current.translation, alpha);
+        transform.rotation = previous.rotation.slerp(current.rotation, alpha);
+        interpolation.rendered = *transform;
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn insert_physics_interpolation_big_space<P: GridPrecision>(
+    mut commands: Commands,
+    object_query: Query<(Entity, GridTransformReadOnly<P>), (With<SpaceObject>, Without<PhysicsInterpolationBigSpace<P>>)>,
+) {
+    for (entity, grid_transform) in object_query.iter() {
+        commands.entity(entity).insert(PhysicsInterpolationBigSpace::new(grid_transform.to_owned()));
+    }
+}
+
+fn prepare_physics_interpolation_big_space<P: GridPrecision>(
+    mut object_query: Query<(&mut PhysicsInterpolationBigSpace<P>, GridTransform<P>)>,
+) {
+    for (mut interpolation, mut grid_transform) in object_query.iter_mut() {
+        if same_grid_transform(&grid_transform.to_owned(), &interpolation.rendered) {
+            *grid_transform.cell = interpolation.current.cell;
+            *grid_transform.transform = interpolation.current.transform;
+        }
+        interpolation.previous = grid_transform.to_owned();
+    }
+}
+
+fn finish_physics_interpolation_big_space<P: GridPrecision>(
+    mut object_query: Query<(&mut PhysicsInterpolationBigSpace<P>, GridTransformReadOnly<P>)>,
+) {
+    for (mut interpolation, grid_transform) in object_query.iter_mut() {
+        interpolation.current = grid_transform.to_owned();
+        interpolation.rendered = grid_transform.to_owned();
+    }
+}
+
+fn interpolate_transforms_big_space<P: GridPrecision>(
+    fixed_time: Res<Time<Fixed>>,
+    frames: ReferenceFrames<P>,
+    mut object_query: Query<(&mut PhysicsInterpolationBigSpace<P>, Entity, GridTransform<P>)>,
+) {
+    let alpha = fixed_time.overstep_fraction_f64();
+    for (mut interpolation, entity, mut grid_transform) in object_query.iter_mut() {
+        if !same_grid_transform(&grid_transform.to_owned(), &interpolation.rendered) {
+            *interpolation = PhysicsInterpolationBigSpace::new(grid_transform.to_owned());
+            continue;
+        }
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        let (previous, current) = (interpolation.previous, interpolation.current);
+
+        // blend relative to the current cell to stay precise far from the frame origin
+        let previous_translation = reference_frame.grid_to_float(&(previous.cell - current.cell))
+            + previous.transform.translation.as_dvec3();
+        let translation = previous_translation.lerp(current.transform.translation.as_dvec3(), alpha);
+        let (delta_cell, translation) = reference_frame.translation_to_grid(translation);
+
+        *grid_transform.cell = current.cell + delta_cell;
+        grid_transform.transform.translation = translation;
+        grid_transform.transform.rotation = previous.transform.rotation.slerp(current.transform.rotation, alpha as f32);
+        interpolation.rendered = grid_transform.to_owned();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::math::DMat3;
+
+    fn earth() -> GravitySource {
+        GravitySource::new(Entity::PLACEHOLDER, DVec3::ZERO, DVec3::ZERO, 5.972e24)
+            .with_radius(6_371_000.0)
+            .with_oblateness(DVec3::Y, Some(Oblateness { j2: 1.08263e-3, reference_radius: 6_378_137.0 }))
+    }
+
+    #[test]
+    fn oblateness_pulls_harder_at_the_equator_than_at_the_poles() {
+        let source = earth();
+        let radius = 7_000_000.0;
+        let point_mass = G * source.mass / (radius * radius);
+        let equator = source.oblateness_acceleration_at(DVec3::new(radius, 0.0, 0.0));
+        let pole = source.oblateness_acceleration_at(DVec3::new(0.0, radius, 0.0));
+        // -3/2 J2 (R/r)^2 and +3 J2 (R/r)^2 of the point mass pull, radially
+        let ratio = 1.08263e-3 * (6_378_137.0f64 / radius).powi(2);
+        assert!((equator.x / point_mass + 1.5 * ratio).abs() < 1e-12);
+        assert!((pole.y / point_mass - 3.0 * ratio).abs() < 1e-12);
+        assert_eq!(source.oblateness_acceleration_at(DVec3::new(1000.0, 0.0, 0.0)), DVec3::ZERO);
+    }
+
+    #[test]
+    fn tides_stretch_along_the_radius_and_squeeze_across_it() {
+        let source = earth();
+        let position = DVec3::new(7_000_000.0, 0.0, 0.0);
+        let gradient = G * source.mass / 7_000_000.0f64.powi(3);
+        let radial = source.tidal_acceleration_at(position, DVec3::new(10.0, 0.0, 0.0));
+        let across = source.tidal_acceleration_at(position, DVec3::new(0.0, 0.0, 10.0));
+        assert!((radial.x - 20.0 * gradient).abs() < 1e-15);
+        assert!((across.z + 10.0 * gradient).abs() < 1e-15);
 
-        let delta_rotation = Quat::from_rotation_x(object.angular_velocity.x * time.delta_seconds())
-            * Quat::from_rotation_y(object.angular_velocity.y * time.delta_seconds())
-            * Quat::from_rotation_z(object.angular_velocity.z * time.delta_seconds());
+        // close to the exact difference of the point mass pull
+        let point_mass = GravitySource { oblateness: None, ..source };
+        let exact = point_mass.acceleration_at(position + DVec3::new(10.0, 0.0, 0.0)) - point_mass.acceleration_at(position);
+        assert!((exact - radial).length() < 1e-3 * radial.length());
+    }
+
+    const SUN_MASS: f64 = 1.989e30;
+    const EARTH_MASS: f64 = 5.972e24;
+    const MOON_MASS: f64 = 7.342e22;
+    const AU: f64 = 1.496e11;
+    const MOON_DISTANCE: f64 = 3.844e8;
+
+    // The Sun at the origin, the Earth on +X and the Moon beyond it.
+    fn solar_system(sun: Entity, earth: Entity, moon: Entity) -> GravitySources {
+        let mut gravity_sources = GravitySources {
+            sources: vec![
+                GravitySource::new(sun, DVec3::ZERO, DVec3::ZERO, SUN_MASS),
+                GravitySource::new(earth, DVec3::new(AU, 0.0, 0.0), DVec3::ZERO, EARTH_MASS),
+                GravitySource::new(moon, DVec3::new(AU + MOON_DISTANCE, 0.0, 0.0), DVec3::ZERO, MOON_MASS),
+            ],
+            ..default()
+        };
+        gravity_sources.update_spheres_of_influence();
+        gravity_sources
+    }
+
+    #[test]
+    fn spheres_of_influence_follow_the_laplace_formula() {
+        let [sun, earth, moon] = [Entity::from_raw(0), Entity::from_raw(1), Entity::from_raw(2)];
+        let gravity_sources = solar_system(sun, earth, moon);
+        let [sun_source, earth_source, moon_source] = &gravity_sources.sources[..] else { unreachable!() };
+
+        // r = a (m / M)^(2/5), about 925 000 km for the Earth
+        let earth_soi = AU * (EARTH_MASS / SUN_MASS).powf(0.4);
+        assert!((earth_source.soi_radius / earth_soi - 1.0).abs() < 1e-12);
+        assert!((earth_source.soi_radius - 9.25e8).abs() < 0.01 * 9.25e8);
+        assert_eq!(earth_source.parent, Some(sun));
+        assert_eq!(moon_source.parent, Some(earth));
+        assert!((moon_source.soi_radius / (MOON_DISTANCE * (MOON_MASS / EARTH_MASS).powf(0.4)) - 1.0).abs() < 1e-12);
+        assert_eq!(sun_source.parent, None);
+        assert_eq!(sun_source.soi_radius, f64::INFINITY);
+    }
+
+    #[test]
+    fn innermost_sphere_of_influence_wins() {
+        let [sun, earth, moon] = [Entity::from_raw(0), Entity::from_raw(1), Entity::from_raw(2)];
+        let gravity_sources = solar_system(sun, earth, moon);
+        let soi_body = |position: DVec3| gravity_sources.soi_source(position).map(|source| source.entity);
+
+        // low Earth orbit, where the Sun is still the heavier body in the sky
+        assert_eq!(soi_body(DVec3::new(AU, 7_000_000.0, 0.0)), Some(earth));
+        // half way to the Moon, then just above it
+        assert_eq!(soi_body(DVec3::new(AU + MOON_DISTANCE / 2.0, 0.0, 0.0)), Some(earth));
+        assert_eq!(soi_body(DVec3::new(AU + MOON_DISTANCE, 2_000_000.0, 0.0)), Some(moon));
+        // outside the sphere of influence of the Earth
+        assert_eq!(soi_body(DVec3::new(AU, 2.0e9, 0.0)), Some(sun));
+    }
+
+    #[test]
+    fn leaving_a_sphere_of_influence_is_reported() {
+        let mut app = App::new();
+        let [sun, earth, moon] = [app.world_mut().spawn_empty().id(), app.world_mut().spawn_empty().id(), app.world_mut().spawn_empty().id()];
+        app.insert_resource(solar_system(sun, earth, moon))
+            .add_event::<SoiChanged>()
+            .add_systems(Update, update_current_soi);
+        let ship = app.world_mut().spawn((CurrentSoi::default(), Transform::from_xyz(AU as f32, 7.0e6, 0.0))).id();
+
+        app.update();
+        app.world_mut().entity_mut(ship).insert(Transform::from_xyz(AU as f32, 2.0e9, 0.0));
+        app.update();
+
+        let events: Vec<SoiChanged> = app.world_mut().resource_mut::<Events<SoiChanged>>().drain().collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].entity, events[0].previous, events[0].current), (ship, None, Some(earth)));
+        assert_eq!((events[1].entity, events[1].previous, events[1].current), (ship, Some(earth), Some(sun)));
+        assert_eq!(app.world().get::<CurrentSoi>(ship).unwrap().body, Some(sun));
+    }
+
+    #[test]
+    fn n_body_step_keeps_momentum_and_energy() {
+        // the Sun, the Earth and the Moon
+        let masses = [1.989e30, 5.972e24, 7.342e22];
+        let positions = [DVec3::ZERO, DVec3::new(1.496e11, 0.0, 0.0), DVec3::new(1.496e11 + 3.844e8, 0.0, 0.0)];
+        let mut velocities = [DVec3::ZERO, DVec3::new(0.0, 0.0, -29_780.0), DVec3::new(0.0, 0.0, -29_780.0 - 1_022.0)];
+        let mut positions = positions.to_vec();
+
+        let momentum = |velocities: &[DVec3]| masses.iter().zip(velocities).map(|(mass, velocity)| *mass * *velocity).sum::<DVec3>();
+        let energy = |positions: &[DVec3], velocities: &[DVec3]| {
+            let mut energy: f64 = masses.iter().zip(velocities).map(|(mass, velocity)| 0.5 * mass * velocity.length_squared()).sum();
+            for i in 0..3 {
+                for j in (i + 1)..3 {
+                    energy -= G * masses[i] * masses[j] / positions[i].distance(positions[j]);
+                }
+            }
+            energy
+        };
+        let initial_momentum = momentum(&velocities);
+        let initial_energy = energy(&positions, &velocities);
+
+        // a month in one hour steps
+        for _ in 0..720 {
+            let (offsets, new_velocities) = step_gravity_points(
+                Integrator::VelocityVerlet,
+                GravitySolver::BruteForce,
+                &masses,
+                &positions,
+                &velocities,
+                &[DVec3::ZERO; 3],
+                &[false; 3],
+                3_600.0,
+            );
+            for (position, offset) in positions.iter_mut().zip(offsets) {
+                *position += offset;
+            }
+            velocities.copy_from_slice(&new_velocities);
+        }
+
+        assert!((momentum(&velocities) - initial_momentum).length() < 1e-12 * initial_momentum.length());
+        assert!(((energy(&positions, &velocities) - initial_energy) / initial_energy).abs() < 1e-6);
+    }
+
+    #[test]
+    fn spin_around_a_tilted_axis_is_a_steady_rotation() {
+        let angular_velocity = Vec3::new(0.3, -0.4, 1.2);
+        let start = Quat::from_rotation_y(0.7);
+        let (steps, delta_seconds) = (640, 1.0 / 64.0);
+        let mut rotation = start;
+        for _ in 0..steps {
+            rotation = integrate_rotation(rotation, angular_velocity, delta_seconds);
+        }
+        let angle = angular_velocity.length() * steps as f32 * delta_seconds;
+        let expected = Quat::from_axis_angle(angular_velocity.normalize(), angle) * start;
+        assert!(rotation.angle_between(expected) < 1e-4, "{rotation} != {expected}");
+    }
+
+    #[test]
+    fn angular_momentum_is_in_world_axes() {
+        let mass_properties = MassProperties {
+            mass: 1.0,
+            inertia: DMat3::from_diagonal(DVec3::new(1.0, 2.0, 3.0)),
+            ..default()
+        };
+        // a quarter turn around Z puts the local -Y axis, with an inertia of 2, along world X
+        let rotation = Quat::from_rotation_z(std::f32::consts::FRAC_PI_2);
+        let object = SpaceObject { angular_velocity: Vec3::X, ..SpaceObject::new(1.0) };
+        let angular_momentum = object.angular_momentum(&mass_properties, rotation);
+        assert!((angular_momentum - DVec3::new(2.0, 0.0, 0.0)).length() < 1e-6, "{angular_momentum}");
+    }
+
+    #[test]
+    fn barnes_hut_solver_is_selected_on_the_plugin() {
+        let settings = PhysicsSettings {
+            gravity_solver: GravitySolver::BarnesHut { opening_angle: 0.5 },
+            ..default()
+        };
+        let mut app = App::new();
+        app.add_plugins(SpacePhysicsPlugin::new(settings));
+
+        // an asteroid field far from the ship
+        for i in 0..512 {
+            let cell = Vec3::new((i % 8) as f32, (i / 8 % 8) as f32, (i / 64) as f32);
+            app.world_mut().spawn((SpaceObject::new(1.0e15), GravityPoint, Transform::from_translation(cell * 1000.0)));
+        }
+        let ship = app.world_mut().spawn((SpaceObject::new(1000.0), Transform::from_xyz(-50_000.0, 2000.0, 3000.0))).id();
+        app.world_mut().run_schedule(FixedUpdate);
 
-        grid_transform.transform.rotation = delta_rotation * grid_transform.transform.rotation;
+        let gravity_sources = app.world().resource::<GravitySources>();
+        assert!(gravity_sources.octree.is_some());
+        let position = DVec3::new(-50_000.0, 2000.0, 3000.0);
+        let brute_force: DVec3 = gravity_sources.sources.iter().map(|source| source.acceleration_at(position)).sum();
+        let acceleration = app.world().get::<SpaceObject>(ship).unwrap().gravitational_force / 1000.0;
+        assert_ne!(acceleration, brute_force);
+        assert!((acceleration - brute_force).length() < 1e-3 * brute_force.length());
     }
 }
@@ -1,19 +1,28 @@
 use bevy::prelude::*;
 use bevy::window::PrimaryWindow;
 use bevy::input::mouse::MouseMotion;
-use big_space::{precision::GridPrecision,world_query::GridTransform, ReferenceFrameCommands};
+use big_space::{precision::GridPrecision,world_query::GridTransform};
 use bevy_hanabi::prelude::*;
 
+use super::allocation::{ThrusterAllocation, ThrusterEffect};
+use super::blueprint::{build_ships_from_blueprints, ShipBlueprint, ShipBlueprintLoader};
+use super::forces::ExternalForces;
+use super::mass::MassProperties;
 use super::physics::{SpaceObject, PhysicsSet};
+use super::propellant::{self, PropellantState, PropellantTank};
+use super::time_warp::{Thrusting, TimeWarp};
 
 pub struct SpaceShipPlugin;
 
 impl Plugin for SpaceShipPlugin {
     fn build(&self, app: &mut App) {
         app
+            .init_asset::<ShipBlueprint>()
+            .init_asset_loader::<ShipBlueprintLoader>()
+            .add_systems(FixedUpdate, apply_thrusters.before(PhysicsSet))
             .add_systems(Update, (
+                build_ships_from_blueprints,
                 control_ship,
-                apply_thrusters,
                 ship_rotation_full_stabilization,
                 ship_rotation_player_aim_stabilization,
                 ship_rotation_ai_aim_stabilization,
@@ -59,6 +68,8 @@ pub struct ControlKeys {
     pub switch_camera_mode: KeyCode,
     pub switch_rotation_stabilization_mode: KeyCode,
     pub switch_movement_stabilization_mode: KeyCode,
+    pub time_warp_faster_key: KeyCode,
+    pub time_warp_slower_key: KeyCode,
 }
 
 impl Default for ControlKeys {
@@ -79,6 +90,8 @@ impl Default for ControlKeys {
             switch_camera_mode: KeyCode::KeyV,
             switch_rotation_stabilization_mode: KeyCode::ControlLeft,
             switch_movement_stabilization_mode: KeyCode::ShiftLeft,
+            time_warp_faster_key: KeyCode::Period,
+            time_warp_slower_key: KeyCode::Comma,
         }
     }
 }
@@ -130,10 +143,159 @@ impl Default for SpaceShipSettings {
     }
 }
 
-#[derive(Component)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThrottleMode {
+    Continuous,
+    // Fires at full throttle for the commanded fraction of every period, in seconds.
+    PulseWidthModulation { period: f32 },
+}
+
+// `throttle` is the fraction of `force` given right now. It follows the commanded throttle
+// through first order lags, `spool_up_time` and `spool_down_time` seconds, zero for instant.
+// A lit thruster cannot run below `min_throttle`, smaller commands round to it or to off.
+// With a `specific_impulse`, in seconds, it burns from the `PropellantTank`s of the ship and
+// flames out when they are empty, without one it needs no propellant.
+#[derive(Component, Clone, Debug)]
 pub struct Thruster {
     pub force: f32,
     pub direction: Vec3,
+    pub specific_impulse: f32,
+    pub throttle: f32,
+    pub min_throttle: f32,
+    pub spool_up_time: f32,
+    pub spool_down_time: f32,
+    pub mode: ThrottleMode,
+    pulse_time: f32,
+}
+
+impl Thruster {
+    pub fn new(force: f32, direction: Vec3) -> Self {
+        Thruster {
+            force,
+            direction,
+            specific_impulse: 0.0,
+            throttle: 0.0,
+            min_throttle: 0.0,
+            spool_up_time: 0.0,
+            spool_down_time: 0.0,
+            mode: ThrottleMode::Continuous,
+            pulse_time: 0.0,
+        }
+    }
+
+    pub fn with_specific_impulse(mut self, specific_impulse: f32) -> Self {
+        self.specific_impulse = specific_impulse;
+        self
+    }
+
+    pub fn with_min_throttle(mut self, min_throttle: f32) -> Self {
+        self.min_throttle = min_throttle.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_spool_times(mut self, spool_up_time: f32, spool_down_time: f32) -> Self {
+        self.spool_up_time = spool_up_time;
+        self.spool_down_time = spool_down_time;
+        self
+    }
+
+    pub fn with_pulse_width_modulation(mut self, period: f32) -> Self {
+        self.mode = ThrottleMode::PulseWidthModulation { period };
+        self
+    }
+
+    pub fn firing(&self) -> bool {
+        self.throttle > MIN_THROTTLE
+    }
+
+    pub fn uses_propellant(&self) -> bool {
+        self.specific_impulse > 0.0
+    }
+
+    // Out of propellant, the flame goes out at once instead of spooling down.
+    pub fn flameout(&mut self) {
+        self.throttle = 0.0;
+        self.pulse_time = 0.0;
+    }
+
+    // Moves the throttle towards `command` over `delta` seconds.
+    pub fn update_throttle(&mut self, command: f32, delta: f32) {
+        let command = command.clamp(0.0, 1.0);
+        let target = match self.mode {
+            ThrottleMode::Continuous if command < 0.5 * self.min_throttle || command <= MIN_THROTTLE => 0.0,
+            ThrottleMode::Continuous => command.max(self.min_throttle),
+            ThrottleMode::PulseWidthModulation { period } => {
+                if command <= MIN_THROTTLE || period <= 0.0 {
+                    // the next pulse starts as soon as it is asked for
+                    self.pulse_time = 0.0;
+                    0.0
+                } else {
+                    let on = self.pulse_time < command * period;
+                    self.pulse_time = (self.pulse_time + delta) % period;
+                    if on { 1.0 } else { 0.0 }
+                }
+            }
+        };
+
+        let time_constant = if target > self.throttle { self.spool_up_time } else { self.spool_down_time };
+        self.throttle = if time_constant > 0.0 {
+            self.throttle + (target - self.throttle) * (1.0 - (-delta / time_constant).exp())
+        } else {
+            target
+        };
+        if target == 0.0 && self.throttle <= MIN_THROTTLE {
+            self.throttle = 0.0;
+        }
+    }
+}
+
+// Swivels a `Thruster` up to `max_angle` radians off its `direction`, at most `slew_rate`
+// radians per second. `deflection` is the rotation vector, in ship axes, turning the direction.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Gimbal {
+    pub max_angle: f32,
+    pub slew_rate: f32,
+    pub deflection: Vec3,
+}
+
+impl Gimbal {
+    pub fn new(max_angle: f32, slew_rate: f32) -> Self {
+        Gimbal { max_angle, slew_rate, deflection: Vec3::ZERO }
+    }
+
+    pub fn rotation(&self) -> Quat {
+        Quat::from_scaled_axis(self.deflection)
+    }
+
+    // Deflection tilting the push of a thruster with the given exhaust direction so that it gets
+    // the `lateral` component next to the `axial` one, as far as the gimbal reaches.
+    pub fn target_deflection(&self, direction: Vec3, lateral: Vec3, axial: f32) -> Vec3 {
+        let push = -direction.normalize_or_zero();
+        let lateral = lateral - push * lateral.dot(push);
+        if axial <= 0.0 || lateral.length_squared() == 0.0 {
+            return Vec3::ZERO;
+        }
+        let angle = lateral.length().atan2(axial).min(self.max_angle);
+        push.cross(lateral).normalize_or_zero() * angle
+    }
+
+    pub fn slew(&mut self, target: Vec3, delta: f32) {
+        self.deflection += (target - self.deflection).clamp_length_max(self.slew_rate * delta);
+        self.deflection = self.deflection.clamp_length_max(self.max_angle);
+    }
+}
+
+// Exhaust particles of a thruster, spawned at `full_rate` per second at full throttle.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ExhaustEffect {
+    pub full_rate: f32,
+    rate: f32,
+}
+
+impl ExhaustEffect {
+    pub fn new(full_rate: f32) -> Self {
+        ExhaustEffect { full_rate, rate: full_rate }
+    }
 }
 
 #[derive(Component)]
@@ -189,6 +351,7 @@ fn control_ship(
     mut ship_query: Query<&mut SpaceShip, With<Player>>,
     mut camera_query: Query<&mut SpaceShipCameraTarget>,
     mut settings_query: Query<&mut SpaceShipSettings, With<Player>>,
+    time_warp: Option<ResMut<TimeWarp>>,
     keys: Res<ButtonInput<KeyCode>>,
 ) {
     let Ok(mut ship) = ship_query.get_single_mut() else { return };
@@ -264,63 +427,149 @@ fn control_ship(
         let new_mode = settings.movement_stabilization.next();
         settings.movement_stabilization = new_mode;
     }
+
+    if let Some(mut time_warp) = time_warp {
+        if keys.just_pressed(ship.control_keys.time_warp_faster_key) {
+            time_warp.faster();
+        }
+        if keys.just_pressed(ship.control_keys.time_warp_slower_key) {
+            time_warp.slower();
+        }
+    }
 }
 
+// Below it a thruster counts as off.
+const MIN_THROTTLE: f32 = 1e-3;
+
+#[allow(clippy::type_complexity)]
 fn apply_thrusters(
-    mut ship_query: Query<(&SpaceShip, &Transform, &mut SpaceObject, &Children)>,
-    thruster_query: Query<(&Thruster, &Transform, &Children)>,
-    mut effect_query: Query<(&mut EffectSpawner, &mut EffectProperties)>,
+    time: Res<Time<Fixed>>,
+    time_warp: Option<Res<TimeWarp>>,
+    mut ship_query: Query<(&SpaceShip, &Transform, &SpaceObject, &MassProperties, &mut ExternalForces, &mut Thrusting, &Children, Option<&mut PropellantState>)>,
+    mut thruster_query: Query<(&mut Thruster, &Transform, &Children, Option<&mut Gimbal>)>,
+    mut tank_query: Query<&mut PropellantTank>,
+    mut effect_query: Query<(&mut EffectSpawner, &mut EffectProperties, Option<&mut ExhaustEffect>)>,
     audio_query: Query<&SpatialAudioSink>,
 ) {
-    for (ship, ship_transform, mut object, ship_children) in ship_query.iter_mut() {
-
-        let mut movement_acceleration = Vec3::ZERO;
-        let mut angular_acceleration = Vec3::ZERO;
-
-        for (thruster, thruster_transform, thruster_children) in thruster_query.iter_many(ship_children) {
-            let force_direction = (ship_transform.rotation * thruster.direction * -1.0).normalize();
-            let r = thruster_transform.translation;
-            let torque = r.cross(thruster.direction * -1.0 * thruster.force);
-
-            let appliable_for_movement = if ship.desired_movement_vector.length() > 0.0 {
-                thruster.direction.dot(ship.desired_movement_vector) < 0.0
-            } else { false };
-
-            let appliable_for_rotation = if ship.desired_rotation_vector.length() > 0.0 {
-                torque.dot(ship.desired_rotation_vector) > 0.0
-            } else { false };
-
-            if appliable_for_movement || appliable_for_rotation {
-                movement_acceleration += force_direction * thruster.force / object.mass;
-
-                let (a, b, c) = (1.0f32, 1.0f32, 2.5f32);
-                let moment_of_inertia = Vec3::new(
-                    (b.powi(2) + c.powi(2)) * object.mass / 12.0,
-                    (a.powi(2) + c.powi(2)) * object.mass / 12.0,
-                    (a.powi(2) + b.powi(2)) * object.mass / 12.0,
-                );
-                // let inertia_tensor = Vec3::new(
-                //     object.mass * (r.y.powi(2) + r.z.powi(2)),  // Ixx
-                //     object.mass * (r.x.powi(2) + r.z.powi(2)),  // Iyy
-                //     object.mass * (r.x.powi(2) + r.y.powi(2))   // Izz
-                // );
-                angular_acceleration += ship_transform.rotation * (torque / moment_of_inertia);
+    // the physics substeps of this fixed step cover the warped time, the engines keep up with it
+    let simulated_delta = time.delta_seconds_f64() * time_warp.map_or(1.0, |time_warp| time_warp.rate());
+    for (ship, ship_transform, object, mass_properties, mut forces, mut thrusting, ship_children, propellant_state) in ship_query.iter_mut() {
+        thrusting.0 = false;
+        let (propellant, capacity) = tank_query
+            .iter_many(ship_children)
+            .fold((0.0, 0.0), |(propellant, capacity), tank| (propellant + tank.contents, capacity + tank.capacity));
+        let starved = |thruster: &Thruster| thruster.uses_propellant() && propellant <= 0.0;
+
+        // thrusters out of propellant are left out of the allocation, gimbals start centred
+        let mut effects: Vec<ThrusterEffect> = thruster_query
+            .iter_many(ship_children)
+            .map(|(thruster, thruster_transform, _, _)| {
+                ThrusterEffect::new(
+                    thruster_transform.translation.as_dvec3(),
+                    thruster.direction.as_dvec3(),
+                    if starved(thruster) { 0.0 } else { thruster.force as f64 },
+                    mass_properties.center_of_mass,
+                )
+            })
+            .collect();
+        let thruster_count = effects.len();
+        let allocation = ThrusterAllocation::new(effects.clone());
+
+        // the wishes of the pilot are fractions of what the thrusters could give along them
+        let movement = ship.desired_movement_vector.as_dvec3();
+        let rotation = ship.desired_rotation_vector.as_dvec3();
+        let force = movement.normalize_or_zero() * allocation.max_force(movement) * movement.length().min(1.0);
+        let torque = rotation.normalize_or_zero() * allocation.max_torque(rotation) * rotation.length().min(1.0);
+        let mut throttles = allocation.allocate(force, torque);
+
+        // a burning gimballed engine can also push sideways, in proportion to its throttle, that
+        // is allocated as four extra thrusters at the engine
+        let mut lateral_columns = Vec::new();
+        for (index, (thruster, thruster_transform, _, gimbal)) in thruster_query.iter_many(ship_children).enumerate() {
+            let Some(gimbal) = gimbal else { continue };
+            if throttles[index] <= MIN_THROTTLE as f64 || starved(thruster) {
+                continue;
+            }
+            let lateral_force = thruster.force as f64 * throttles[index] * gimbal.max_angle.sin() as f64;
+            let (u, v) = thruster.direction.any_orthonormal_pair();
+            for axis in [u, -u, v, -v] {
+                effects.push(ThrusterEffect::new(
+                    thruster_transform.translation.as_dvec3(),
+                    -axis.as_dvec3(),
+                    lateral_force,
+                    mass_properties.center_of_mass,
+                ));
+                lateral_columns.push(index);
+            }
+        }
+        let mut lateral_pushes = vec![Vec3::ZERO; thruster_count];
+        if !lateral_columns.is_empty() {
+            throttles = ThrusterAllocation::new(effects.clone()).allocate(force, torque);
+            for (column, index) in lateral_columns.into_iter().enumerate() {
+                let column = thruster_count + column;
+                lateral_pushes[index] += (effects[column].force * throttles[column]).as_vec3();
+            }
+        }
+
+        let mut mass_flow = 0.0;
+        let mut specific_impulse = None;
+        let mut strongest_force = 0.0;
+        let mut thrusters = thruster_query.iter_many_mut(ship_children);
+        let mut index = 0;
+        while let Some((mut thruster, thruster_transform, thruster_children, gimbal)) = thrusters.fetch_next() {
+            let command = throttles.get(index).copied().unwrap_or(0.0) as f32;
+            if starved(&thruster) {
+                thruster.flameout();
+            } else {
+                thruster.update_throttle(command, simulated_delta as f32);
+            }
+            let direction = match gimbal {
+                Some(mut gimbal) => {
+                    let target = gimbal.target_deflection(thruster.direction, lateral_pushes[index], thruster.force * command);
+                    gimbal.slew(target, simulated_delta as f32);
+                    gimbal.rotation() * thruster.direction
+                }
+                None => thruster.direction,
+            };
+            index += 1;
+            if thruster.uses_propellant() {
+                mass_flow += propellant::mass_flow((thruster.force * thruster.throttle) as f64, thruster.specific_impulse as f64);
+                if thruster.force > strongest_force {
+                    strongest_force = thruster.force;
+                    specific_impulse = Some(thruster.specific_impulse as f64);
+                }
             }
+            let force_direction = (ship_transform.rotation * direction * -1.0).normalize();
+
+            if thruster.firing() {
+                let r = thruster_transform.translation.as_dvec3() - mass_properties.center_of_mass;
+                let point = ship_transform.rotation.as_dquat() * r;
+                forces.apply_force_at_point((force_direction * thruster.force * thruster.throttle).as_dvec3(), point);
+                thrusting.0 = true;
 
-            if appliable_for_movement || appliable_for_rotation {
                 for &child in thruster_children {
-                    if let Ok((mut effect_spawner, mut effect_properties)) = effect_query.get_mut(child) {
+                    if let Ok((mut effect_spawner, mut effect_properties, exhaust)) = effect_query.get_mut(child) {
                         let Some(velocity_value) = effect_properties.get_stored("velocity_value") else { continue; };
-                        effect_properties.set("velocity", (force_direction * -1.0 * velocity_value.as_scalar().as_f32() + object.velocity).into());
+                        effect_properties.set("velocity", (force_direction * -1.0 * velocity_value.as_scalar().as_f32() + object.velocity.as_vec3()).into());
+                        // a new spawner starts counting from scratch, so it is only swapped
+                        // when the rate changes noticeably
+                        if let Some(mut exhaust) = exhaust {
+                            let rate = exhaust.full_rate * thruster.throttle;
+                            if (rate - exhaust.rate).abs() > 0.05 * exhaust.full_rate {
+                                exhaust.rate = rate;
+                                effect_spawner.set_spawner(Spawner::rate(rate.into()));
+                            }
+                        }
                         effect_spawner.set_active(true);
                     }
                     if let Ok(audio_sink) = audio_query.get(child) {
+                        audio_sink.set_volume(thruster.throttle);
                         audio_sink.play();
                     }
                 }
             } else {
                 for &child in thruster_children {
-                    if let Ok((mut effect_spawner, _)) = effect_query.get_mut(child) {
+                    if let Ok((mut effect_spawner, _, _)) = effect_query.get_mut(child) {
                         effect_spawner.set_active(false);
                     }
                     if let Ok(audio_sink) = audio_query.get(child) {
@@ -329,46 +578,30 @@ fn apply_thrusters(
                 }
             }
         }
-        object.acceleration = movement_acceleration;
-        object.angular_acceleration = angular_acceleration;
-    }
-}
 
-fn move_camera(
-    mut mouse_motion: EventReader<MouseMotion>,
-    window_query: Query<&Window, With<PrimaryWindow>>,
-    mut camera: Query<&mut Transform, With<SpaceShipCameraTarget>>,
-    player: Query<&Transform, (With<Player>, Without<SpaceShipCameraTarget>)>,
-) {
-    let Ok(player_transform) = player.get_single() else { return };
-    let Ok(mut camera_transform) = camera.get_single_mut() else { return };
-    let Ok(window) = window_query.get_single() else { return };
-
-    let mut motion = Vec2::ZERO;
-    for m in mouse_motion.read() {
-        motion = m.delta;
-    }
-
-    let rotation = {
-        if motion.length_squared() > 0.0 {
-            let delta_x = -motion.x / window.width() * std::f32::consts::PI;
-            let delta_y = -motion.y / window.height() * std::f32::consts::PI;
-            let rotation = Quat::from_rotation_y(delta_x) * camera_transform.rotation;
-            let rotation_with_pitch = rotation * Quat::from_rotation_x(delta_y);
-            let up_vector = rotation_with_pitch * Vec3::Y;
-            if up_vector.y > 0.0 {
-                rotation_with_pitch
-            } else {
-                rotation
+        // the burnt propellant leaves the tank masses, and so the ship, on the next fixed step
+        let burnt = (mass_flow * simulated_delta).min(propellant);
+        if burnt > 0.0 {
+            for &child in ship_children {
+                if let Ok(mut tank) = tank_query.get_mut(child) {
+                    tank.drain(burnt / propellant);
+                }
             }
-        } else {
-            camera_transform.rotation
         }
-    };
 
-    let rotation_metrix = Mat3::from_quat(rotation);
-    camera_transform.translation = rotation_metrix.mul_vec3(Vec3::new(0.0,1.5, 10.0)) + player_transform.translation;
-    camera_transform.rotation = rotation;
+        if let Some(mut state) = propellant_state {
+            let remaining = propellant - burnt;
+            *state = PropellantState {
+                propellant: remaining,
+                capacity,
+                mass_flow,
+                delta_v: specific_impulse.map_or(0.0, |specific_impulse| {
+                    propellant::delta_v(specific_impulse, object.mass - burnt, object.mass - burnt - remaining)
+                }),
+                flameout: remaining <= 0.0 && specific_impulse.is_some(),
+            };
+        }
+    }
 }
 
 fn move_camera_big_space<P: GridPrecision>(
@@ -404,7 +637,7 @@ fn move_camera_big_space<P: GridPrecision>(
     };
 
     let rotation_metrix = Mat3::from_quat(rotation);
-    *camera_grid_transform.cell = player_grid_transform.cell.clone();
+    *camera_grid_transform.cell = *player_grid_transform.cell;
     camera_grid_transform.transform.translation = player_grid_transform.transform.translation + rotation_metrix.mul_vec3(Vec3::new(0.0,1.5, 10.0));
     camera_grid_transform.transform.rotation = rotation;
 }
@@ -423,14 +656,14 @@ fn ship_rotation_full_stabilization(
             ship.desired_rotation_vector = Vec3::ZERO;
             continue;
         }
-        let stabilization_angular_vector = object.angular_velocity.normalize() * -1.0;
+        let stabilization_angular_vector = object.local_angular_velocity(ship_transform.rotation).normalize() * -1.0;
 
         // let stabilization_angular_vector_x = if object.angular_velocity.x.abs().to_degrees() > PERMISSIBLE_STABILIZATION_ERROR { -object.angular_velocity.x } else { 0.0 };
         // let stabilization_angular_vector_y = if object.angular_velocity.y.abs().to_degrees() > PERMISSIBLE_STABILIZATION_ERROR { -object.angular_velocity.y } else { 0.0 };
         // let stabilization_angular_vector_z = if object.angular_velocity.z.abs().to_degrees() > PERMISSIBLE_STABILIZATION_ERROR { -object.angular_velocity.z } else { 0.0 };
         // let stabilization_angular_vector = Vec3::new(stabilization_angular_vector_x, stabilization_angular_vector_y, stabilization_angular_vector_z).normalize();
 
-        ship.desired_rotation_vector = ship_transform.rotation.inverse() * stabilization_angular_vector;
+        ship.desired_rotation_vector = stabilization_angular_vector;
     }
 }
 
@@ -465,21 +698,23 @@ fn ship_rotation_aim_stabilization(
     const MIN_ANGULAR_VELOCITY: f32 = std::f32::consts::PI / 12.0;  // 15 degrees / second
     const STABILIZATION_THRESHOLD: f32 = std::f32::consts::PI / 60.0;  // 3 degrees
 
+    // `desired_rotation_vector` is in ship axes, so is everything here
+    let angular_velocity = object.local_angular_velocity(player_transform.rotation);
     let desired_direction = (player_transform.rotation.inverse() * target_rotation).xyz().normalize();
-    let current_velocity = object.angular_velocity.length();
+    let current_velocity = angular_velocity.length();
     let max_angular_velocity = (player_transform.rotation.angle_between(target_rotation) / 2.0).max(MIN_ANGULAR_VELOCITY);  // from 15 to 120 degrees per sec
 
     if player_transform.rotation.angle_between(target_rotation) < STABILIZATION_THRESHOLD {
         if current_velocity > STABILIZATION_THRESHOLD {
-            ship.desired_rotation_vector = player_transform.rotation.inverse() * object.angular_velocity.normalize_or_zero() * -1.0;
+            ship.desired_rotation_vector = angular_velocity.normalize_or_zero() * -1.0;
         } else {
             ship.desired_rotation_vector = Vec3::ZERO;
         }
     } else if current_velocity > max_angular_velocity {
-        ship.desired_rotation_vector = player_transform.rotation.inverse() * object.angular_velocity.normalize_or_zero() * -1.0;
+        ship.desired_rotation_vector = angular_velocity.normalize_or_zero() * -1.0;
     } else {
-        if object.angular_velocity.normalize_or_zero().dot(desired_direction) < 0.97 || current_velocity < MIN_ANGULAR_VELOCITY {
-            let current_direction = object.angular_velocity.normalize_or_zero();
+        if angular_velocity.normalize_or_zero().dot(desired_direction) < 0.97 || current_velocity < MIN_ANGULAR_VELOCITY {
+            let current_direction = angular_velocity.normalize_or_zero();
             let direction_difference = desired_direction - current_direction;
             ship.desired_rotation_vector = (current_direction + direction_difference).normalize();
         } else {
@@ -491,7 +726,7 @@ fn ship_rotation_aim_stabilization(
 fn ship_movement_stabilization(
     mut ship_query: Query<(&SpaceObject, &Transform, &mut SpaceShip, &SpaceShipSettings)>,
 ) {
-    const PERMISSIBLE_STABILIZATION_ERROR: f32 = 0.3;  // 0.3 m/s
+    const PERMISSIBLE_STABILIZATION_ERROR: f64 = 0.3;  // 0.3 m/s
     for (object, ship_transform, mut ship, settings) in ship_query.iter_mut() {
         if settings.movement_stabilization == MovementStabilization::No { continue; }
 
@@ -500,12 +735,12 @@ fn ship_movement_stabilization(
             continue;
         }
 
-        let stabilization_vector = object.velocity.normalize() * -1.0;
+        let stabilization_vector = object.velocity.normalize().as_vec3() * -1.0;
         ship.desired_movement_vector = ship_transform.rotation.inverse() * stabilization_vector;
     }
 }
 
-fn create_side_thruster_effect() -> EffectAsset {
+pub(super) fn create_side_thruster_effect() -> EffectAsset {
     let writer = ExprWriter::new();
 
     let age = writer.lit(0.).expr();
@@ -558,7 +793,7 @@ fn create_side_thruster_effect() -> EffectAsset {
     })
 }
 
-fn create_main_thruster_effect() -> EffectAsset {
+pub(super) fn create_main_thruster_effect() -> EffectAsset {
     let writer = ExprWriter::new();
 
     let age = writer.lit(0.).expr();
@@ -613,411 +848,111 @@ fn create_main_thruster_effect() -> EffectAsset {
     })
 }
 
-pub fn spawn_ship(
-    commands: &mut ReferenceFrameCommands<i64>,
-    meshes: &mut ResMut<Assets<Mesh>>,
-    materials: &mut ResMut<Assets<StandardMaterial>>,
-    effects: &mut ResMut<Assets<EffectAsset>>,
-    asset_server: &Res<AssetServer>,
-) {
-    let ship_mesh = meshes.add(Cuboid::new(1.0, 1.0, 2.5));
-
-    commands.with_children(|children| {
-        children.spawn((
-            PbrBundle {
-                mesh: ship_mesh,
-                material: materials.add(Color::srgb_u8(255, 0, 0)),
-                ..default()
-            },
-        ));
-    });
-
-    // Thrusters
-    
-    commands.with_children(|parent| {
-
-        // main thruster
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.5, 0.5, 0.5)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(0.0, 0.0, 1.5),
-                ..default()
-            },
-            Thruster { force: 1000.0, direction: Vec3::Z },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_main_thruster_effect())),
-                    transform: Transform::from_xyz(0.0, 0.0, 0.25),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/main_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        // side thrusters (top)
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(0.0, 0.55, -1.1),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::Y },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(0.0, 0.05, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(0.0, 0.55, 1.1),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::Y },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(0.0, 0.05, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(-0.4, 0.55, 0.0),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::Y },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(0.0, 0.05, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(0.4, 0.55, 0.0),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::Y },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(0.0, 0.05, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        // side thrusters (bottom)
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(0.0, -0.55, -1.1),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::NEG_Y },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(0.0, -0.05, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(0.0, -0.55, 1.1),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::NEG_Y },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(0.0, -0.05, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(-0.4, -0.55, 0.0),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::NEG_Y },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(0.0, -0.05, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(0.4, -0.55, 0.0),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::NEG_Y },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(0.0, -0.05, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        // side thrusters (left)
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(-0.55, 0.0, -1.1),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::NEG_X },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(-0.05, 0.0, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(-0.55, 0.0, 1.1),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::NEG_X },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(-0.05, 0.0, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(-0.55, 0.4, 0.0),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::NEG_X },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(-0.05, 0.0, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(-0.55, -0.4, 0.0),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::NEG_X },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(-0.05, 0.0, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        // side thrusters (right)
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(0.55, 0.0, -1.1),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::X },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(0.05, 0.0, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(0.55, 0.0, 1.1),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::X },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(0.05, 0.0, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(0.55, 0.4, 0.0),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::X },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(0.05, 0.0, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-
-        parent.spawn((
-            PbrBundle {
-                mesh: meshes.add(Cuboid::new(0.1, 0.1, 0.1)),
-                material: materials.add(Color::srgb_u8(50, 50, 50)),
-                transform: Transform::from_xyz(0.55, -0.4, 0.0),
-                ..default()
-            },
-            Thruster { force: 100.0, direction: Vec3::X },
-        )).with_children(|thruster| {
-            thruster.spawn((
-                ParticleEffectBundle {
-                    effect: ParticleEffect::new(effects.add(create_side_thruster_effect())),
-                    transform: Transform::from_xyz(0.05, 0.0, 0.0),
-                    ..default()
-                },
-                AudioBundle {
-                    source: asset_server.load("sounds/side_thruster.ogg"),
-                    settings: PlaybackSettings::LOOP.with_spatial(true).paused(),
-                },
-            ));
-        });
-    });
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::mass::{MassPart, PartShape};
+    use super::super::physics::SpacePhysicsPlugin;
+
+    const STEP: f32 = 1.0 / 64.0;
+
+    #[test]
+    fn throttle_spools_up_and_down() {
+        let mut thruster = Thruster::new(1000.0, Vec3::Z).with_spool_times(0.4, 0.2);
+        thruster.update_throttle(1.0, STEP);
+        assert!(thruster.throttle > 0.0 && thruster.throttle < 0.1);
+        // one time constant gets about two thirds of the way
+        for _ in 1..26 {
+            thruster.update_throttle(1.0, STEP);
+        }
+        assert!((thruster.throttle - (1.0 - (-1.0f32).exp())).abs() < 0.02);
+        for _ in 0..128 {
+            thruster.update_throttle(0.0, STEP);
+        }
+        assert_eq!(thruster.throttle, 0.0);
+        assert!(!thruster.firing());
+    }
+
+    #[test]
+    fn small_commands_round_to_min_throttle_or_off() {
+        let mut thruster = Thruster::new(1000.0, Vec3::Z).with_min_throttle(0.4);
+        thruster.update_throttle(0.3, STEP);
+        assert_eq!(thruster.throttle, 0.4);
+        thruster.update_throttle(0.1, STEP);
+        assert_eq!(thruster.throttle, 0.0);
+        thruster.update_throttle(0.7, STEP);
+        assert_eq!(thruster.throttle, 0.7);
+    }
+
+    #[test]
+    fn pulse_width_modulation_averages_to_the_command() {
+        let mut thruster = Thruster::new(100.0, Vec3::Y).with_pulse_width_modulation(0.25);
+        let steps = 64 * 4;
+        let mut total = 0.0;
+        for _ in 0..steps {
+            thruster.update_throttle(0.25, STEP);
+            assert!(thruster.throttle == 0.0 || thruster.throttle == 1.0);
+            total += thruster.throttle;
+        }
+        assert!((total / steps as f32 - 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn gimbal_tilts_the_push_towards_the_lateral_force() {
+        let mut gimbal = Gimbal::new(0.1, 0.2);
+        // pushing along -Z, asked for a little +X
+        let target = gimbal.target_deflection(Vec3::Z, Vec3::new(5.0, 0.0, 0.0), 100.0);
+        assert!((target.length() - 0.05f32.atan()).abs() < 1e-6);
+        let push = Quat::from_scaled_axis(target) * Vec3::NEG_Z;
+        assert!(push.x > 0.0 && push.y.abs() < 1e-6);
+        // too much is clamped, without thrust it centres
+        assert_eq!(gimbal.target_deflection(Vec3::Z, Vec3::new(500.0, 0.0, 0.0), 100.0).length(), 0.1);
+        assert_eq!(gimbal.target_deflection(Vec3::Z, Vec3::X, 0.0), Vec3::ZERO);
+
+        gimbal.slew(Vec3::new(0.0, 0.1, 0.0), 0.25);
+        assert!((gimbal.deflection.y - 0.05).abs() < 1e-6);
+        gimbal.slew(Vec3::new(0.0, 0.1, 0.0), 1.0);
+        assert!((gimbal.deflection.y - 0.1).abs() < 1e-6);
+        gimbal.slew(Vec3::new(0.0, 1.0, 0.0), 10.0);
+        assert!(gimbal.deflection.length() <= 0.1 + 1e-6);
+    }
+
+    #[test]
+    fn burn_under_time_warp_spends_the_propellant_of_its_delta_v() {
+        let mut app = App::new();
+        app
+            .add_plugins(SpacePhysicsPlugin::default())
+            .add_systems(FixedUpdate, apply_thrusters.before(PhysicsSet));
+        let ship = app.world_mut().spawn((
+            SpaceShip { desired_movement_vector: Vec3::NEG_Z, ..default() },
+            SpaceObject::new(1500.0),
+            MassPart::new(1000.0, PartShape::Point),
+            MassProperties::default(),
+            ExternalForces::default(),
+            Thrusting(false),
+            PropellantState::default(),
+            Transform::default(),
+        )).with_children(|ship| {
+            ship.spawn((PropellantTank::new(500.0, 0.0), MassPart::new(500.0, PartShape::Point), Transform::default()));
+            ship.spawn((Thruster::new(1000.0, Vec3::Z).with_specific_impulse(100.0), Transform::default()))
+                .with_children(|thruster| { thruster.spawn_empty(); });
+        }).id();
+        app.world_mut().resource_mut::<TimeWarp>().set_level(2);
+
+        for _ in 0..64 {
+            app.world_mut().resource_mut::<Time<Fixed>>().advance_by(Duration::from_secs_f64(STEP as f64));
+            app.world_mut().run_schedule(FixedUpdate);
+        }
+
+        assert_eq!(app.world().resource::<TimeWarp>().rate(), 4.0);
+        let object = app.world().get::<SpaceObject>(ship).unwrap();
+        // four simulated seconds of burning at full thrust
+        let (spent, burn) = (1500.0 - object.mass, 4.0 * propellant::mass_flow(1000.0, 100.0));
+        assert!((spent - burn).abs() < 1e-5 * burn);
+        let delta_v = propellant::delta_v(100.0, 1500.0, object.mass);
+        assert!((object.velocity.length() - delta_v).abs() < 1e-3 * delta_v);
+        assert!(object.velocity.z < 0.0);
+    }
 }
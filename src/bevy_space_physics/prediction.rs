@@ -0,0 +1,504 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    color::palettes::css::{AQUA, ORANGE, RED},
+    math::DVec3,
+    prelude::*,
+    utils::HashMap,
+};
+
+use big_space::{
+    precision::GridPrecision,
+    reference_frame::local_origin::ReferenceFrames,
+    world_query::GridTransformReadOnly,
+    FloatingOrigin,
+};
+
+use super::integrator::Integrator;
+use super::orbit::KeplerOrbit;
+use super::physics::{
+    kepler_state, step_gravity_points, BodyRadius, GravityPoint, GravitySources, GravitySource, Oblateness, PhysicsSet,
+    PhysicsSettings, PhysicsTime, SpaceObject,
+};
+
+pub struct TrajectoryPredictionPlugin;
+
+impl Plugin for TrajectoryPredictionPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_systems(FixedUpdate, predict_trajectories.after(PhysicsSet))
+            .add_systems(PostUpdate, draw_trajectory_predictions);
+    }
+}
+
+pub struct TrajectoryPredictionPluginBigSpace<P: GridPrecision> {
+    _precision: PhantomData<P>,
+}
+
+impl<P: GridPrecision> Default for TrajectoryPredictionPluginBigSpace<P> {
+    fn default() -> Self {
+        TrajectoryPredictionPluginBigSpace { _precision: PhantomData }
+    }
+}
+
+impl<P: GridPrecision> Plugin for TrajectoryPredictionPluginBigSpace<P> {
+    fn build(&self, app: &mut App) {
+        app
+            .add_systems(FixedUpdate, predict_trajectories_big_space::<P>.after(PhysicsSet))
+            .add_systems(PostUpdate, draw_trajectory_predictions_big_space::<P>);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PredictedEvent {
+    pub body: Entity,
+    pub time: f64,  // physics time of the event
+    pub position: DVec3,  // in the same frame as `TrajectoryPrediction::points`
+    pub distance: f64,  // from the centre of the body
+}
+
+// Coasting trajectory of an object under the gravity points, recomputed every `refresh_interval`
+// seconds of physics time. Points are relative to the `reference` body, the one whose sphere of
+// influence the object is in at the start, so that orbits are drawn as closed curves.
+// The closest approach is measured to `target`, or to the reference body when it is not set.
+#[derive(Component, Debug)]
+pub struct TrajectoryPrediction {
+    pub horizon: f64,
+    pub step: f64,
+    pub refresh_interval: f64,
+    pub target: Option<Entity>,
+    pub reference: Option<Entity>,
+    pub points: Vec<DVec3>,
+    pub closest_approach: Option<PredictedEvent>,
+    pub impact: Option<PredictedEvent>,
+    next_refresh: f64,
+}
+
+impl Default for TrajectoryPrediction {
+    fn default() -> Self {
+        TrajectoryPrediction {
+            horizon: 86_400.0,
+            step: 30.0,
+            refresh_interval: 0.25,
+            target: None,
+            reference: None,
+            points: Vec::new(),
+            closest_approach: None,
+            impact: None,
+            next_refresh: 0.0,
+        }
+    }
+}
+
+// Gravity point as seen at the start of the prediction.
+#[derive(Clone, Copy)]
+struct PredictedBody {
+    entity: Entity,
+    position: DVec3,
+    velocity: DVec3,
+    acceleration: DVec3,
+    mass: f64,
+    orbit: Option<KeplerOrbit>,
+    radius: Option<f64>,
+    axis: DVec3,
+    oblateness: Option<Oblateness>,
+}
+
+// Moves gravity points the same way the physics step does: on-rails ones analytically,
+// the others with mutual gravity in n-body mode or with their own acceleration otherwise.
+fn advance_bodies(
+    bodies: &mut [PredictedBody],
+    orbits: &HashMap<Entity, KeplerOrbit>,
+    settings: &PhysicsSettings,
+    time: f64,
+    delta_seconds: f64,
+) {
+    if settings.n_body {
+        let masses: Vec<f64> = bodies.iter().map(|body| body.mass).collect();
+        let positions: Vec<DVec3> = bodies.iter().map(|body| body.position).collect();
+        let velocities: Vec<DVec3> = bodies.iter().map(|body| body.velocity).collect();
+        let accelerations: Vec<DVec3> = bodies.iter().map(|body| body.acceleration).collect();
+        let kinematic: Vec<bool> = bodies.iter().map(|body| body.orbit.is_some()).collect();
+        let (offsets, velocities) = step_gravity_points(settings.integrator, settings.gravity_solver, &masses, &positions, &velocities, &accelerations, &kinematic, delta_seconds);
+        for (i, body) in bodies.iter_mut().enumerate() {
+            body.position += offsets[i];
+            body.velocity = velocities[i];
+        }
+    } else {
+        for body in bodies.iter_mut().filter(|body| body.orbit.is_none()) {
+            body.position += body.velocity * delta_seconds + 0.5 * body.acceleration * delta_seconds * delta_seconds;
+            body.velocity += body.acceleration * delta_seconds;
+        }
+    }
+
+    let states: HashMap<Entity, (DVec3, DVec3, f64)> = bodies
+        .iter()
+        .map(|body| (body.entity, (body.position, body.velocity, body.mass)))
+        .collect();
+    for body in bodies.iter_mut().filter(|body| body.orbit.is_some()) {
+        if let Some((position, velocity)) = kepler_state(body.entity, time, orbits, &states, 0) {
+            body.position = position;
+            body.velocity = velocity;
+        }
+    }
+}
+
+fn gravity_sources_of(bodies: &[PredictedBody], settings: &PhysicsSettings) -> GravitySources {
+    let mut gravity_sources = GravitySources::default();
+    gravity_sources.sources = bodies
+        .iter()
+        .map(|body| {
+            GravitySource::new(body.entity, body.position, body.velocity, body.mass)
+                .with_radius(body.radius.unwrap_or(0.0))
+                .with_oblateness(body.axis, body.oblateness)
+        })
+        .collect();
+    gravity_sources.rebuild(settings);
+    gravity_sources
+}
+
+// Fraction of the segment at which it enters a sphere around the origin.
+fn segment_sphere_entry(start: DVec3, end: DVec3, radius: f64) -> Option<f64> {
+    if start.length_squared() <= radius * radius {
+        return Some(0.0);
+    }
+    let direction = end - start;
+    let a = direction.length_squared();
+    let b = 2.0 * start.dot(direction);
+    let c = start.length_squared() - radius * radius;
+    let discriminant = b * b - 4.0 * a * c;
+    if a == 0.0 || discriminant < 0.0 {
+        return None;
+    }
+    let fraction = (-b - discriminant.sqrt()) / (2.0 * a);
+    (0.0..=1.0).contains(&fraction).then_some(fraction)
+}
+
+// Fraction of the segment closest to the origin.
+fn segment_closest_point(start: DVec3, end: DVec3) -> f64 {
+    let direction = end - start;
+    if direction.length_squared() == 0.0 {
+        return 0.0;
+    }
+    (-start.dot(direction) / direction.length_squared()).clamp(0.0, 1.0)
+}
+
+fn predict_trajectory(
+    prediction: &mut TrajectoryPrediction,
+    settings: &PhysicsSettings,
+    integrator: Integrator,
+    start_time: f64,
+    mut bodies: Vec<PredictedBody>,
+    mut position: DVec3,
+    mut velocity: DVec3,
+) {
+    const MAX_STEPS: usize = 100_000;
+
+    prediction.points.clear();
+    prediction.closest_approach = None;
+    prediction.impact = None;
+    let step = prediction.step;
+    if step <= 0.0 {
+        return;
+    }
+
+    let orbits: HashMap<Entity, KeplerOrbit> = bodies
+        .iter()
+        .filter_map(|body| Some((body.entity, body.orbit?)))
+        .collect();
+
+    let mut gravity_sources = gravity_sources_of(&bodies, settings);
+    gravity_sources.update_spheres_of_influence();
+    prediction.reference = gravity_sources.soi_source(position).map(|source| source.entity);
+
+    let reference_index = prediction.reference.and_then(|reference| bodies.iter().position(|body| body.entity == reference));
+    let target_index = prediction.target.or(prediction.reference).and_then(|target| bodies.iter().position(|body| body.entity == target));
+    let relative = |bodies: &[PredictedBody], position: DVec3| match reference_index {
+        Some(i) => position - bodies[i].position,
+        None => position,
+    };
+
+    prediction.points.push(relative(&bodies, position));
+    let steps = ((prediction.horizon / step).ceil() as usize).min(MAX_STEPS);
+    for k in 1..=steps {
+        let time = start_time + k as f64 * step;
+        let previous_position = position;
+        let previous_bodies: Vec<DVec3> = bodies.iter().map(|body| body.position).collect();
+        let previous_point = *prediction.points.last().unwrap();
+
+        // like in the physics step, gravity is sampled from the bodies at the end of the step
+        advance_bodies(&mut bodies, &orbits, settings, time, step);
+        let mut gravity_sources = gravity_sources_of(&bodies, settings);
+        if settings.patched_conics {
+            gravity_sources.update_spheres_of_influence();
+        }
+        (position, velocity) = integrator.step(position, velocity, step, |position| {
+            gravity_sources.acceleration_at(position)
+        });
+        let point = relative(&bodies, position);
+
+        let event_at = |body: &PredictedBody, fraction: f64, distance: f64| PredictedEvent {
+            body: body.entity,
+            time: time - (1.0 - fraction) * step,
+            position: previous_point.lerp(point, fraction),
+            distance,
+        };
+
+        if let Some(i) = target_index {
+            let start = previous_position - previous_bodies[i];
+            let end = position - bodies[i].position;
+            let fraction = segment_closest_point(start, end);
+            let distance = start.lerp(end, fraction).length();
+            if prediction.closest_approach.is_none_or(|closest| distance < closest.distance) {
+                prediction.closest_approach = Some(event_at(&bodies[i], fraction, distance));
+            }
+        }
+
+        for (body, previous_body) in bodies.iter().zip(&previous_bodies) {
+            let Some(radius) = body.radius else {
+                continue;
+            };
+            let start = previous_position - *previous_body;
+            let end = position - body.position;
+            if let Some(fraction) = segment_sphere_entry(start, end, radius) {
+                let impact = event_at(body, fraction, radius);
+                prediction.points.push(impact.position);
+                prediction.impact = Some(impact);
+                return;
+            }
+        }
+
+        prediction.points.push(point);
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn predict_trajectories(
+    settings: Res<PhysicsSettings>,
+    time: Res<PhysicsTime>,
+    bodies_query: Query<(&SpaceObject, Entity, &Transform, Option<&KeplerOrbit>, Option<&BodyRadius>, Option<&Oblateness>), With<GravityPoint>>,
+    mut object_query: Query<(&mut TrajectoryPrediction, &SpaceObject, &Transform, Option<&Integrator>), Without<GravityPoint>>,
+) {
+    for (mut prediction, object, transform, integrator) in object_query.iter_mut() {
+        if time.elapsed_seconds_f64() < prediction.next_refresh {
+            continue;
+        }
+        prediction.next_refresh = time.elapsed_seconds_f64() + prediction.refresh_interval;
+
+        let bodies = bodies_query
+            .iter()
+            .map(|(body, entity, transform, orbit, radius, oblateness)| PredictedBody {
+                entity,
+                position: transform.translation.as_dvec3(),
+                velocity: body.velocity,
+                acceleration: body.acceleration,
+                mass: body.mass,
+                orbit: orbit.copied(),
+                radius: radius.map(|radius| radius.0),
+                axis: (transform.rotation * Vec3::Y).as_dvec3(),
+                oblateness: oblateness.copied(),
+            })
+            .collect();
+        let integrator = integrator.copied().unwrap_or(settings.integrator);
+        predict_trajectory(&mut prediction, &settings, integrator, time.elapsed_seconds_f64(), bodies, transform.translation.as_dvec3(), object.velocity);
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn predict_trajectories_big_space<P: GridPrecision>(
+    settings: Res<PhysicsSettings>,
+    time: Res<PhysicsTime>,
+    frames: ReferenceFrames<P>,
+    bodies_query: Query<(&SpaceObject, Entity, GridTransformReadOnly<P>, Option<&KeplerOrbit>, Option<&BodyRadius>, Option<&Oblateness>), With<GravityPoint>>,
+    mut object_query: Query<(&mut TrajectoryPrediction, &SpaceObject, Entity, GridTransformReadOnly<P>, Option<&Integrator>), Without<GravityPoint>>,
+) {
+    for (mut prediction, object, entity, grid_transform, integrator) in object_query.iter_mut() {
+        if time.elapsed_seconds_f64() < prediction.next_refresh {
+            continue;
+        }
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        prediction.next_refresh = time.elapsed_seconds_f64() + prediction.refresh_interval;
+
+        let bodies = bodies_query
+            .iter()
+            .filter_map(|(body, entity, grid_transform, orbit, radius, oblateness)| {
+                let reference_frame = frames.parent_frame(entity)?;
+                Some(PredictedBody {
+                    entity,
+                    position: grid_transform.position_double(reference_frame),
+                    velocity: body.velocity,
+                    acceleration: body.acceleration,
+                    mass: body.mass,
+                    orbit: orbit.copied(),
+                    radius: radius.map(|radius| radius.0),
+                    axis: (grid_transform.transform.rotation * Vec3::Y).as_dvec3(),
+                    oblateness: oblateness.copied(),
+                })
+            })
+            .collect();
+        let integrator = integrator.copied().unwrap_or(settings.integrator);
+        let position = grid_transform.position_double(reference_frame);
+        predict_trajectory(&mut prediction, &settings, integrator, time.elapsed_seconds_f64(), bodies, position, object.velocity);
+    }
+}
+
+// `anchor` is the rendered position of the origin of the prediction points.
+fn draw_trajectory_prediction(gizmos: &mut Gizmos, prediction: &TrajectoryPrediction, anchor: DVec3) {
+    let to_render = |point: DVec3| (anchor + point).as_vec3();
+    gizmos.linestrip(prediction.points.iter().map(|point| to_render(*point)), AQUA);
+
+    // markers keep the same apparent size at any distance from the camera
+    if let Some(closest_approach) = prediction.closest_approach {
+        let position = to_render(closest_approach.position);
+        gizmos.sphere(position, Quat::IDENTITY, position.length() * 0.01, ORANGE);
+    }
+    if let Some(impact) = prediction.impact {
+        let position = to_render(impact.position);
+        gizmos.sphere(position, Quat::IDENTITY, position.length() * 0.01, RED);
+    }
+}
+
+fn draw_trajectory_predictions(
+    object_query: Query<(&TrajectoryPrediction, &Transform)>,
+    body_query: Query<&Transform, With<GravityPoint>>,
+    mut gizmos: Gizmos,
+) {
+    for (prediction, transform) in object_query.iter() {
+        let Some(&start) = prediction.points.first() else {
+            continue;
+        };
+        let anchor = match prediction.reference.and_then(|reference| body_query.get(reference).ok()) {
+            Some(body_transform) => body_transform.translation.as_dvec3(),
+            None => transform.translation.as_dvec3() - start,
+        };
+        draw_trajectory_prediction(&mut gizmos, prediction, anchor);
+    }
+}
+
+// Positions are made relative to the floating origin in f64 before going to f32 rendering space.
+fn draw_trajectory_predictions_big_space<P: GridPrecision>(
+    frames: ReferenceFrames<P>,
+    origin_query: Query<(Entity, GridTransformReadOnly<P>), With<FloatingOrigin>>,
+    object_query: Query<(&TrajectoryPrediction, Entity, GridTransformReadOnly<P>)>,
+    body_query: Query<GridTransformReadOnly<P>, With<GravityPoint>>,
+    mut gizmos: Gizmos,
+) {
+    let Ok((origin_entity, origin_grid_transform)) = origin_query.get_single() else { return };
+    let Some(origin_frame) = frames.parent_frame(origin_entity) else { return };
+    let origin = origin_grid_transform.position_double(origin_frame);
+
+    for (prediction, entity, grid_transform) in object_query.iter() {
+        let Some(&start) = prediction.points.first() else {
+            continue;
+        };
+        let reference = prediction.reference.and_then(|reference| {
+            let reference_frame = frames.parent_frame(reference)?;
+            Some(body_query.get(reference).ok()?.position_double(reference_frame))
+        });
+        let anchor = match reference {
+            Some(position) => position - origin,
+            None => {
+                let Some(reference_frame) = frames.parent_frame(entity) else {
+                    continue;
+                };
+                grid_transform.position_double(reference_frame) - start - origin
+            }
+        };
+        draw_trajectory_prediction(&mut gizmos, prediction, anchor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::physics::G;
+
+    const EARTH_MASS: f64 = 5.972e24;
+    const EARTH_RADIUS: f64 = 6_371_000.0;
+
+    fn body_at_origin(mass: f64, radius: Option<f64>) -> PredictedBody {
+        PredictedBody {
+            entity: Entity::from_raw(0),
+            position: DVec3::ZERO,
+            velocity: DVec3::ZERO,
+            acceleration: DVec3::ZERO,
+            mass,
+            orbit: None,
+            radius,
+            axis: DVec3::Y,
+            oblateness: None,
+        }
+    }
+
+    #[test]
+    fn segment_entering_a_sphere() {
+        let hit = segment_sphere_entry(DVec3::new(3.0, 0.0, 0.0), DVec3::new(-1.0, 0.0, 0.0), 1.0);
+        assert_eq!(hit, Some(0.5));
+        assert_eq!(segment_sphere_entry(DVec3::new(0.5, 0.0, 0.0), DVec3::new(3.0, 0.0, 0.0), 1.0), Some(0.0));
+        // passing beside the sphere, and stopping short of it
+        assert_eq!(segment_sphere_entry(DVec3::new(3.0, 2.0, 0.0), DVec3::new(-3.0, 2.0, 0.0), 1.0), None);
+        assert_eq!(segment_sphere_entry(DVec3::new(3.0, 0.0, 0.0), DVec3::new(2.0, 0.0, 0.0), 1.0), None);
+    }
+
+    #[test]
+    fn segment_closest_point_is_clamped_to_the_segment() {
+        assert_eq!(segment_closest_point(DVec3::new(-1.0, 1.0, 0.0), DVec3::new(3.0, 1.0, 0.0)), 0.25);
+        assert_eq!(segment_closest_point(DVec3::new(1.0, 1.0, 0.0), DVec3::new(2.0, 1.0, 0.0)), 0.0);
+        assert_eq!(segment_closest_point(DVec3::new(-2.0, 1.0, 0.0), DVec3::new(-1.0, 1.0, 0.0)), 1.0);
+    }
+
+    #[test]
+    fn closest_approach_of_a_flyby_falls_between_points() {
+        // a body too light to bend the path, passed 100 m away halfway through a step
+        let body = body_at_origin(1.0, None);
+        let mut prediction = TrajectoryPrediction { horizon: 200.0, step: 30.0, ..default() };
+        let settings = PhysicsSettings::default();
+        let position = DVec3::new(-1_050.0, 100.0, 0.0);
+        let velocity = DVec3::new(10.0, 0.0, 0.0);
+        predict_trajectory(&mut prediction, &settings, Integrator::VelocityVerlet, 0.0, vec![body], position, velocity);
+
+        let closest = prediction.closest_approach.unwrap();
+        assert_eq!(prediction.reference, Some(body.entity));
+        assert!((closest.distance - 100.0).abs() < 1e-6, "{closest:?}");
+        assert!((closest.time - 105.0).abs() < 1e-6, "{closest:?}");
+        assert!(prediction.impact.is_none());
+    }
+
+    #[test]
+    fn falling_trajectory_ends_at_the_impact() {
+        let body = body_at_origin(1.0, Some(100.0));
+        let mut prediction = TrajectoryPrediction { horizon: 200.0, step: 30.0, ..default() };
+        let settings = PhysicsSettings::default();
+        let position = DVec3::new(-1_050.0, 0.0, 0.0);
+        let velocity = DVec3::new(10.0, 0.0, 0.0);
+        predict_trajectory(&mut prediction, &settings, Integrator::VelocityVerlet, 0.0, vec![body], position, velocity);
+
+        let impact = prediction.impact.unwrap();
+        assert!((impact.time - 95.0).abs() < 1e-6, "{impact:?}");
+        assert!((impact.position - DVec3::new(-100.0, 0.0, 0.0)).length() < 1e-6, "{impact:?}");
+        assert_eq!(*prediction.points.last().unwrap(), impact.position);
+    }
+
+    #[test]
+    fn predicted_circular_orbit_closes_on_itself() {
+        let earth = body_at_origin(EARTH_MASS, Some(EARTH_RADIUS));
+        let radius = 7_000_000.0;
+        let speed = (G * EARTH_MASS / radius).sqrt();
+        let period = std::f64::consts::TAU * radius / speed;
+        let step = period / 600.0;
+        let mut prediction = TrajectoryPrediction { horizon: period - step / 2.0, step, ..default() };
+        let settings = PhysicsSettings::default();
+        let position = DVec3::new(radius, 0.0, 0.0);
+        let velocity = DVec3::new(0.0, 0.0, -speed);
+        predict_trajectory(&mut prediction, &settings, Integrator::Yoshida4, 0.0, vec![earth], position, velocity);
+
+        assert_eq!(prediction.points.len(), 601);
+        assert!(prediction.impact.is_none());
+        let closest = prediction.closest_approach.unwrap();
+        assert!((closest.distance - radius).abs() < 1e-4 * radius, "{closest:?}");
+        let gap = prediction.points[0].distance(*prediction.points.last().unwrap());
+        assert!(gap < 1e-4 * radius, "the orbit missed its start by {gap} m");
+    }
+}
@@ -0,0 +1,93 @@
+use bevy::prelude::*;
+
+use super::mass::MassPart;
+
+pub const STANDARD_GRAVITY: f64 = 9.80665;
+
+// Propellant in kilograms. Insert it with a `MassPart` on a child of a ship, that part weighs
+// `dry_mass` plus the `contents` and every thruster of the ship burns from all its tanks.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct PropellantTank {
+    pub capacity: f64,
+    pub contents: f64,
+    pub dry_mass: f64,
+}
+
+impl PropellantTank {
+    pub fn new(capacity: f64, dry_mass: f64) -> Self {
+        PropellantTank { capacity, contents: capacity, dry_mass }
+    }
+
+    pub fn with_contents(mut self, contents: f64) -> Self {
+        self.contents = contents.clamp(0.0, self.capacity);
+        self
+    }
+
+    pub fn mass(&self) -> f64 {
+        self.dry_mass + self.contents
+    }
+
+    // Tanks of a ship are drained by the same fraction of what they hold, so they run dry together.
+    pub fn drain(&mut self, fraction: f64) {
+        self.contents = (self.contents * (1.0 - fraction.clamp(0.0, 1.0))).max(0.0);
+    }
+}
+
+// Fuel state of a ship, filled in by `apply_thrusters`. The delta-v is what the strongest
+// thruster could still give to the current mass.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct PropellantState {
+    pub propellant: f64,
+    pub capacity: f64,
+    pub mass_flow: f64,  // kg/s
+    pub delta_v: f64,
+    pub flameout: bool,
+}
+
+// Propellant burnt per second by a thruster giving `force` newtons.
+pub fn mass_flow(force: f64, specific_impulse: f64) -> f64 {
+    if specific_impulse > 0.0 { force / (specific_impulse * STANDARD_GRAVITY) } else { 0.0 }
+}
+
+// Tsiolkovsky's rocket equation.
+pub fn delta_v(specific_impulse: f64, wet_mass: f64, dry_mass: f64) -> f64 {
+    if dry_mass <= 0.0 || wet_mass <= dry_mass {
+        return 0.0;
+    }
+    specific_impulse * STANDARD_GRAVITY * (wet_mass / dry_mass).ln()
+}
+
+pub(super) fn update_propellant_masses(mut tank_query: Query<(&PropellantTank, &mut MassPart), Changed<PropellantTank>>) {
+    for (tank, mut part) in tank_query.iter_mut() {
+        part.mass = tank.mass();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rocket_equation() {
+        // an exhaust velocity of one kilometre per second and a mass ratio of e
+        let specific_impulse = 1000.0 / STANDARD_GRAVITY;
+        assert!((delta_v(specific_impulse, std::f64::consts::E * 100.0, 100.0) - 1000.0).abs() < 1e-9);
+        assert_eq!(delta_v(300.0, 100.0, 100.0), 0.0);
+        assert!((mass_flow(1000.0, specific_impulse) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn tanks_drain_together() {
+        let mut tanks = [PropellantTank::new(100.0, 10.0), PropellantTank::new(300.0, 20.0).with_contents(500.0)];
+        assert_eq!(tanks[1].contents, 300.0);
+        for tank in tanks.iter_mut() {
+            tank.drain(0.25);
+        }
+        assert_eq!(tanks[0].mass(), 85.0);
+        assert_eq!(tanks[1].mass(), 245.0);
+        for tank in tanks.iter_mut() {
+            tank.drain(2.0);
+        }
+        assert!(tanks.iter().all(|tank| tank.contents == 0.0));
+    }
+}
@@ -0,0 +1,244 @@
+use bevy::{
+    math::DVec3,
+    prelude::*,
+};
+
+use big_space::{
+    precision::GridPrecision,
+    reference_frame::local_origin::ReferenceFrames,
+    world_query::GridTransformReadOnly,
+};
+
+use super::forces::ExternalForces;
+use super::mass::MassProperties;
+use super::physics::{BodyRadius, GravityPoint};
+
+pub const SPEED_OF_LIGHT: f64 = 299_792_458.0;
+pub const SUN_LUMINOSITY: f64 = 3.828e26;
+
+// Radiated power in watts of a luminous gravity point. Its `BodyRadius` sets the size of the
+// disk seen from an object, other gravity points with a radius cast shadows.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Luminosity(pub f64);
+
+// Surface of an object lit by a `Luminosity`, with the whole `area` facing the light.
+// `reflectivity` goes from 0 for a black body to 1 for a mirror, which is pushed twice as hard.
+// `center_of_pressure` is in the axes of the object.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct RadiationProfile {
+    pub area: f64,
+    pub reflectivity: f64,
+    pub center_of_pressure: DVec3,
+}
+
+impl RadiationProfile {
+    pub fn new(area: f64, reflectivity: f64) -> Self {
+        RadiationProfile { area, reflectivity, center_of_pressure: DVec3::ZERO }
+    }
+
+    pub fn with_center_of_pressure(mut self, center_of_pressure: DVec3) -> Self {
+        self.center_of_pressure = center_of_pressure;
+        self
+    }
+
+    // Force at `offset` from the light source lit with the given fraction of its disk.
+    pub fn force(&self, luminosity: f64, offset: DVec3, illumination: f64) -> DVec3 {
+        let distance_squared = offset.length_squared();
+        if distance_squared == 0.0 {
+            return DVec3::ZERO;
+        }
+        let pressure = luminosity / (4.0 * std::f64::consts::PI * SPEED_OF_LIGHT * distance_squared);
+        pressure * illumination * self.area * (1.0 + self.reflectivity) * offset.normalize()
+    }
+}
+
+// Visible fraction of a light source disk of `light_radius`, partly covered by a body. Both are
+// seen from the origin, the umbra gives 0 and the penumbra a partial eclipse.
+pub fn illumination(light: DVec3, light_radius: f64, occluder: DVec3, occluder_radius: f64) -> f64 {
+    let light_distance = light.length();
+    let occluder_distance = occluder.length();
+    if occluder_radius <= 0.0 || occluder_distance == 0.0 || occluder_distance >= light_distance {
+        return 1.0;
+    }
+    if occluder_distance <= occluder_radius {
+        return 0.0;
+    }
+
+    let light_angle = (light_radius / light_distance).min(1.0).asin();
+    let occluder_angle = (occluder_radius / occluder_distance).asin();
+    let separation = light.angle_between(occluder);
+    if separation >= light_angle + occluder_angle {
+        return 1.0;
+    }
+    if light_angle == 0.0 || separation <= occluder_angle - light_angle {
+        return 0.0;
+    }
+    if separation <= light_angle - occluder_angle {
+        return 1.0 - (occluder_angle / light_angle).powi(2);
+    }
+
+    // lens shaped overlap of the two disks, small angles are treated as flat
+    let (r1, r2, d) = (light_angle, occluder_angle, separation);
+    let a1 = ((d * d + r1 * r1 - r2 * r2) / (2.0 * d * r1)).clamp(-1.0, 1.0).acos();
+    let a2 = ((d * d + r2 * r2 - r1 * r1) / (2.0 * d * r2)).clamp(-1.0, 1.0).acos();
+    let overlap = r1 * r1 * a1 + r2 * r2 * a2
+        - 0.5 * ((-d + r1 + r2) * (d + r1 - r2) * (d - r1 + r2) * (d + r1 + r2)).max(0.0).sqrt();
+    (1.0 - overlap / (std::f64::consts::PI * r1 * r1)).clamp(0.0, 1.0)
+}
+
+struct LightSource {
+    entity: Entity,
+    position: DVec3,
+    radius: f64,
+    luminosity: f64,
+}
+
+struct Occluder {
+    entity: Entity,
+    position: DVec3,
+    radius: f64,
+}
+
+fn apply_radiation_pressure(
+    lights: &[LightSource],
+    occluders: &[Occluder],
+    position: DVec3,
+    rotation: Quat,
+    profile: &RadiationProfile,
+    forces: &mut ExternalForces,
+    mass_properties: Option<&MassProperties>,
+) {
+    let center_of_mass = mass_properties.map_or(DVec3::ZERO, |mass_properties| mass_properties.center_of_mass);
+    let point = rotation.as_dquat() * (profile.center_of_pressure - center_of_mass);
+    for light in lights {
+        let illumination = occluders
+            .iter()
+            .filter(|occluder| occluder.entity != light.entity)
+            .map(|occluder| illumination(light.position - position, light.radius, occluder.position - position, occluder.radius))
+            .fold(1.0, f64::min);
+        if illumination > 0.0 {
+            forces.apply_force_at_point(profile.force(light.luminosity, position - light.position, illumination), point);
+        }
+    }
+}
+
+pub(super) fn radiation_pressure(
+    light_query: Query<(Entity, &Transform, &Luminosity, Option<&BodyRadius>)>,
+    occluder_query: Query<(Entity, &Transform, &BodyRadius), With<GravityPoint>>,
+    mut object_query: Query<(&Transform, &RadiationProfile, &mut ExternalForces, Option<&MassProperties>)>,
+) {
+    let lights: Vec<LightSource> = light_query
+        .iter()
+        .map(|(entity, transform, luminosity, radius)| LightSource {
+            entity,
+            position: transform.translation.as_dvec3(),
+            radius: radius.map_or(0.0, |radius| radius.0),
+            luminosity: luminosity.0,
+        })
+        .collect();
+    if lights.is_empty() {
+        return;
+    }
+    let occluders: Vec<Occluder> = occluder_query
+        .iter()
+        .map(|(entity, transform, radius)| Occluder { entity, position: transform.translation.as_dvec3(), radius: radius.0 })
+        .collect();
+
+    for (transform, profile, mut forces, mass_properties) in object_query.iter_mut() {
+        apply_radiation_pressure(&lights, &occluders, transform.translation.as_dvec3(), transform.rotation, profile, &mut forces, mass_properties);
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(super) fn radiation_pressure_big_space<P: GridPrecision>(
+    frames: ReferenceFrames<P>,
+    light_query: Query<(Entity, GridTransformReadOnly<P>, &Luminosity, Option<&BodyRadius>)>,
+    occluder_query: Query<(Entity, GridTransformReadOnly<P>, &BodyRadius), With<GravityPoint>>,
+    mut object_query: Query<(Entity, GridTransformReadOnly<P>, &RadiationProfile, &mut ExternalForces, Option<&MassProperties>)>,
+) {
+    let lights: Vec<LightSource> = light_query
+        .iter()
+        .filter_map(|(entity, grid_transform, luminosity, radius)| {
+            let reference_frame = frames.parent_frame(entity)?;
+            Some(LightSource {
+                entity,
+                position: grid_transform.position_double(reference_frame),
+                radius: radius.map_or(0.0, |radius| radius.0),
+                luminosity: luminosity.0,
+            })
+        })
+        .collect();
+    if lights.is_empty() {
+        return;
+    }
+    let occluders: Vec<Occluder> = occluder_query
+        .iter()
+        .filter_map(|(entity, grid_transform, radius)| {
+            let reference_frame = frames.parent_frame(entity)?;
+            Some(Occluder { entity, position: grid_transform.position_double(reference_frame), radius: radius.0 })
+        })
+        .collect();
+
+    for (entity, grid_transform, profile, mut forces, mass_properties) in object_query.iter_mut() {
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        apply_radiation_pressure(
+            &lights,
+            &occluders,
+            grid_transform.position_double(reference_frame),
+            grid_transform.transform.rotation,
+            profile,
+            &mut forces,
+            mass_properties,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AU: f64 = 149_597_871_000.0;
+
+    #[test]
+    fn pressure_at_one_astronomical_unit() {
+        let profile = RadiationProfile::new(1.0, 0.0);
+        let force = profile.force(SUN_LUMINOSITY, DVec3::new(AU, 0.0, 0.0), 1.0);
+        // the solar constant is about 1361 W/m2
+        assert!((force.x - 1361.0 / SPEED_OF_LIGHT).abs() < 1e-8);
+        let mirror = RadiationProfile::new(1.0, 1.0).force(SUN_LUMINOSITY, DVec3::new(AU, 0.0, 0.0), 1.0);
+        assert!((mirror.x - 2.0 * force.x).abs() < 1e-15);
+        assert_eq!(force * 4.0, profile.force(SUN_LUMINOSITY, DVec3::new(AU / 2.0, 0.0, 0.0), 1.0));
+    }
+
+    #[test]
+    fn planet_shadow_has_umbra_and_penumbra() {
+        let sun = DVec3::new(AU, 0.0, 0.0);
+        let sun_radius = 696_340_000.0;
+        let earth_radius = 6_371_000.0;
+        // low orbit behind the Earth
+        assert_eq!(illumination(sun, sun_radius, DVec3::new(7_000_000.0, 0.0, 0.0), earth_radius), 0.0);
+        // beside the Earth
+        assert_eq!(illumination(sun, sun_radius, DVec3::new(0.0, 7_000_000.0, 0.0), earth_radius), 1.0);
+        // Earth on the far side of the light
+        assert_eq!(illumination(sun, sun_radius, DVec3::new(-7_000_000.0, 0.0, 0.0), earth_radius), 1.0);
+        // grazing the edge of the shadow
+        let edge = DVec3::new(100_000_000.0, earth_radius * 0.99, 0.0);
+        let partial = illumination(sun, sun_radius, edge, earth_radius);
+        assert!(partial > 0.0 && partial < 1.0);
+    }
+
+    #[test]
+    fn sail_off_the_centre_of_mass_is_turned_by_the_light() {
+        let light = LightSource { entity: Entity::PLACEHOLDER, position: DVec3::new(-AU, 0.0, 0.0), radius: 0.0, luminosity: SUN_LUMINOSITY };
+        let profile = RadiationProfile::new(100.0, 1.0).with_center_of_pressure(DVec3::new(0.0, 3.0, 0.0));
+        let mut forces = ExternalForces::default();
+        apply_radiation_pressure(&[light], &[], DVec3::ZERO, Quat::IDENTITY, &profile, &mut forces, None);
+
+        let force = profile.force(SUN_LUMINOSITY, DVec3::new(AU, 0.0, 0.0), 1.0);
+        assert_eq!(forces.force, force);
+        // pushed along +X above the centre of mass, the sail tips around -Z
+        assert!((forces.torque - DVec3::new(0.0, 0.0, -3.0 * force.x)).length() < 1e-15);
+    }
+}
@@ -1,7 +1,12 @@
 use bevy::prelude::*;
 
 use super::player::{Player, SpaceShip, SpaceShipCameraTarget, SpaceShipSettings};
-use super::physics::SpaceObject;
+use super::physics::{SpaceObject, TidalAcceleration};
+use super::orbit::OsculatingOrbit;
+use super::time_warp::{TimeWarp, WarpRefusal};
+use super::atmosphere::AerodynamicState;
+use super::body_frame::SurfaceRelativeState;
+use super::propellant::PropellantState;
 
 pub struct DataDysplayPlugin;
 
@@ -57,21 +62,23 @@ pub fn setup_text(mut commands: Commands) {
     ));
 }
 
+#[allow(clippy::type_complexity)]
 pub fn update_metrics_text(
     mut text_query: Query<&mut Text, With<MetricsText>>,
-    ship_query: Query<(&SpaceObject, &SpaceShip, &Transform), With<Player>>,
+    ship_query: Query<(&SpaceObject, &SpaceShip, &Transform, Option<&OsculatingOrbit>, Option<&AerodynamicState>, Option<&TidalAcceleration>, Option<&SurfaceRelativeState>, Option<&PropellantState>), With<Player>>,
+    name_query: Query<&Name>,
 ) {
     let Ok(mut text) = text_query.get_single_mut() else { return };
-    let Ok((object, ship, transform)) = ship_query.get_single() else { return };
+    let Ok((object, ship, transform, orbit, aerodynamics, tidal, surface, propellant)) = ship_query.get_single() else { return };
 
     const EARTH_G: f32 = 9.81;
 
     let velocity = object.velocity.length();
     let angular_velocity = object.angular_velocity.length().to_degrees();
 
-    let linear_overload = object.acceleration + object.gravitational_force / object.mass;
+    let linear_overload = (object.acceleration + object.gravitational_force / object.mass).as_vec3();
 
-    let centripetal_velocity = object.angular_velocity.cross(ship.pilot_position);
+    let centripetal_velocity = object.local_angular_velocity(transform.rotation).cross(ship.pilot_position);
     let direction_to_center = transform.rotation * -ship.pilot_position.normalize_or_zero();
     let centripetal_acceleration = centripetal_velocity.length().powi(2) / ship.pilot_position.length();
     let angular_overload = direction_to_center * centripetal_acceleration;
@@ -79,12 +86,68 @@ pub fn update_metrics_text(
     let overload = (linear_overload + angular_overload).length() / EARTH_G;
 
     text.sections[0].value = format!("Overload: {overload:.2} G\nVelocity: {velocity:.2} m/s\nAngular velocity: {angular_velocity:.2} deg/s");
+
+    if let Some(PropellantState { propellant, capacity, delta_v, flameout, .. }) = propellant {
+        let mass = object.mass;
+        text.sections[0].value += &format!("\n\nMass: {mass:.1} kg\nPropellant: {propellant:.1} / {capacity:.0} kg\nDelta-v: {delta_v:.1} m/s");
+        if *flameout {
+            text.sections[0].value += "\nFLAMEOUT";
+        }
+    }
+
+    if let Some(OsculatingOrbit { body: Some(body), elements }) = orbit {
+        let body_name = name_query.get(*body).map(|name| name.as_str()).unwrap_or("?");
+        let apoapsis = elements.apoapsis / 1000.0;
+        let periapsis = elements.periapsis / 1000.0;
+        let eccentricity = elements.eccentricity;
+        let inclination = elements.inclination.to_degrees();
+        let period = format_duration(Some(elements.period));
+        let time_to_apoapsis = format_duration(elements.time_to_apoapsis);
+        let time_to_periapsis = format_duration(elements.time_to_periapsis);
+        text.sections[0].value += &format!(
+            "\n\nOrbiting: {body_name}\nApoapsis: {apoapsis:.1} km (in {time_to_apoapsis})\nPeriapsis: {periapsis:.1} km (in {time_to_periapsis})\nEccentricity: {eccentricity:.4}\nInclination: {inclination:.2} deg\nPeriod: {period}"
+        );
+    }
+
+    if let Some(SurfaceRelativeState { body: Some(_), latitude, longitude, altitude, vertical_speed, ground_speed, .. }) = surface {
+        let latitude = latitude.to_degrees();
+        let longitude = longitude.to_degrees();
+        let altitude = altitude / 1000.0;
+        text.sections[0].value += &format!(
+            "\n\nLatitude: {latitude:.3} deg\nLongitude: {longitude:.3} deg\nAltitude: {altitude:.1} km\nGround speed: {ground_speed:.2} m/s\nVertical speed: {vertical_speed:.2} m/s"
+        );
+    }
+
+    if let Some(AerodynamicState { body: Some(_), density, dynamic_pressure, heat_flux, .. }) = aerodynamics {
+        let dynamic_pressure = dynamic_pressure / 1000.0;
+        let heat_flux = heat_flux / 1000.0;
+        text.sections[0].value += &format!(
+            "\n\nAir density: {density:.2e} kg/m3\nDynamic pressure: {dynamic_pressure:.2} kPa\nHeat flux: {heat_flux:.1} kW/m2"
+        );
+    }
+
+    if let Some(tidal) = tidal {
+        let stretch = tidal.stretch;
+        text.sections[0].value += &format!("\n\nTidal stretch: {stretch:.3e} m/s2");
+    }
+}
+
+fn format_duration(seconds: Option<f64>) -> String {
+    let Some(seconds) = seconds.filter(|seconds| seconds.is_finite()) else { return "-".to_string() };
+    let seconds = seconds as u64;
+    let (days, hours, minutes, seconds) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60, seconds % 60);
+    if days > 0 {
+        format!("{days}d {hours:02}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{hours:02}:{minutes:02}:{seconds:02}")
+    }
 }
 
 pub fn update_settings_text(
     mut text_query: Query<&mut Text, With<SettingsText>>,
     camera_query: Query<&SpaceShipCameraTarget>,
     settings_query: Query<&SpaceShipSettings, With<Player>>,
+    time_warp: Option<Res<TimeWarp>>,
 ) {
     let Ok(mut text) = text_query.get_single_mut() else { return };
     let Ok(camera) = camera_query.get_single() else { return };
@@ -95,4 +158,15 @@ pub fn update_settings_text(
     let movement_stabilization_mode = &settings.movement_stabilization;
 
     text.sections[0].value = format!("Camera: {camera_mode:?} | Rotation stabilization: {rotation_stabilization_mode:?} | Movement Stabilization: {movement_stabilization_mode:?}");
+
+    if let Some(time_warp) = time_warp {
+        let rate = time_warp.rate();
+        text.sections[0].value += &format!(" | Time warp: {rate}x");
+        match time_warp.refusal {
+            Some(WarpRefusal::Thrusting(_)) => text.sections[0].value += " (not while thrusting)",
+            Some(WarpRefusal::InAtmosphere(_)) => text.sections[0].value += " (not in atmosphere)",
+            Some(WarpRefusal::PoweredNearBody(_)) => text.sections[0].value += " (ships under power nearby)",
+            None => {}
+        }
+    }
 }
@@ -0,0 +1,337 @@
+use bevy::{
+    math::DVec3,
+    prelude::*,
+};
+
+use big_space::{
+    precision::GridPrecision,
+    reference_frame::local_origin::ReferenceFrames,
+    world_query::GridTransformReadOnly,
+};
+
+use super::atmosphere::AerodynamicState;
+use super::landing::Landed;
+use super::orbit::KeplerOrbit;
+use super::physics::{GravityPoint, GravitySources, PhysicsTime, SpaceObject, G};
+
+// Set by whatever powers an object, `apply_thrusters` for ships. Time warp is refused while a
+// `WarpFocus` object is under power, others keep their physics steps while the rest coasts on rails.
+// Near a planet or a moon their steps would be long enough to jump through the ground or the air,
+// so there they hold time warp at the fastest physics warp.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct Thrusting(pub bool);
+
+// The object time warp is for, usually the ship under control. Only its thrust and its flight
+// through an atmosphere refuse warp, so that AI ships burning all the time or craft parked in the
+// air of a planet do not hold it back.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct WarpFocus;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WarpRefusal {
+    Thrusting(Entity),
+    InAtmosphere(Entity),
+    PoweredNearBody(Entity),
+}
+
+// Speeds up the physics clock by one of `levels`. Up to `max_physics_warp` the fixed step is cut
+// into proportionally more substeps, so the simulation stays as accurate as at 1x. Above it the
+// steps get longer and coasting objects are put on Kepler orbits around the body of their
+// sphere of influence. Requests are dropped back to 1x while the `WarpFocus` thrusts or flies
+// through an atmosphere, and to physics warp while other objects are under power near a planet
+// or a moon, `refusal` tells why.
+#[derive(Resource, Clone, Debug)]
+pub struct TimeWarp {
+    pub levels: Vec<f64>,
+    pub max_physics_warp: f64,
+    pub refusal: Option<WarpRefusal>,
+    level: usize,
+}
+
+impl Default for TimeWarp {
+    fn default() -> Self {
+        TimeWarp {
+            levels: vec![1.0, 2.0, 4.0, 10.0, 100.0, 1_000.0, 10_000.0, 100_000.0],
+            max_physics_warp: 4.0,
+            refusal: None,
+            level: 0,
+        }
+    }
+}
+
+impl TimeWarp {
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    pub fn set_level(&mut self, level: usize) {
+        self.level = level.min(self.levels.len().saturating_sub(1));
+    }
+
+    pub fn faster(&mut self) {
+        self.set_level(self.level + 1);
+    }
+
+    pub fn slower(&mut self) {
+        self.set_level(self.level.saturating_sub(1));
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.levels.get(self.level).copied().unwrap_or(1.0)
+    }
+
+    // Part of the warp done by running more substeps.
+    pub fn physics_rate(&self) -> f64 {
+        self.rate().min(self.max_physics_warp).max(1.0)
+    }
+
+    pub fn on_rails(&self) -> bool {
+        self.rate() > self.max_physics_warp
+    }
+
+    fn hold_at_physics_warp(&mut self, refusal: WarpRefusal) {
+        self.level = self.levels.iter().rposition(|&rate| rate <= self.max_physics_warp).unwrap_or(0);
+        self.refusal = Some(refusal);
+    }
+}
+
+// Kepler orbit given to a coasting object by time warp, taken back when it drops to physics warp.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct WarpRails;
+
+#[allow(clippy::type_complexity)]
+pub(super) fn update_time_warp(
+    mut time_warp: ResMut<TimeWarp>,
+    focus_query: Query<(Entity, Option<&Thrusting>, Option<&AerodynamicState>), With<WarpFocus>>,
+) {
+    let refusal = focus_query.iter().find_map(|(entity, thrusting, aerodynamics)| {
+        if thrusting.is_some_and(|thrusting| thrusting.0) {
+            Some(WarpRefusal::Thrusting(entity))
+        } else if aerodynamics.is_some_and(|aerodynamics| aerodynamics.body.is_some()) {
+            Some(WarpRefusal::InAtmosphere(entity))
+        } else {
+            None
+        }
+    });
+    match refusal {
+        Some(_) if time_warp.level > 0 => {
+            time_warp.level = 0;
+            time_warp.refusal = refusal;
+        }
+        None => time_warp.refusal = None,
+        Some(_) => {}
+    }
+}
+
+// Inside the sphere of influence of a planet or a moon, not only the one of the star.
+fn near_body(gravity_sources: &GravitySources, position: DVec3) -> bool {
+    gravity_sources.soi_source(position).is_some_and(|source| source.parent.is_some())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_warp_orbit(
+    commands: &mut Commands,
+    gravity_sources: &GravitySources,
+    time: f64,
+    entity: Entity,
+    object: &SpaceObject,
+    position: DVec3,
+    orbit: Option<&KeplerOrbit>,
+    warp_rails: bool,
+) {
+    let Some(source) = gravity_sources.soi_source(position) else {
+        return;
+    };
+    if warp_rails && orbit.is_some_and(|orbit| orbit.parent == source.entity) {
+        return;
+    }
+    let mu = G * (source.mass + object.mass);
+    match KeplerOrbit::from_state(source.entity, position - source.position, object.velocity - source.velocity, mu, time) {
+        Some(orbit) => {
+            commands.entity(entity).insert((orbit, WarpRails));
+        }
+        None if warp_rails => {
+            commands.entity(entity).remove::<(KeplerOrbit, WarpRails)>();
+        }
+        None => {}
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(super) fn update_warp_rails(
+    mut commands: Commands,
+    mut time_warp: ResMut<TimeWarp>,
+    time: Res<PhysicsTime>,
+    mut gravity_sources: ResMut<GravitySources>,
+    object_query: Query<(Entity, &SpaceObject, &Transform, Option<&KeplerOrbit>, Has<WarpRails>, Has<Landed>, Option<&Thrusting>), Without<GravityPoint>>,
+) {
+    if time_warp.on_rails() {
+        gravity_sources.update_spheres_of_influence();
+        let powered_near_body = object_query.iter().find(|(_, _, transform, _, _, landed, thrusting)| {
+            !landed && thrusting.is_some_and(|thrusting| thrusting.0) && near_body(&gravity_sources, transform.translation.as_dvec3())
+        });
+        if let Some((entity, ..)) = powered_near_body {
+            time_warp.hold_at_physics_warp(WarpRefusal::PoweredNearBody(entity));
+        }
+    }
+
+    for (entity, object, transform, orbit, warp_rails, landed, thrusting) in object_query.iter() {
+        let coasting = !landed && !thrusting.is_some_and(|thrusting| thrusting.0);
+        if !time_warp.on_rails() || !coasting {
+            if warp_rails {
+                commands.entity(entity).remove::<(KeplerOrbit, WarpRails)>();
+            }
+            continue;
+        }
+        // on rails of its own
+        if orbit.is_some() && !warp_rails {
+            continue;
+        }
+        update_warp_orbit(&mut commands, &gravity_sources, time.elapsed_seconds_f64(), entity, object, transform.translation.as_dvec3(), orbit, warp_rails);
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(super) fn update_warp_rails_big_space<P: GridPrecision>(
+    mut commands: Commands,
+    mut time_warp: ResMut<TimeWarp>,
+    time: Res<PhysicsTime>,
+    mut gravity_sources: ResMut<GravitySources>,
+    frames: ReferenceFrames<P>,
+    object_query: Query<(Entity, &SpaceObject, GridTransformReadOnly<P>, Option<&KeplerOrbit>, Has<WarpRails>, Has<Landed>, Option<&Thrusting>), Without<GravityPoint>>,
+) {
+    if time_warp.on_rails() {
+        gravity_sources.update_spheres_of_influence();
+        let powered_near_body = object_query.iter().find(|(entity, _, grid_transform, _, _, landed, thrusting)| {
+            let Some(reference_frame) = frames.parent_frame(*entity) else {
+                return false;
+            };
+            !landed && thrusting.is_some_and(|thrusting| thrusting.0) && near_body(&gravity_sources, grid_transform.position_double(reference_frame))
+        });
+        if let Some((entity, ..)) = powered_near_body {
+            time_warp.hold_at_physics_warp(WarpRefusal::PoweredNearBody(entity));
+        }
+    }
+
+    for (entity, object, grid_transform, orbit, warp_rails, landed, thrusting) in object_query.iter() {
+        let coasting = !landed && !thrusting.is_some_and(|thrusting| thrusting.0);
+        if !time_warp.on_rails() || !coasting {
+            if warp_rails {
+                commands.entity(entity).remove::<(KeplerOrbit, WarpRails)>();
+            }
+            continue;
+        }
+        // on rails of its own
+        if orbit.is_some() && !warp_rails {
+            continue;
+        }
+        let Some(reference_frame) = frames.parent_frame(entity) else {
+            continue;
+        };
+        let position = grid_transform.position_double(reference_frame);
+        update_warp_orbit(&mut commands, &gravity_sources, time.elapsed_seconds_f64(), entity, object, position, orbit, warp_rails);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::physics::SpacePhysicsPlugin;
+
+    #[test]
+    fn levels_split_into_physics_and_rails_warp() {
+        let mut time_warp = TimeWarp::default();
+        assert_eq!(time_warp.rate(), 1.0);
+        time_warp.faster();
+        time_warp.faster();
+        assert_eq!(time_warp.rate(), 4.0);
+        assert!(!time_warp.on_rails());
+        time_warp.set_level(100);
+        assert_eq!(time_warp.rate(), 100_000.0);
+        assert_eq!(time_warp.physics_rate(), 4.0);
+        assert!(time_warp.on_rails());
+        time_warp.slower();
+        assert_eq!(time_warp.rate(), 10_000.0);
+    }
+
+    fn warp_level_after_update(focus: impl Bundle, other: impl Bundle) -> (usize, Option<WarpRefusal>) {
+        let mut app = App::new();
+        let mut time_warp = TimeWarp::default();
+        time_warp.set_level(5);
+        app.insert_resource(time_warp).add_systems(Update, update_time_warp);
+        app.world_mut().spawn((focus, WarpFocus));
+        app.world_mut().spawn(other);
+        app.update();
+        let time_warp = app.world().resource::<TimeWarp>();
+        (time_warp.level(), time_warp.refusal)
+    }
+
+    fn in_atmosphere() -> AerodynamicState {
+        AerodynamicState { body: Some(Entity::PLACEHOLDER), ..default() }
+    }
+
+    #[test]
+    fn thrust_of_other_ships_does_not_refuse_warp() {
+        assert_eq!(warp_level_after_update(Thrusting(false), Thrusting(true)), (5, None));
+    }
+
+    #[test]
+    fn thrust_of_the_focus_refuses_warp() {
+        let (level, refusal) = warp_level_after_update(Thrusting(true), Thrusting(false));
+        assert_eq!(level, 0);
+        assert!(matches!(refusal, Some(WarpRefusal::Thrusting(_))));
+    }
+
+    #[test]
+    fn atmosphere_around_other_objects_does_not_refuse_warp() {
+        assert_eq!(warp_level_after_update(AerodynamicState::default(), in_atmosphere()), (5, None));
+    }
+
+    #[test]
+    fn atmosphere_around_the_focus_refuses_warp() {
+        let (level, refusal) = warp_level_after_update(in_atmosphere(), AerodynamicState::default());
+        assert_eq!(level, 0);
+        assert!(matches!(refusal, Some(WarpRefusal::InAtmosphere(_))));
+    }
+
+    // Asks for 100000x with a ship under power in low orbit around the Earth, or far from it.
+    // Returns the time warp, the substep length and whether the ship was put on rails.
+    fn rails_warp_with_ship_under_power(near_earth: bool) -> (TimeWarp, Entity, f64, bool) {
+        const AU: f64 = 149_597_871_000.0;
+        let mut app = App::new();
+        app.add_plugins(SpacePhysicsPlugin::default());
+        app.world_mut().spawn((SpaceObject::new(1.989e30), GravityPoint, Transform::default()));
+        app.world_mut().spawn((SpaceObject::new(5.972e24), GravityPoint, Transform::from_xyz(AU as f32, 0.0, 0.0)));
+        let position = if near_earth { DVec3::new(AU, 7.0e6, 0.0) } else { DVec3::new(0.0, AU, 0.0) };
+        let ship = app.world_mut().spawn((SpaceObject::new(1000.0), Thrusting(true), Transform::from_translation(position.as_vec3()))).id();
+
+        app.world_mut().resource_mut::<Time<Fixed>>().advance_by(Duration::from_secs_f64(1.0 / 64.0));
+        app.world_mut().run_schedule(FixedUpdate);
+        app.world_mut().resource_mut::<TimeWarp>().set_level(7);
+        app.world_mut().run_schedule(FixedUpdate);
+
+        let time_warp = app.world().resource::<TimeWarp>().clone();
+        (time_warp, ship, app.world().resource::<PhysicsTime>().delta_seconds_f64(), app.world().get::<WarpRails>(ship).is_some())
+    }
+
+    #[test]
+    fn ship_under_power_near_a_planet_keeps_its_physics_steps() {
+        let (time_warp, ship, substep, on_rails) = rails_warp_with_ship_under_power(true);
+        assert_eq!(time_warp.rate(), 4.0);
+        assert_eq!(time_warp.refusal, Some(WarpRefusal::PoweredNearBody(ship)));
+        // as long as the substeps at 1x
+        assert_eq!(substep, 1.0 / 64.0 / 4.0);
+        assert!(!on_rails);
+    }
+
+    #[test]
+    fn ship_under_power_far_from_planets_does_not_hold_warp() {
+        let (time_warp, _, substep, on_rails) = rails_warp_with_ship_under_power(false);
+        assert_eq!(time_warp.rate(), 100_000.0);
+        assert_eq!(time_warp.refusal, None);
+        assert_eq!(substep, 100_000.0 / 64.0 / 16.0);
+        assert!(!on_rails);
+    }
+}
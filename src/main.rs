@@ -1,19 +1,26 @@
 use bevy::{
-    color::palettes::css::{GREEN, RED, WHITE, YELLOW}, core_pipeline::bloom::BloomSettings, math::DVec3, pbr::NotShadowCaster, prelude::*, render::camera::Exposure
+    color::palettes::css::{GREEN, RED, WHITE, YELLOW}, core_pipeline::bloom::BloomSettings, math::{DQuat, DVec3}, pbr::NotShadowCaster, prelude::*
 };
 use bevy_math::Dir3;
+use std::f64::consts::FRAC_PI_2;
 use big_space::{
     commands::BigSpaceCommands,
     reference_frame::ReferenceFrame,
     FloatingOrigin,
 };
 use bevy_hanabi::prelude::*;
-use bevy_editor_pls::prelude::*;
 
-mod bevy_space_physics;
-use bevy_space_physics::player::{spawn_ship, AIPlayer, CameraPlugin, CameraSet, Player, SpaceShip, SpaceShipCameraTarget, SpaceShipPlugin, SpaceShipSettings};
-use bevy_space_physics::physics::{SpacePhysicsPluginBigSpace, SpaceObject, GravityPoint};
+use bevy_space_physics::atmosphere::Atmosphere;
+use bevy_space_physics::blueprint::ShipBlueprintHandle;
+use bevy_space_physics::diagnostics::ConservationDiagnosticsPluginBigSpace;
+use bevy_space_physics::body_frame::{BodyRotation, GroundStation, SurfaceRelativeState};
+use bevy_space_physics::player::{AIPlayer, CameraPlugin, CameraSet, Player, SpaceShip, SpaceShipCameraTarget, SpaceShipPlugin, SpaceShipSettings};
+use bevy_space_physics::orbit::{CurrentSoi, KeplerOrbit, OsculatingOrbit};
+use bevy_space_physics::physics::{BodyRadius, GravityPoint, Oblateness, PhysicsSettings, SpaceObject, SpacePhysicsPluginBigSpace, TidalAcceleration, G};
+use bevy_space_physics::radiation::{Luminosity, SUN_LUMINOSITY};
+use bevy_space_physics::prediction::{TrajectoryPrediction, TrajectoryPredictionPluginBigSpace};
 use bevy_space_physics::text::DataDysplayPlugin;
+use bevy_space_physics::time_warp::WarpFocus;
 
 mod setup_effect;
 
@@ -28,7 +35,14 @@ fn main() {
         .insert_resource(ClearColor(Color::BLACK))
         .add_plugins(HanabiPlugin)
         .add_systems(Startup, setup)
-        .add_plugins((SpacePhysicsPluginBigSpace::<i64>::default(), SpaceShipPlugin, DataDysplayPlugin, CameraPlugin))
+        .add_plugins((
+            SpacePhysicsPluginBigSpace::<i64>::new(PhysicsSettings { n_body: true, ..default() }),
+            TrajectoryPredictionPluginBigSpace::<i64>::default(),
+            ConservationDiagnosticsPluginBigSpace::<i64>::new(true),
+            SpaceShipPlugin,
+            DataDysplayPlugin,
+            CameraPlugin,
+        ))
         .add_systems(Update, update_gizmos.after(CameraSet))
         .run();
 }
@@ -37,10 +51,9 @@ fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut effects: ResMut<Assets<EffectAsset>>,
     asset_server: Res<AssetServer>,
-    mut config_store: ResMut<GizmoConfigStore>,
 ) {
+    let ship_blueprint = asset_server.load("ships/default.ship.ron");
 
     // commands.spawn((
     //     PbrBundle {
@@ -67,11 +80,12 @@ fn setup(
         // Sun
 
         // root_frame.with_frame_default(|sun| {
-            let sun_mass: f32 = 1.989e30;  // 1.989 × 10^30 kg
+            let sun_mass: f64 = 1.989e30;  // 1.989 × 10^30 kg
+            let sun_radius: f32 = 696_340_000.0;
             sun.insert(Name::new("Sun"));
-            sun.spawn_spatial((
+            let sun_entity = sun.spawn_spatial((
                 PbrBundle {
-                    mesh: meshes.add(Sphere::new(696_340_000.0)),
+                    mesh: meshes.add(Sphere::new(sun_radius)),
                     material: materials.add(Color::srgb_u8(250, 160, 0)),
                     transform: Transform::from_xyz(0.0, 0.0, 0.0),
                     ..default()
@@ -79,39 +93,55 @@ fn setup(
                 NotShadowCaster,
                 SpaceObject::new(sun_mass),
                 GravityPoint,
-            ));
+                BodyRadius(sun_radius as f64),
+                Luminosity(SUN_LUMINOSITY),
+            )).id();
 
             // Earth
 
             let earth_orbit_radius: f64 = 149_597_871_000.0;
             let earth_radius: f32 = 6_371_000.0;
-            let earth_mass: f32 = 5.972e24;  // 5.972 × 10^24 kg
-            let geostationary_orbit_high: f64 = 35_786_000.0;
+            let earth_mass: f64 = 5.972e24;  // 5.972 × 10^24 kg
+            let earth_rotation = BodyRotation::new(86_164.090_5, DQuat::from_rotation_x(23.44f64.to_radians()) * DVec3::Y);
 
-            let (earth_cell, earth_translation) = sun.frame().translation_to_grid(DVec3::new(0.0, 0.0, earth_orbit_radius));
+            // planets start on the +Z axis and move towards +X
+            let earth_orbit = KeplerOrbit::circular(sun_entity, earth_orbit_radius).with_mean_anomaly_at_epoch(-FRAC_PI_2);
+            let (earth_position, earth_velocity) = earth_orbit.state_at(0.0, G * (sun_mass + earth_mass));
 
+            let (earth_cell, earth_translation) = sun.frame().translation_to_grid(earth_position);
+
+            let mut earth_entity = Entity::PLACEHOLDER;
             sun.with_frame_default(|earth| {
                 earth.insert(Name::new("Earth"));
-                earth.spawn_spatial((
+                earth_entity = earth.spawn_spatial((
                     PbrBundle {
                         mesh: meshes.add(Sphere::new(earth_radius)),
                         material: materials.add(Color::srgb_u8(0, 150, 255)),
-                        transform: Transform::from_translation(earth_translation),
+                        transform: Transform::from_translation(earth_translation).with_rotation(earth_rotation.rotation_at(0.0).as_quat()),
                         ..default()
                     },
-                    SpaceObject::new(earth_mass),
+                    SpaceObject { velocity: earth_velocity, ..SpaceObject::new(earth_mass) },
                     GravityPoint,
+                    BodyRadius(earth_radius as f64),
+                    Atmosphere::new(1.225, 8_500.0, earth_radius as f64 + 140_000.0),
+                    Oblateness { j2: 1.08263e-3, reference_radius: 6_378_137.0 },
+                    earth_rotation,
+                    earth_orbit,
                     earth_cell,
-                ));
+                )).id();
             });
 
             // Mars
 
             let mars_orbit_radius: f64 = 228_000_000_000.0;
-            let mars_radius = 3_389_500.0;
-            let mars_mass = 6.39e23; // 6.39 × 10^23 kg
+            let mars_radius: f32 = 3_389_500.0;
+            let mars_mass: f64 = 6.39e23; // 6.39 × 10^23 kg
+            let mars_rotation = BodyRotation::new(88_642.66, DQuat::from_rotation_x(25.19f64.to_radians()) * DVec3::Y);
 
-            let (mars_cell, mars_translation) = sun.frame().translation_to_grid(DVec3::new(0.0, 0.0, mars_orbit_radius));
+            let mars_orbit = KeplerOrbit::circular(sun_entity, mars_orbit_radius).with_mean_anomaly_at_epoch(-FRAC_PI_2);
+            let (mars_position, mars_velocity) = mars_orbit.state_at(0.0, G * (sun_mass + mars_mass));
+
+            let (mars_cell, mars_translation) = sun.frame().translation_to_grid(mars_position);
 
             sun.with_frame_default(|mars| {
                 mars.insert(Name::new("Mars"));
@@ -119,26 +149,61 @@ fn setup(
                     PbrBundle {
                         mesh: meshes.add(Sphere::new(mars_radius)),
                         material: materials.add(Color::srgb_u8(255, 150, 0)),
-                        transform: Transform::from_translation(mars_translation),
+                        transform: Transform::from_translation(mars_translation).with_rotation(mars_rotation.rotation_at(0.0).as_quat()),
                         ..default()
                     },
-                    SpaceObject::new(mars_mass),
+                    SpaceObject { velocity: mars_velocity, ..SpaceObject::new(mars_mass) },
                     GravityPoint,
+                    BodyRadius(mars_radius as f64),
+                    Atmosphere::new(0.020, 11_100.0, mars_radius as f64 + 120_000.0).with_heating_constant(1.9027e-4),
+                    Oblateness { j2: 1.96045e-3, reference_radius: 3_396_200.0 },
+                    mars_rotation,
+                    mars_orbit,
                     mars_cell,
                 ));
             });
 
+            // a ground station on the equator, with the ships on the geostationary orbit above it
+            let ground_station = GroundStation::new(earth_entity, 0.0, 0.0);
+            let ground_station_landed = ground_station.landed(earth_radius as f64);
+            let ground_station_position = earth_position + earth_rotation.rotation_at(0.0) * ground_station_landed.offset;
+            let (ground_station_cell, ground_station_translation) = sun.frame().translation_to_grid(ground_station_position);
+
+            sun.spawn_spatial((
+                Name::new("Ground station"),
+                PbrBundle {
+                    mesh: meshes.add(Cuboid::new(50.0, 50.0, 50.0)),
+                    material: materials.add(Color::srgb_u8(200, 200, 200)),
+                    transform: Transform::from_translation(ground_station_translation),
+                    ..default()
+                },
+                SpaceObject::new(100_000.0),
+                ground_station,
+                ground_station_landed,
+                ground_station_cell,
+            ));
+
+            let (ship_offset, ship_relative_velocity) = earth_rotation.stationary_state(earth_mass, ground_station.longitude, 0.0);
+            let ship_position = earth_position + ship_offset;
+            let ship_velocity = earth_velocity + ship_relative_velocity;
 
-            let (player_cell, player_translation) = sun.frame().translation_to_grid(DVec3::new(geostationary_orbit_high, 0.0, earth_orbit_radius));
+            let (player_cell, player_translation) = sun.frame().translation_to_grid(ship_position);
 
             sun.with_frame_default(|ship| {
                 ship.insert((
                     Name::new("Player"),
                     player_cell,
                     Transform::from_translation(player_translation),
-                    SpaceObject::new(1000.0),
+                    SpaceObject { velocity: ship_velocity, ..SpaceObject::new(1000.0) },
                     SpaceShip::default(),
                     SpaceShipSettings::default(),
+                    OsculatingOrbit::default(),
+                    CurrentSoi::default(),
+                    SurfaceRelativeState::default(),
+                    TidalAcceleration::new(DVec3::new(0.0, 0.0, 2.5)),
+                    TrajectoryPrediction::default(),
+                    ShipBlueprintHandle(ship_blueprint.clone()),
+                    WarpFocus,
                     Player,
                 ));
 
@@ -148,17 +213,9 @@ fn setup(
                         SpatialListener::new(0.5),
                     ));
                 });
-
-                spawn_ship(
-                    ship,
-                    &mut meshes,
-                    &mut materials,
-                    &mut effects,
-                    &asset_server,
-                );
             });
 
-            let (camera_cell, camera_translation) = sun.frame().translation_to_grid(DVec3::new(geostationary_orbit_high, 0.0, earth_orbit_radius + 10.0));
+            let (camera_cell, camera_translation) = sun.frame().translation_to_grid(ship_position + DVec3::new(0.0, 0.0, 10.0));
 
             sun.with_frame_default(|camera| {
                 camera.insert((
@@ -179,26 +236,19 @@ fn setup(
                 ));
             });
 
-            let (ai_player_cell, ai_player_translation) = sun.frame().translation_to_grid(DVec3::new(geostationary_orbit_high, 0.0, earth_orbit_radius + 10000.0));
+            let (ai_player_cell, ai_player_translation) = sun.frame().translation_to_grid(ship_position + DVec3::new(0.0, 0.0, 10000.0));
 
             sun.with_frame_default(|ship| {
                 ship.insert((
                     Name::new("AI Player"),
                     ai_player_cell,
                     Transform::from_translation(ai_player_translation),
-                    SpaceObject::new(1000.0),
+                    SpaceObject { velocity: ship_velocity, ..SpaceObject::new(1000.0) },
                     SpaceShip::default(),
                     SpaceShipSettings::default(),
+                    ShipBlueprintHandle(ship_blueprint.clone()),
                     AIPlayer,
                 ));
-
-                spawn_ship(
-                    ship,
-                    &mut meshes,
-                    &mut materials,
-                    &mut effects,
-                    &asset_server,
-                );
             });
         // });
     });
@@ -230,6 +280,7 @@ fn setup(
 }
 
 
+#[allow(clippy::type_complexity)]
 fn update_gizmos(player_query: Query<(&Transform, &SpaceObject, &SpaceShip), (With<SpaceShip>, With<Player>)>, mut gizmos: Gizmos) {
     for (ship_transform, object, ship) in player_query.iter() {
         gizmos.arrow(ship_transform.translation, ship_transform.translation + ship_transform.forward() * 3.0, GREEN);
@@ -238,22 +289,19 @@ fn update_gizmos(player_query: Query<(&Transform, &SpaceObject, &SpaceShip), (Wi
             gizmos.arrow(ship_transform.translation, ship_transform.translation + ship_transform.rotation * ship.desired_movement_vector * 2.5, WHITE);
         }
         if object.velocity.length() > 0.0 {
-            gizmos.arrow(ship_transform.translation, ship_transform.translation + object.velocity.normalize() * 2.5, YELLOW);
+            gizmos.arrow(ship_transform.translation, ship_transform.translation + object.velocity.normalize().as_vec3() * 2.5, YELLOW);
         }
         if object.acceleration.length() > 0.0 {
-            gizmos.arrow(ship_transform.translation, ship_transform.translation + object.acceleration.normalize() * 2.5, RED);
+            gizmos.arrow(ship_transform.translation, ship_transform.translation + object.acceleration.normalize().as_vec3() * 2.5, RED);
         }
-        let angular_velocity_direction = Dir3::new(object.angular_velocity.normalize_or_zero());
-        if angular_velocity_direction.is_ok() {
-            gizmos.circle(ship_transform.translation, angular_velocity_direction.unwrap(), 2.0, GREEN);
+        if let Ok(angular_velocity_direction) = Dir3::new(object.angular_velocity.normalize_or_zero()) {
+            gizmos.circle(ship_transform.translation, angular_velocity_direction, 2.0, GREEN);
         }
-        let angular_acceleration_direction = Dir3::new(object.angular_acceleration.normalize_or_zero());
-        if angular_acceleration_direction.is_ok() {
-            gizmos.circle(ship_transform.translation, angular_acceleration_direction.unwrap(), 2.0, RED);
+        if let Ok(angular_acceleration_direction) = Dir3::new(object.angular_acceleration.normalize_or_zero()) {
+            gizmos.circle(ship_transform.translation, angular_acceleration_direction, 2.0, RED);
         }
-        let desired_angular_direction = Dir3::new(ship.desired_rotation_vector.normalize_or_zero());
-        if desired_angular_direction.is_ok() {
-            gizmos.circle(ship_transform.translation, ship_transform.rotation * desired_angular_direction.unwrap(), 2.0, WHITE);
+        if let Ok(desired_angular_direction) = Dir3::new(ship.desired_rotation_vector.normalize_or_zero()) {
+            gizmos.circle(ship_transform.translation, ship_transform.rotation * desired_angular_direction, 2.0, WHITE);
         }
         // gizmos.grid(Vec3::ZERO, Quat::from_rotation_x(std::f32::consts::PI / 2.0), UVec2::splat(50), Vec2::new(10.0, 10.0), DARK_GRAY);
     }